@@ -10,8 +10,51 @@ export type VaultFile = {
     vault_id: string,
     path_id: string,
     name: string,
-    file_type: 'file' | 'folder',
+    file_type: 'file' | 'folder' | 'symlink',
     parent_id: string | null,
     created_at: string | null,
+    modified_at: string | null,
     size: number | null,
-};
\ No newline at end of file
+    mode: number | null,
+    is_hidden: boolean,
+    symlink_target: string | null,
+    content_sha256: string | null,
+    mime_type: string | null,
+    subtree_size: number,
+    subtree_file_count: number,
+    subtree_folder_count: number,
+};
+export type VaultFileSearchResult = VaultFile & {
+    folder_path: string,
+};
+
+export type VaultFileMediaMetadata = {
+    width: number | null,
+    height: number | null,
+    orientation: number | null,
+    captured_at: string | null,
+    camera_make: string | null,
+    camera_model: string | null,
+    latitude: number | null,
+    longitude: number | null,
+    altitude: number | null,
+    duration_seconds: number | null,
+};
+export type VaultFileDetails = VaultFile & {
+    media_metadata: VaultFileMediaMetadata | null,
+};
+
+export type VaultIndexJob = {
+    id: string,
+    vault_id: string,
+    kind: 'reindex' | 'reconcile',
+    state: 'pending' | 'running' | 'succeeded' | 'failed' | 'cancelled',
+    created_at: string,
+    started_at: string | null,
+    finished_at: string | null,
+    files_total: number | null,
+    files_seen: number,
+    error_count: number,
+    errors: string[],
+    progress: number,
+};
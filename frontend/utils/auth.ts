@@ -6,7 +6,24 @@ export type UserTokens = {
     refresh_token: string,
 };
 
-export function signOut() {
+/** Returned instead of tokens when the user has two-factor authentication enabled */
+export type TwoFactorChallenge = {
+    two_factor_challenge: string,
+    expires_at: string,
+};
+
+export async function signOut() {
+    const refreshToken = localStorage.getItem('auth__refresh_token');
+
+    // Signing out locally still goes ahead if the token can't be revoked (e.g. it already expired)
+    if (refreshToken) {
+        await $fetch(`${import.meta.env.BASE_URL}/logout/`, {
+            method: 'POST',
+            body: JSON.stringify({ refresh_token: refreshToken }),
+            headers: { 'Content-Type': 'application/json' },
+        }).catch(() => {});
+    }
+
     localStorage.removeItem('auth__access_token');
     localStorage.removeItem('auth__refresh_token');
     window.location.reload();
@@ -73,7 +90,7 @@ function createAuth() {
                 body: JSON.stringify({ refresh_token: refreshToken.value }),
                 headers: { 'Content-Type': 'application/json' },
                 responseType: 'json',
-            }).then((data) => void setTokens(data as { access_token: string })),
+            }).then((data) => void setTokens(data as UserTokens)),
         ));
 
         return refreshState.value.promise;
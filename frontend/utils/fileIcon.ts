@@ -1,6 +1,31 @@
 import { faFile, faFileAudio, faFileCode, faFileExcel, faFileImage, faFileLines, faFilePdf, faFilePowerpoint, faFileVideo, faFileWord, faFileZipper } from "@fortawesome/free-regular-svg-icons";
 
-export function getFileIcon(fileName: string) {
+function getMimeTypeIcon(mimeType: string) {
+    if (mimeType.startsWith('image/')) {
+        return faFileImage;
+    }
+
+    if (mimeType.startsWith('video/')) {
+        return faFileVideo;
+    }
+
+    if (mimeType.startsWith('audio/')) {
+        return faFileAudio;
+    }
+
+    if (mimeType === 'application/pdf') {
+        return faFilePdf;
+    }
+
+    return null;
+}
+
+export function getFileIcon(fileName: string, mimeType: string | null = null) {
+    const mimeTypeIcon = mimeType ? getMimeTypeIcon(mimeType) : null;
+    if (mimeTypeIcon) {
+        return mimeTypeIcon;
+    }
+
     const fileParts = fileName.split('.');
     const fileExtension = fileParts.at(-1) || '';
 
@@ -51,4 +51,8 @@ export function formatDuration(seconds: number): string {
     const years = weeks / 52;
 
     return `${years.toFixed(1)} year${p(years)}`;
-}
\ No newline at end of file
+}
+
+export function formatItemCount(count: number): string {
+    return `${count.toLocaleString('en-US')} ${count === 1 ? 'item' : 'items'}`;
+}
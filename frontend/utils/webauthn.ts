@@ -0,0 +1,50 @@
+/** The server sends & expects binary WebAuthn fields as unpadded base64url strings */
+function decodeBase64Url(value: string): ArrayBuffer {
+    const base64 = value.replace(/-/g, '+').replace(/_/g, '/');
+    const binary = atob(base64.padEnd(base64.length + (4 - base64.length % 4) % 4, '='));
+
+    return Uint8Array.from(binary, (char) => char.charCodeAt(0)).buffer;
+}
+
+function encodeBase64Url(value: ArrayBuffer): string {
+    const binary = String.fromCharCode(...new Uint8Array(value));
+
+    return btoa(binary).replace(/\+/g, '-').replace(/\//g, '_').replace(/=+$/, '');
+}
+
+export type WebauthnLoginStart = {
+    ceremony_id: string,
+    options: { publicKey: any },
+};
+
+/** Asks the browser for a passkey matching the options from `/login/webauthn/start/` */
+export async function getPasskeyCredential(options: WebauthnLoginStart['options']) {
+    const publicKey: PublicKeyCredentialRequestOptions = {
+        ...options.publicKey,
+        challenge: decodeBase64Url(options.publicKey.challenge),
+        allowCredentials: options.publicKey.allowCredentials?.map((credential: any) => ({
+            ...credential,
+            id: decodeBase64Url(credential.id),
+        })),
+    };
+
+    const credential = await navigator.credentials.get({ publicKey }) as PublicKeyCredential | null;
+    if (!credential) {
+        throw new Error('No passkey was chosen');
+    }
+
+    const response = credential.response as AuthenticatorAssertionResponse;
+
+    return {
+        id: credential.id,
+        rawId: encodeBase64Url(credential.rawId),
+        type: credential.type,
+        extensions: credential.getClientExtensionResults(),
+        response: {
+            authenticatorData: encodeBase64Url(response.authenticatorData),
+            clientDataJSON: encodeBase64Url(response.clientDataJSON),
+            signature: encodeBase64Url(response.signature),
+            userHandle: response.userHandle ? encodeBase64Url(response.userHandle) : null,
+        },
+    };
+}
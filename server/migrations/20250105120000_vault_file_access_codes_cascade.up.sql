@@ -0,0 +1,4 @@
+ALTER TABLE vault_file_access_codes
+    DROP CONSTRAINT vault_file_access_codes_vault_file_id_fkey,
+    ADD CONSTRAINT vault_file_access_codes_vault_file_id_fkey
+        FOREIGN KEY (vault_file_id) REFERENCES vault_files (id) ON DELETE CASCADE;
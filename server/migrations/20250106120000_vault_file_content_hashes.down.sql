@@ -0,0 +1,3 @@
+DROP INDEX vault_files_content_sha256_idx;
+
+ALTER TABLE vault_files DROP COLUMN content_sha256;
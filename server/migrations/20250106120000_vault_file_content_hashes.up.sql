@@ -0,0 +1,3 @@
+ALTER TABLE vault_files ADD COLUMN content_sha256 BYTEA NULL;
+
+CREATE INDEX vault_files_content_sha256_idx ON vault_files (content_sha256);
@@ -0,0 +1,3 @@
+DROP INDEX vault_files_mime_type_idx;
+
+ALTER TABLE vault_files DROP COLUMN mime_type;
@@ -0,0 +1,3 @@
+ALTER TABLE vault_files ADD COLUMN mime_type VARCHAR NULL;
+
+CREATE INDEX vault_files_mime_type_idx ON vault_files (vault_id, mime_type);
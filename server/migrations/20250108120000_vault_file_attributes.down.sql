@@ -0,0 +1,7 @@
+DROP INDEX vault_files_parent_id_modified_at_idx;
+
+ALTER TABLE vault_files
+    DROP COLUMN modified_at,
+    DROP COLUMN mode,
+    DROP COLUMN is_hidden,
+    DROP COLUMN symlink_target;
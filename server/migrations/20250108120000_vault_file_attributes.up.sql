@@ -0,0 +1,9 @@
+ALTER TABLE vault_files
+    ADD COLUMN modified_at     TIMESTAMPTZ NULL,
+    ADD COLUMN mode            INTEGER NULL,
+    ADD COLUMN is_hidden       BOOLEAN NOT NULL DEFAULT FALSE,
+    ADD COLUMN symlink_target  VARCHAR NULL;
+
+UPDATE vault_files SET is_hidden = TRUE WHERE name LIKE '.%';
+
+CREATE INDEX vault_files_parent_id_modified_at_idx ON vault_files (vault_id, parent_id, modified_at);
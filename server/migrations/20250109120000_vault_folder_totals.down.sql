@@ -0,0 +1,8 @@
+DROP TRIGGER vault_files_propagate_subtree_totals ON vault_files;
+
+DROP FUNCTION vault_files_propagate_subtree_totals;
+
+ALTER TABLE vault_files
+    DROP COLUMN subtree_size,
+    DROP COLUMN subtree_file_count,
+    DROP COLUMN subtree_folder_count;
@@ -0,0 +1,89 @@
+ALTER TABLE vault_files
+    ADD COLUMN subtree_size          BIGINT NOT NULL DEFAULT 0,
+    ADD COLUMN subtree_file_count    BIGINT NOT NULL DEFAULT 0,
+    ADD COLUMN subtree_folder_count  BIGINT NOT NULL DEFAULT 0;
+
+-- Backfill the totals of existing folders
+WITH RECURSIVE descendants AS (
+    SELECT id AS ancestor_id, id, file_type, size FROM vault_files WHERE file_type = 'folder'
+    UNION ALL
+    SELECT descendants.ancestor_id, children.id, children.file_type, children.size
+    FROM vault_files children
+    INNER JOIN descendants ON children.parent_id = descendants.id
+)
+UPDATE vault_files SET
+    subtree_size = totals.subtree_size,
+    subtree_file_count = totals.subtree_file_count,
+    subtree_folder_count = totals.subtree_folder_count
+FROM (
+    SELECT
+        ancestor_id,
+        COALESCE(SUM(size) FILTER (WHERE file_type = 'file' AND id <> ancestor_id), 0) AS subtree_size,
+        COUNT(*) FILTER (WHERE file_type <> 'folder' AND id <> ancestor_id) AS subtree_file_count,
+        COUNT(*) FILTER (WHERE file_type = 'folder' AND id <> ancestor_id) AS subtree_folder_count
+    FROM descendants
+    GROUP BY ancestor_id
+) totals
+WHERE vault_files.id = totals.ancestor_id;
+
+-- Each row passes the change in what it contributes to its parent's totals on to its parent, whose own update then
+-- passes it on to the next parent and so on until the top of the tree. When a folder is deleted its children are
+-- removed by the cascade before this runs for the folder, so their updates to it are no-ops and the folder takes its
+-- whole subtree off of its parent's totals in one go.
+CREATE FUNCTION vault_files_propagate_subtree_totals() RETURNS TRIGGER AS $$
+DECLARE
+    old_size    BIGINT := 0;
+    old_files   BIGINT := 0;
+    old_folders BIGINT := 0;
+    new_size    BIGINT := 0;
+    new_files   BIGINT := 0;
+    new_folders BIGINT := 0;
+BEGIN
+    IF TG_OP IN ('UPDATE', 'DELETE') THEN
+        old_size := OLD.subtree_size + CASE WHEN OLD.file_type = 'file' THEN COALESCE(OLD.size, 0) ELSE 0 END;
+        old_files := OLD.subtree_file_count + CASE WHEN OLD.file_type <> 'folder' THEN 1 ELSE 0 END;
+        old_folders := OLD.subtree_folder_count + CASE WHEN OLD.file_type = 'folder' THEN 1 ELSE 0 END;
+    END IF;
+
+    IF TG_OP IN ('UPDATE', 'INSERT') THEN
+        new_size := NEW.subtree_size + CASE WHEN NEW.file_type = 'file' THEN COALESCE(NEW.size, 0) ELSE 0 END;
+        new_files := NEW.subtree_file_count + CASE WHEN NEW.file_type <> 'folder' THEN 1 ELSE 0 END;
+        new_folders := NEW.subtree_folder_count + CASE WHEN NEW.file_type = 'folder' THEN 1 ELSE 0 END;
+    END IF;
+
+    IF TG_OP = 'UPDATE' AND OLD.parent_id IS NOT DISTINCT FROM NEW.parent_id THEN
+        IF new_size <> old_size OR new_files <> old_files OR new_folders <> old_folders THEN
+            UPDATE vault_files SET
+                subtree_size = subtree_size + new_size - old_size,
+                subtree_file_count = subtree_file_count + new_files - old_files,
+                subtree_folder_count = subtree_folder_count + new_folders - old_folders
+            WHERE id = NEW.parent_id;
+        END IF;
+
+        RETURN NULL;
+    END IF;
+
+    IF TG_OP IN ('UPDATE', 'DELETE') AND OLD.parent_id IS NOT NULL THEN
+        UPDATE vault_files SET
+            subtree_size = subtree_size - old_size,
+            subtree_file_count = subtree_file_count - old_files,
+            subtree_folder_count = subtree_folder_count - old_folders
+        WHERE id = OLD.parent_id;
+    END IF;
+
+    IF TG_OP IN ('UPDATE', 'INSERT') AND NEW.parent_id IS NOT NULL THEN
+        UPDATE vault_files SET
+            subtree_size = subtree_size + new_size,
+            subtree_file_count = subtree_file_count + new_files,
+            subtree_folder_count = subtree_folder_count + new_folders
+        WHERE id = NEW.parent_id;
+    END IF;
+
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER vault_files_propagate_subtree_totals
+    AFTER INSERT OR DELETE OR UPDATE OF file_type, size, parent_id, subtree_size, subtree_file_count, subtree_folder_count
+    ON vault_files
+    FOR EACH ROW EXECUTE FUNCTION vault_files_propagate_subtree_totals();
@@ -0,0 +1,23 @@
+CREATE TABLE vault_index_jobs (
+    id           BYTEA PRIMARY KEY,
+    vault_id     BYTEA NOT NULL REFERENCES vaults (id) ON DELETE CASCADE,
+    kind         VARCHAR NOT NULL,
+    state        VARCHAR NOT NULL DEFAULT 'running',
+    started_at   TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    finished_at  TIMESTAMPTZ NULL,
+    -- Unknown until the vault has been walked
+    files_total  BIGINT NULL,
+    files_seen   BIGINT NOT NULL DEFAULT 0,
+    error_count  BIGINT NOT NULL DEFAULT 0,
+    -- Only the first few errors are kept, error_count has the full count
+    errors       TEXT[] NOT NULL DEFAULT '{}',
+    progress     INTEGER NOT NULL GENERATED ALWAYS AS (
+        CASE
+            WHEN state = 'succeeded' THEN 100
+            WHEN files_total IS NULL OR files_total = 0 THEN 0
+            ELSE LEAST(files_seen * 100 / files_total, 99)
+        END
+    ) STORED
+);
+
+CREATE INDEX vault_index_jobs_vault_id_started_at_idx ON vault_index_jobs (vault_id, started_at);
@@ -0,0 +1,12 @@
+DROP INDEX vault_index_jobs_vault_id_created_at_idx;
+CREATE INDEX vault_index_jobs_vault_id_started_at_idx ON vault_index_jobs (vault_id, started_at);
+
+DELETE FROM vault_index_jobs WHERE started_at IS NULL;
+
+ALTER TABLE vault_index_jobs
+    DROP COLUMN created_at,
+    ALTER COLUMN state SET DEFAULT 'running',
+    ALTER COLUMN started_at SET NOT NULL,
+    ALTER COLUMN started_at SET DEFAULT NOW();
+
+DROP TABLE jobs;
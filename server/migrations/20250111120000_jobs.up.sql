@@ -0,0 +1,31 @@
+CREATE TABLE jobs (
+    id            BYTEA PRIMARY KEY,
+    kind          VARCHAR NOT NULL,
+    payload       JSONB NOT NULL,
+    -- One of pending, running, succeeded, failed or cancelled
+    state         VARCHAR NOT NULL DEFAULT 'pending',
+    attempts      INTEGER NOT NULL DEFAULT 0,
+    max_attempts  INTEGER NOT NULL,
+    -- When a pending job becomes claimable, pushed back after each failed attempt
+    run_at        TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    -- Running jobs are kept locked by their worker, those whose lock runs out were interrupted and can be claimed again
+    locked_until  TIMESTAMPTZ NULL,
+    created_at    TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    finished_at   TIMESTAMPTZ NULL,
+    last_error    TEXT NULL
+);
+
+CREATE INDEX jobs_claimable_idx ON jobs (run_at) WHERE state IN ('pending', 'running');
+CREATE INDEX jobs_created_at_idx ON jobs (created_at);
+
+-- Index jobs can now wait in the queue before they start
+ALTER TABLE vault_index_jobs
+    ADD COLUMN created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    ALTER COLUMN state SET DEFAULT 'pending',
+    ALTER COLUMN started_at DROP NOT NULL,
+    ALTER COLUMN started_at DROP DEFAULT;
+
+UPDATE vault_index_jobs SET created_at = started_at;
+
+DROP INDEX vault_index_jobs_vault_id_started_at_idx;
+CREATE INDEX vault_index_jobs_vault_id_created_at_idx ON vault_index_jobs (vault_id, created_at);
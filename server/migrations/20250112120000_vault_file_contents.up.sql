@@ -0,0 +1,7 @@
+CREATE TABLE vault_file_contents (
+    vault_file_id  BYTEA PRIMARY KEY REFERENCES vault_files (id) ON DELETE CASCADE,
+    content        TEXT NOT NULL,
+    content_tsv    TSVECTOR NOT NULL GENERATED ALWAYS AS (to_tsvector('english', content)) STORED
+);
+
+CREATE INDEX vault_file_contents_content_tsv_idx ON vault_file_contents USING GIN (content_tsv);
@@ -0,0 +1,6 @@
+ALTER TABLE vault_files DROP COLUMN search_name;
+
+DROP FUNCTION immutable_unaccent;
+
+DROP EXTENSION IF EXISTS unaccent;
+DROP EXTENSION IF EXISTS pg_trgm;
@@ -0,0 +1,14 @@
+CREATE EXTENSION IF NOT EXISTS pg_trgm;
+CREATE EXTENSION IF NOT EXISTS unaccent;
+
+-- unaccent is only STABLE (its dictionary could change), which keeps it out of generated columns & indexes unless the
+-- dictionary is pinned like this
+CREATE FUNCTION immutable_unaccent(TEXT) RETURNS TEXT AS $$
+    SELECT public.unaccent('public.unaccent'::REGDICTIONARY, $1)
+$$ LANGUAGE SQL IMMUTABLE PARALLEL SAFE STRICT;
+
+-- The name as it's matched against searches, accents & case don't matter
+ALTER TABLE vault_files
+    ADD COLUMN search_name TEXT NOT NULL GENERATED ALWAYS AS (LOWER(immutable_unaccent(name))) STORED;
+
+CREATE INDEX vault_files_search_name_trgm_idx ON vault_files USING GIN (search_name gin_trgm_ops);
@@ -0,0 +1,15 @@
+CREATE TABLE vault_file_media_metadata (
+    vault_file_id     BYTEA PRIMARY KEY REFERENCES vault_files (id) ON DELETE CASCADE,
+    width             INTEGER,
+    height            INTEGER,
+    -- EXIF orientation, 1-8
+    orientation       SMALLINT,
+    -- EXIF capture times are in the camera's local time without a time zone
+    captured_at       TIMESTAMP,
+    camera_make       VARCHAR,
+    camera_model      VARCHAR,
+    latitude          DOUBLE PRECISION,
+    longitude         DOUBLE PRECISION,
+    altitude          DOUBLE PRECISION,
+    duration_seconds  DOUBLE PRECISION
+);
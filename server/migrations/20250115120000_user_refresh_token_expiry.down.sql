@@ -0,0 +1 @@
+ALTER TABLE user_refresh_tokens DROP COLUMN expires_at, DROP COLUMN revoked_at;
@@ -0,0 +1,8 @@
+-- Tokens issued before expiry was enforced get the default 30 day lifetime from now
+ALTER TABLE user_refresh_tokens
+    ADD COLUMN expires_at TIMESTAMPTZ NOT NULL DEFAULT NOW() + INTERVAL '30 days',
+    ADD COLUMN revoked_at TIMESTAMPTZ;
+
+ALTER TABLE user_refresh_tokens ALTER COLUMN expires_at DROP DEFAULT;
+
+CREATE INDEX user_refresh_tokens_expires_at_idx ON user_refresh_tokens (expires_at);
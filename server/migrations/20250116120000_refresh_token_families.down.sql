@@ -0,0 +1,3 @@
+DROP TABLE user_security_events;
+
+ALTER TABLE user_refresh_tokens DROP COLUMN family_id, DROP COLUMN rotated_at;
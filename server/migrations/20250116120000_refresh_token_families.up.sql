@@ -0,0 +1,23 @@
+-- Every login starts a family of refresh tokens, each refresh replaces the family's current token with a new one
+ALTER TABLE user_refresh_tokens
+    ADD COLUMN family_id  BYTEA,
+    ADD COLUMN rotated_at TIMESTAMPTZ;
+
+UPDATE user_refresh_tokens SET family_id = id;
+
+ALTER TABLE user_refresh_tokens ALTER COLUMN family_id SET NOT NULL;
+
+CREATE INDEX user_refresh_tokens_family_id_idx ON user_refresh_tokens (family_id);
+
+CREATE TABLE user_security_events (
+    id              BYTEA PRIMARY KEY,
+    user_id         BYTEA NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    -- e.g. refresh_token_reuse
+    kind            VARCHAR NOT NULL,
+    created_at      TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    user_agent      VARCHAR,
+    remote_address  VARCHAR,
+    data            JSONB NOT NULL DEFAULT '{}'
+);
+
+CREATE INDEX user_security_events_user_id_idx ON user_security_events (user_id, created_at);
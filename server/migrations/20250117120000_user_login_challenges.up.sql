@@ -0,0 +1,11 @@
+-- Logins which got past the password but still need a second factor
+CREATE TABLE user_login_challenges (
+    id               BYTEA PRIMARY KEY,
+    user_id          BYTEA NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    token_hash       BYTEA UNIQUE NOT NULL,
+    created_at       TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    expires_at       TIMESTAMPTZ NOT NULL,
+    failed_attempts  INTEGER NOT NULL DEFAULT 0
+);
+
+CREATE INDEX user_login_challenges_expires_at_idx ON user_login_challenges (expires_at);
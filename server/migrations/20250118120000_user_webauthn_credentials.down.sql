@@ -0,0 +1,2 @@
+DROP TABLE user_webauthn_ceremonies;
+DROP TABLE user_webauthn_credentials;
@@ -0,0 +1,24 @@
+-- Passkeys of users with a `webauthn` identity, which holds the user handle shared by all of them
+CREATE TABLE user_webauthn_credentials (
+    id             BYTEA PRIMARY KEY,
+    user_id        BYTEA NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    credential_id  BYTEA UNIQUE NOT NULL,
+    name           VARCHAR NOT NULL,
+    passkey        JSONB NOT NULL,
+    created_at     TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    last_used_at   TIMESTAMPTZ
+);
+
+CREATE INDEX user_webauthn_credentials_user_id_idx ON user_webauthn_credentials (user_id);
+
+-- Server side state of registrations & logins in progress, the user isn't known yet when logging in with a
+-- discoverable credential
+CREATE TABLE user_webauthn_ceremonies (
+    id          BYTEA PRIMARY KEY,
+    user_id     BYTEA REFERENCES users (id) ON DELETE CASCADE,
+    state       JSONB NOT NULL,
+    created_at  TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    expires_at  TIMESTAMPTZ NOT NULL
+);
+
+CREATE INDEX user_webauthn_ceremonies_expires_at_idx ON user_webauthn_ceremonies (expires_at);
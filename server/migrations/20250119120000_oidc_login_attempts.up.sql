@@ -0,0 +1,11 @@
+-- Logins which were sent to the OpenID Connect provider & haven't come back yet
+CREATE TABLE oidc_login_attempts (
+    id             BYTEA PRIMARY KEY,
+    state          VARCHAR UNIQUE NOT NULL,
+    nonce          VARCHAR NOT NULL,
+    code_verifier  VARCHAR NOT NULL,
+    created_at     TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    expires_at     TIMESTAMPTZ NOT NULL
+);
+
+CREATE INDEX oidc_login_attempts_expires_at_idx ON oidc_login_attempts (expires_at);
@@ -0,0 +1,11 @@
+-- Links sent by email which let a user who forgot their password set a new one
+CREATE TABLE user_password_reset_tokens (
+    id          BYTEA PRIMARY KEY,
+    user_id     BYTEA NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    token_hash  BYTEA UNIQUE NOT NULL,
+    created_at  TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    expires_at  TIMESTAMPTZ NOT NULL
+);
+
+CREATE INDEX user_password_reset_tokens_user_id_idx ON user_password_reset_tokens (user_id);
+CREATE INDEX user_password_reset_tokens_expires_at_idx ON user_password_reset_tokens (expires_at);
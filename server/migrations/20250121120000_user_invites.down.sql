@@ -0,0 +1,2 @@
+DROP TABLE user_invite_vault_links;
+DROP TABLE user_invites;
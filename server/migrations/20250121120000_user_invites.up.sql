@@ -0,0 +1,22 @@
+-- Codes which let someone create their own account, handed out by admins
+CREATE TABLE user_invites (
+    id           BYTEA PRIMARY KEY,
+    code_hash    BYTEA UNIQUE NOT NULL,
+    -- Not set for invites created through the CLI
+    created_by   BYTEA REFERENCES users (id) ON DELETE CASCADE,
+    created_at   TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    expires_at   TIMESTAMPTZ NOT NULL,
+    redeemed_at  TIMESTAMPTZ,
+    redeemed_by  BYTEA REFERENCES users (id) ON DELETE SET NULL
+);
+
+CREATE INDEX user_invites_created_by_idx ON user_invites (created_by);
+
+-- Vaults the user who redeems an invite is linked to
+CREATE TABLE user_invite_vault_links (
+    invite_id  BYTEA NOT NULL REFERENCES user_invites (id) ON DELETE CASCADE,
+    vault_id   BYTEA NOT NULL REFERENCES vaults (id) ON DELETE CASCADE,
+    is_admin   BOOLEAN NOT NULL DEFAULT FALSE,
+
+    PRIMARY KEY (invite_id, vault_id)
+);
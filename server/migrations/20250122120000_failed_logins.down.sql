@@ -0,0 +1,2 @@
+DROP TABLE login_lockouts;
+DROP TABLE failed_login_attempts;
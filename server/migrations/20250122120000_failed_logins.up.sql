@@ -0,0 +1,28 @@
+-- Every rejected login, kept for a while so attacks can be looked into
+CREATE TABLE failed_login_attempts (
+    id              BYTEA PRIMARY KEY,
+    -- Not set when no user has the email address or username which was tried
+    user_id         BYTEA REFERENCES users (id) ON DELETE CASCADE,
+    -- Lowercased email address or username the login was attempted with
+    identifier      VARCHAR NOT NULL,
+    method          VARCHAR NOT NULL,
+    reason          VARCHAR NOT NULL,
+    remote_address  VARCHAR NOT NULL,
+    user_agent      VARCHAR,
+    created_at      TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX failed_login_attempts_identifier_idx ON failed_login_attempts (identifier, created_at);
+CREATE INDEX failed_login_attempts_remote_address_idx ON failed_login_attempts (remote_address, created_at);
+CREATE INDEX failed_login_attempts_created_at_idx ON failed_login_attempts (created_at);
+
+-- Recent failures per account (by identifier) & per IP address, which logins are slowed down & locked out by
+CREATE TABLE login_lockouts (
+    kind             VARCHAR NOT NULL,
+    key              VARCHAR NOT NULL,
+    failed_attempts  INTEGER NOT NULL,
+    last_failed_at   TIMESTAMPTZ NOT NULL,
+    locked_until     TIMESTAMPTZ,
+
+    PRIMARY KEY (kind, key)
+);
@@ -0,0 +1,7 @@
+-- Set once the user proved they own their email address, or it came from someone trusted with it (an admin, the
+-- directory or the identity provider). Logins through LDAP or OIDC only link to existing accounts by a verified email.
+ALTER TABLE users ADD COLUMN email_verified_at TIMESTAMPTZ;
+
+-- Only accounts created with an invite picked their own email address
+UPDATE users SET email_verified_at = created_at
+WHERE NOT EXISTS(SELECT FROM user_invites WHERE user_invites.redeemed_by = users.id);
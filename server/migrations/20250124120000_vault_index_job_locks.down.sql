@@ -0,0 +1 @@
+ALTER TABLE vault_index_jobs DROP COLUMN locked_until;
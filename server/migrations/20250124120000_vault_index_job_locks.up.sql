@@ -0,0 +1,3 @@
+-- Running index jobs are kept locked by whoever runs them (the server or the CLI), those whose lock runs out were
+-- interrupted and are failed
+ALTER TABLE vault_index_jobs ADD COLUMN locked_until TIMESTAMPTZ NULL;
@@ -1,6 +1,8 @@
-use std::{any::type_name, env::Args, fmt::Display, str::FromStr};
+use std::{any::type_name, env::Args, error::Error, fmt::Display, str::FromStr};
 use thiserror::Error;
 
+use crate::utils::{hex::decode_hex, xid::Xid};
+
 #[derive(Error, Debug)]
 pub enum ArgumentError {
     #[error("Missing argument {}", .arg_name)]
@@ -45,3 +47,43 @@ pub fn handle_arg_error(command_syntax: String) -> Box<dyn Fn(ArgumentError) ->
         error
     })
 }
+
+/// Parses an id given either as an XID or as the hex representation of its bytes
+pub fn parse_xid_arg(arg_name: &str, value: &str) -> Result<Xid, CommandError> {
+    match value.len() {
+        24 => Ok(Xid::from(decode_hex(value).map_err(|_| {
+            CommandError("Expected valid hex representation of XID bytes".into())
+        })?)),
+        _ => Xid::try_from(value)
+            .map_err(|_| CommandError(format!("The {arg_name} parameter must be a valid XID"))),
+    }
+}
+
+/// Looks up an existing user given either by their id or their email address
+pub async fn find_user_arg(
+    db: &sqlx::Pool<sqlx::Postgres>,
+    arg_name: &str,
+    value: &str,
+) -> Result<Xid, Box<dyn Error>> {
+    let user_id = match value.contains('@') {
+        true => sqlx::query!("SELECT id FROM users WHERE LOWER(email) = LOWER($1)", value)
+            .fetch_optional(db)
+            .await?
+            .map(|user| Xid::from(user.id)),
+        false => {
+            let user_id = parse_xid_arg(arg_name, value)?;
+
+            sqlx::query!("SELECT id FROM users WHERE id = $1", user_id.as_bytes())
+                .fetch_optional(db)
+                .await?
+                .map(|_| user_id)
+        }
+    };
+
+    user_id.ok_or_else(|| {
+        CommandError(format!(
+            "The {arg_name} parameter must refer to an existing user"
+        ))
+        .into()
+    })
+}
@@ -1,13 +1,11 @@
 use std::{env::Args, error::Error};
 
-use argon2::{password_hash::SaltString, Argon2, PasswordHasher};
 use chrono::{DateTime, Utc};
-use rand::rngs::OsRng;
-use serde_json::json;
 
 use crate::{
     cli::arguments::{handle_arg_error, require_arg, CommandError},
     config::Config,
+    logic::passwords::set_user_password,
     utils::xid::Xid,
 };
 
@@ -39,32 +37,12 @@ pub async fn create_user(
     let user_id = Xid::new();
 
     sqlx::query!(
-        "INSERT INTO users (id, created_at, last_login_at, name, email) VALUES ($1, $2, $3, $4, $5)",
+        "INSERT INTO users (id, created_at, last_login_at, name, email, email_verified_at) VALUES ($1, $2, $3, $4, $5, $2)",
         user_id.as_bytes(), Utc::now(), <Option<DateTime<Utc>>>::None, name, email,
     ).execute(&mut *db)
     .await?;
 
-    let user_identity_id = Xid::new();
-
-    let hashed_password: String = {
-        let salt = SaltString::generate(&mut OsRng);
-        let argon2 = Argon2::default();
-
-        argon2
-            .hash_password(password.as_bytes(), &salt)
-            .unwrap()
-            .to_string()
-    };
-
-    sqlx::query!(
-        "INSERT INTO user_identities (id, user_id, provider, data) VALUES ($1, $2, $3, $4)",
-        user_identity_id.as_bytes(),
-        user_id.as_bytes(),
-        "email_and_password",
-        json!({"password": hashed_password})
-    )
-    .execute(&mut *db)
-    .await?;
+    set_user_password(&mut *db, user_id, &password).await?;
 
     db.commit().await?;
 
@@ -2,12 +2,14 @@ use std::{env::Args, error::Error};
 
 use crate::{
     config::Config,
-    logic::indexing::local_folder::reindex_local_folder_vault,
+    logic::indexing::{
+        jobs::{IndexJob, IndexJobKind},
+        local_folder::reindex_local_folder_vault,
+    },
     models::vaults::Vault,
-    utils::{hex::decode_hex, xid::Xid},
 };
 
-use super::arguments::{handle_arg_error, require_arg, CommandError};
+use super::arguments::{handle_arg_error, parse_xid_arg, require_arg, CommandError};
 
 pub async fn index_vault(
     _config: Config,
@@ -20,15 +22,7 @@ pub async fn index_vault(
     let vault_id =
         require_arg::<String>("vault_id".to_string(), args).map_err(arg_error_handler)?;
 
-    let vault_id = match vault_id.len() {
-        24 => {
-            Xid::from(decode_hex(&vault_id).map_err(|_| {
-                CommandError("Expected valid hex representation of XID bytes".into())
-            })?)
-        }
-        _ => Xid::try_from(vault_id.as_str())
-            .map_err(|_| CommandError("The vault_id parameter must be a valid XID".to_string()))?,
-    };
+    let vault_id = parse_xid_arg("vault_id", &vault_id)?;
 
     let vault = sqlx::query_as!(
         Vault,
@@ -46,7 +40,13 @@ pub async fn index_vault(
     }
     let vault = vault.unwrap();
 
-    let file_count = reindex_local_folder_vault(db, vault).await?;
+    let mut job = IndexJob::start(&db, vault.id, IndexJobKind::Reindex).await?;
+    println!("Reindexing vault as job {}...", job.id.to_string());
+
+    let result = reindex_local_folder_vault(&db, &vault, &mut job).await;
+    job.finish(result.as_ref().err().map(|error| error.to_string()))
+        .await?;
+    let file_count = result?;
 
     println!(
         "Successfully reindexed vault {} (found {} files)",
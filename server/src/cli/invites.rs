@@ -0,0 +1,60 @@
+use std::{env::Args, error::Error};
+
+use crate::{
+    config::Config,
+    logic::invites::{create_invite, invite_signup_url, InviteVaultLink},
+};
+
+use super::arguments::{parse_xid_arg, CommandError};
+
+/// Creates an invite someone can sign up with, linking them to the given vaults. Each vault is given by its id, with
+/// `:admin` appended to make the user an admin of it.
+pub async fn create_user_invite(
+    config: Config,
+    db: sqlx::Pool<sqlx::Postgres>,
+    args: &mut Args,
+) -> Result<(), Box<dyn Error>> {
+    let mut vault_links = vec![];
+
+    for arg in args {
+        let (vault_id, is_admin) = match arg.split_once(':') {
+            Some((vault_id, "admin")) => (vault_id, true),
+            Some(_) => {
+                println!("Command Error: Expected {arg:?} to be <vault_id> or <vault_id>:admin. Example usage: createinvite [<vault_id>[:admin] ...]");
+                return Err(CommandError("Invalid vault argument".to_string()).into());
+            }
+            None => (arg.as_str(), false),
+        };
+        let vault_id = parse_xid_arg("vault_id", vault_id)?;
+
+        let vault = sqlx::query!("SELECT name FROM vaults WHERE id = $1", vault_id.as_bytes())
+            .fetch_optional(&db)
+            .await?;
+
+        let Some(vault) = vault else {
+            return Err(
+                CommandError(format!("Vault {} doesn't exist", vault_id.to_string())).into(),
+            );
+        };
+
+        println!(
+            "The invite links to vault {} ({}){}",
+            vault_id.to_string(),
+            vault.name,
+            if is_admin { " as an admin" } else { "" }
+        );
+        vault_links.push(InviteVaultLink { vault_id, is_admin });
+    }
+
+    let invite = create_invite(&db, &config, None, &vault_links).await?;
+
+    println!(
+        "Successfully created invite {}, it can be redeemed until {}\nCode: {}\nSignup link: {}",
+        invite.id.to_string(),
+        invite.expires_at,
+        invite.code,
+        invite_signup_url(&config, &invite.code)
+    );
+
+    Ok(())
+}
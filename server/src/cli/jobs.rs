@@ -0,0 +1,98 @@
+use std::{env::Args, error::Error};
+
+use crate::{
+    config::Config,
+    logic::job_queue::{cancel_job as cancel_queued_job, retry_job as retry_queued_job},
+    models::jobs::Job,
+};
+
+use super::arguments::{handle_arg_error, parse_xid_arg, require_arg, CommandError};
+
+const LISTED_JOBS_LIMIT: i64 = 50;
+
+pub async fn list_jobs(
+    _config: Config,
+    db: sqlx::Pool<sqlx::Postgres>,
+    args: &mut Args,
+) -> Result<(), Box<dyn Error>> {
+    let state = args.next();
+
+    let jobs = sqlx::query_as!(
+        Job,
+        "SELECT id, kind, payload, state, attempts, max_attempts, run_at, locked_until, created_at, finished_at, last_error \
+        FROM jobs WHERE ($1::TEXT IS NULL OR state = $1::TEXT) ORDER BY created_at DESC LIMIT $2",
+        state,
+        LISTED_JOBS_LIMIT,
+    )
+    .fetch_all(&db)
+    .await?;
+
+    if jobs.is_empty() {
+        println!("There are no jobs");
+    }
+
+    for job in jobs {
+        println!(
+            "{} {} ({}) - {}, attempt {}/{}, created {}{}",
+            job.id.to_string(),
+            job.kind,
+            job.payload,
+            job.state,
+            job.attempts,
+            job.max_attempts,
+            job.created_at,
+            match job.last_error {
+                Some(error) => format!(", last error: {error}"),
+                None => "".to_string(),
+            }
+        );
+    }
+
+    Ok(())
+}
+
+pub async fn retry_job(
+    _config: Config,
+    db: sqlx::Pool<sqlx::Postgres>,
+    args: &mut Args,
+) -> Result<(), Box<dyn Error>> {
+    let command_syntax = "retryjob <job_id>".to_string();
+    let arg_error_handler = handle_arg_error(command_syntax);
+
+    let job_id = require_arg::<String>("job_id".to_string(), args).map_err(arg_error_handler)?;
+    let job_id = parse_xid_arg("job_id", &job_id)?;
+
+    if !retry_queued_job(&db, job_id).await? {
+        return Err(CommandError(
+            "The job_id parameter must refer to a failed or cancelled job".to_string(),
+        )
+        .into());
+    }
+
+    println!("Successfully queued job {} again", job_id.to_string());
+
+    Ok(())
+}
+
+pub async fn cancel_job(
+    _config: Config,
+    db: sqlx::Pool<sqlx::Postgres>,
+    args: &mut Args,
+) -> Result<(), Box<dyn Error>> {
+    let command_syntax = "canceljob <job_id>".to_string();
+    let arg_error_handler = handle_arg_error(command_syntax);
+
+    let job_id = require_arg::<String>("job_id".to_string(), args).map_err(arg_error_handler)?;
+    let job_id = parse_xid_arg("job_id", &job_id)?;
+
+    if !cancel_queued_job(&db, job_id).await? {
+        return Err(CommandError(
+            "The job_id parameter must refer to a pending or running job".to_string(),
+        )
+        .into());
+    }
+
+    println!("Successfully cancelled job {}", job_id.to_string());
+
+    Ok(())
+}
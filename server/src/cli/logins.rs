@@ -0,0 +1,89 @@
+use std::{env::Args, error::Error};
+
+use crate::{config::Config, logic::login_throttling::unlock_login};
+
+use super::arguments::{handle_arg_error, require_arg};
+
+const LISTED_FAILED_LOGINS_LIMIT: i64 = 50;
+
+/// Lists the most recent failed logins & the accounts and IP addresses which are locked out, optionally only those of
+/// one email address, username or IP address
+pub async fn list_failed_logins(
+    _config: Config,
+    db: sqlx::Pool<sqlx::Postgres>,
+    args: &mut Args,
+) -> Result<(), Box<dyn Error>> {
+    let key = args.next().map(|key| key.trim().to_lowercase());
+
+    let lockouts = sqlx::query!(
+        "SELECT kind, key, failed_attempts, last_failed_at, locked_until FROM login_lockouts \
+        WHERE locked_until > NOW() AND ($1::TEXT IS NULL OR key = $1::TEXT) ORDER BY locked_until DESC",
+        key,
+    )
+    .fetch_all(&db)
+    .await?;
+
+    for lockout in &lockouts {
+        println!(
+            "Locked out {} {} until {} after {} failed attempts, the last one at {}",
+            lockout.kind,
+            lockout.key,
+            lockout.locked_until.unwrap(),
+            lockout.failed_attempts,
+            lockout.last_failed_at,
+        );
+    }
+    if !lockouts.is_empty() {
+        println!();
+    }
+
+    let attempts = sqlx::query!(
+        "SELECT identifier, method, reason, remote_address, user_agent, created_at FROM failed_login_attempts \
+        WHERE ($1::TEXT IS NULL OR identifier = $1::TEXT OR remote_address = $1::TEXT) ORDER BY created_at DESC LIMIT $2",
+        key,
+        LISTED_FAILED_LOGINS_LIMIT,
+    )
+    .fetch_all(&db)
+    .await?;
+
+    if attempts.is_empty() {
+        println!("There are no failed logins");
+    }
+
+    for attempt in attempts {
+        println!(
+            "{} {} via {} from {} ({}) - {}",
+            attempt.created_at,
+            attempt.identifier,
+            attempt.method,
+            attempt.remote_address,
+            attempt
+                .user_agent
+                .as_deref()
+                .unwrap_or("unknown user agent"),
+            attempt.reason,
+        );
+    }
+
+    Ok(())
+}
+
+/// Lifts the lockout of an account or IP address before it runs out, e.g. for a user who was locked out by someone
+/// else guessing their password
+pub async fn unlock_logins(
+    _config: Config,
+    db: sqlx::Pool<sqlx::Postgres>,
+    args: &mut Args,
+) -> Result<(), Box<dyn Error>> {
+    let command_syntax = "unlocklogin <email, username or ip address>".to_string();
+    let arg_error_handler = handle_arg_error(command_syntax);
+
+    let key = require_arg::<String>("key".to_string(), args).map_err(arg_error_handler)?;
+
+    match unlock_login(&db, &key).await? {
+        true => println!("Successfully unlocked logins for {key}"),
+        false => println!("There are no failed logins for {key}"),
+    }
+
+    Ok(())
+}
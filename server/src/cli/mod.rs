@@ -5,3 +5,13 @@ mod create_vault;
 pub use create_vault::create_vault;
 mod index_vault;
 pub use index_vault::index_vault;
+mod invites;
+pub use invites::create_user_invite;
+mod jobs;
+pub use jobs::{cancel_job, list_jobs, retry_job};
+mod logins;
+pub use logins::{list_failed_logins, unlock_logins};
+mod sessions;
+pub use sessions::kill_sessions;
+mod totp;
+pub use totp::reset_totp;
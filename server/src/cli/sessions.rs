@@ -0,0 +1,29 @@
+use std::{env::Args, error::Error};
+
+use crate::{config::Config, logic::refresh_tokens::revoke_user_sessions};
+
+use super::arguments::{find_user_arg, handle_arg_error, require_arg};
+
+/// Revokes every session of a user, e.g. when their account has been compromised. Access tokens which were already
+/// issued stay valid until they expire.
+pub async fn kill_sessions(
+    _config: Config,
+    db: sqlx::Pool<sqlx::Postgres>,
+    args: &mut Args,
+) -> Result<(), Box<dyn Error>> {
+    let command_syntax = "killsessions <user_id or email>".to_string();
+    let arg_error_handler = handle_arg_error(command_syntax);
+
+    let user = require_arg::<String>("user".to_string(), args).map_err(arg_error_handler)?;
+
+    let user_id = find_user_arg(&db, "user", &user).await?;
+
+    let revoked = revoke_user_sessions(&db, user_id, None).await?;
+
+    println!(
+        "Successfully revoked {revoked} sessions of user {}",
+        user_id.to_string()
+    );
+
+    Ok(())
+}
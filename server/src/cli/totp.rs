@@ -0,0 +1,52 @@
+use std::{env::Args, error::Error};
+
+use crate::{config::Config, logic::totp::TOTP_IDENTITY_PROVIDER};
+
+use super::arguments::{find_user_arg, handle_arg_error, require_arg};
+
+/// Turns off two-factor authentication for a user who lost access to their authenticator app & recovery codes, so
+/// they can log in with just their password again
+pub async fn reset_totp(
+    _config: Config,
+    db: sqlx::Pool<sqlx::Postgres>,
+    args: &mut Args,
+) -> Result<(), Box<dyn Error>> {
+    let command_syntax = "resettotp <user_id or email>".to_string();
+    let arg_error_handler = handle_arg_error(command_syntax);
+
+    let user = require_arg::<String>("user".to_string(), args).map_err(arg_error_handler)?;
+    let user_id = find_user_arg(&db, "user", &user).await?;
+
+    let mut tx = db.begin().await?;
+
+    let deleted = sqlx::query!(
+        "DELETE FROM user_identities WHERE user_id = $1 AND provider = $2",
+        user_id.as_bytes(),
+        TOTP_IDENTITY_PROVIDER,
+    )
+    .execute(&mut *tx)
+    .await?
+    .rows_affected();
+
+    sqlx::query!(
+        "DELETE FROM user_login_challenges WHERE user_id = $1",
+        user_id.as_bytes(),
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+
+    match deleted {
+        0 => println!(
+            "User {} doesn't have two-factor authentication set up",
+            user_id.to_string()
+        ),
+        _ => println!(
+            "Successfully reset two-factor authentication of user {}",
+            user_id.to_string()
+        ),
+    }
+
+    Ok(())
+}
@@ -1,5 +1,9 @@
 use std::{any::type_name, env, str::FromStr};
 
+use serde::Deserialize;
+
+use crate::utils::xid::Xid;
+
 #[derive(Clone)]
 pub struct Config {
     pub database_url: String,
@@ -7,6 +11,79 @@ pub struct Config {
     pub server_host_address: String,
     pub jwt_signing_key: String,
     pub frontend_url: String,
+    pub vault_reconcile_interval_seconds: u64,
+    pub job_workers: usize,
+    /// How long a refresh token can be used for after logging in, no matter how often it's used
+    pub refresh_token_lifetime_seconds: u64,
+    /// How long a refresh token can go unused before it expires
+    pub refresh_token_idle_timeout_seconds: u64,
+    /// Only set when single sign-on through an OpenID Connect provider is configured
+    pub oidc: Option<OidcConfig>,
+    /// Only set when logging in with a directory account is configured
+    pub ldap: Option<LdapConfig>,
+    /// Emails are printed instead of sent when no SMTP server is configured
+    pub smtp: Option<SmtpConfig>,
+    /// How long the link in a password reset email can be used for
+    pub password_reset_token_lifetime_seconds: u64,
+    /// How long an invite can be redeemed for after it was created
+    pub invite_lifetime_seconds: u64,
+    /// Failed logins for the same account before further attempts are slowed down
+    pub login_max_account_attempts: u32,
+    /// Failed logins from the same IP address before further attempts are slowed down
+    pub login_max_ip_attempts: u32,
+    /// The longest an account or IP address is locked out for, the lockout doubles with every failure until it's
+    /// reached
+    pub login_lockout_seconds: u64,
+}
+
+#[derive(Clone)]
+pub struct OidcConfig {
+    /// The provider's configuration is discovered from `<issuer_url>/.well-known/openid-configuration`
+    pub issuer_url: String,
+    pub client_id: String,
+    /// Not needed by public clients, which rely on PKCE alone
+    pub client_secret: Option<String>,
+    /// Where the provider sends the user back to, which has to be the frontend's OIDC login page
+    pub redirect_url: String,
+    pub scopes: String,
+    /// Shown on the login button
+    pub provider_name: String,
+    /// Whether users without an account are created on their first login, rather than turned away
+    pub auto_provision_users: bool,
+}
+
+#[derive(Clone)]
+pub struct SmtpConfig {
+    pub host: String,
+    pub port: u16,
+    pub security: SmtpSecurity,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Sender of all emails, e.g. `Floppy <floppy@example.com>`
+    pub from: String,
+}
+
+#[derive(Clone, Copy)]
+pub enum SmtpSecurity {
+    /// Connects with TLS right away, usually on port 465
+    Tls,
+    /// Upgrades the connection with STARTTLS, usually on port 587
+    StartTls,
+    /// Sends everything unencrypted, only meant for a mail server on the same machine or network
+    None,
+}
+
+impl FromStr for SmtpSecurity {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "tls" => Ok(SmtpSecurity::Tls),
+            "starttls" => Ok(SmtpSecurity::StartTls),
+            "none" => Ok(SmtpSecurity::None),
+            _ => Err(()),
+        }
+    }
 }
 
 fn load_env<T: FromStr>(key: &str) -> T {
@@ -23,6 +100,48 @@ fn load_env<T: FromStr>(key: &str) -> T {
     }
 }
 
+fn load_optional_env<T: FromStr>(key: &str) -> Option<T> {
+    let string = env::var(key).ok().filter(|value| !value.is_empty())?;
+
+    let parsed = string.parse::<T>();
+
+    match parsed {
+        Ok(value) => Some(value),
+        Err(_) => {
+            let type_name = type_name::<T>();
+            panic!("Expected {key} to be a valid {type_name} in your .env");
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct LdapConfig {
+    /// e.g. `ldaps://ldap.example.com` or `ldap://ldap.example.com:389`
+    pub url: String,
+    /// Upgrades plain `ldap://` connections with StartTLS
+    pub starttls: bool,
+    /// Account the user is searched for with, the search is anonymous if not set
+    pub bind_dn: Option<String>,
+    pub bind_password: Option<String>,
+    pub user_base_dn: String,
+    /// Search filter for the user's entry, `{username}` is replaced with what the user entered
+    pub user_filter: String,
+    pub name_attribute: String,
+    pub email_attribute: String,
+    /// Attribute of the user's entry listing the DNs of their groups
+    pub group_attribute: String,
+    pub group_vault_links: Vec<LdapGroupVaultLink>,
+}
+
+/// Gives the members of a directory group access to a vault, which is taken away again once they leave the group
+#[derive(Clone, Deserialize)]
+pub struct LdapGroupVaultLink {
+    pub group_dn: String,
+    pub vault_id: Xid,
+    #[serde(default)]
+    pub is_admin: bool,
+}
+
 pub fn load_config() -> Config {
     dotenv::dotenv().unwrap();
 
@@ -30,7 +149,70 @@ pub fn load_config() -> Config {
     let database_pool_size: u32 = load_env("DATABASE_POOL_SIZE");
     let server_host_address: String = load_env("SERVER_HOST_ADDRESS");
     let jwt_signing_key: String = load_env("JWT_SIGNING_KEY");
-    let frontend_url = load_env("FRONTEND_URL");
+    let frontend_url: String = load_env("FRONTEND_URL");
+    let vault_reconcile_interval_seconds: u64 =
+        load_optional_env("VAULT_RECONCILE_INTERVAL_SECONDS").unwrap_or(60 * 60);
+    let job_workers: usize = load_optional_env("JOB_WORKERS").unwrap_or(4);
+    let refresh_token_lifetime_seconds: u64 =
+        load_optional_env("REFRESH_TOKEN_LIFETIME_SECONDS").unwrap_or(60 * 60 * 24 * 30);
+    let refresh_token_idle_timeout_seconds: u64 =
+        load_optional_env("REFRESH_TOKEN_IDLE_TIMEOUT_SECONDS").unwrap_or(60 * 60 * 24 * 7);
+    let oidc = load_optional_env::<String>("OIDC_ISSUER_URL").map(|issuer_url| OidcConfig {
+        issuer_url: issuer_url.trim_end_matches('/').to_string(),
+        client_id: load_env("OIDC_CLIENT_ID"),
+        client_secret: load_optional_env("OIDC_CLIENT_SECRET"),
+        redirect_url: load_optional_env("OIDC_REDIRECT_URL")
+            .unwrap_or_else(|| format!("{}/login/oidc", frontend_url.trim_end_matches('/'))),
+        scopes: load_optional_env("OIDC_SCOPES")
+            .unwrap_or_else(|| "openid email profile".to_string()),
+        provider_name: load_optional_env("OIDC_PROVIDER_NAME").unwrap_or_else(|| "SSO".to_string()),
+        auto_provision_users: load_optional_env("OIDC_AUTO_PROVISION_USERS").unwrap_or(false),
+    });
+    let ldap = load_optional_env::<String>("LDAP_URL").map(|url| LdapConfig {
+        url,
+        starttls: load_optional_env("LDAP_STARTTLS").unwrap_or(false),
+        bind_dn: load_optional_env("LDAP_BIND_DN"),
+        bind_password: load_optional_env("LDAP_BIND_PASSWORD"),
+        user_base_dn: load_env("LDAP_USER_BASE_DN"),
+        user_filter: load_optional_env("LDAP_USER_FILTER")
+            .unwrap_or_else(|| "(|(uid={username})(mail={username}))".to_string()),
+        name_attribute: load_optional_env("LDAP_NAME_ATTRIBUTE").unwrap_or_else(|| "cn".to_string()),
+        email_attribute: load_optional_env("LDAP_EMAIL_ATTRIBUTE")
+            .unwrap_or_else(|| "mail".to_string()),
+        group_attribute: load_optional_env("LDAP_GROUP_ATTRIBUTE")
+            .unwrap_or_else(|| "memberOf".to_string()),
+        group_vault_links: load_optional_env::<String>("LDAP_GROUP_VAULT_LINKS")
+            .map(|links| {
+                serde_json::from_str(&links).unwrap_or_else(|_| {
+                    panic!("Expected LDAP_GROUP_VAULT_LINKS to be a JSON list of {{\"group_dn\", \"vault_id\", \"is_admin\"}} in your .env")
+                })
+            })
+            .unwrap_or_default(),
+    });
+    let smtp = load_optional_env::<String>("SMTP_HOST").map(|host| {
+        let security = load_optional_env("SMTP_SECURITY").unwrap_or(SmtpSecurity::StartTls);
+
+        SmtpConfig {
+            host,
+            port: load_optional_env("SMTP_PORT").unwrap_or(match security {
+                SmtpSecurity::Tls => 465,
+                SmtpSecurity::StartTls => 587,
+                SmtpSecurity::None => 25,
+            }),
+            security,
+            username: load_optional_env("SMTP_USERNAME"),
+            password: load_optional_env("SMTP_PASSWORD"),
+            from: load_env("SMTP_FROM"),
+        }
+    });
+    let password_reset_token_lifetime_seconds: u64 =
+        load_optional_env("PASSWORD_RESET_TOKEN_LIFETIME_SECONDS").unwrap_or(60 * 60);
+    let invite_lifetime_seconds: u64 =
+        load_optional_env("INVITE_LIFETIME_SECONDS").unwrap_or(60 * 60 * 24 * 7);
+    let login_max_account_attempts: u32 =
+        load_optional_env("LOGIN_MAX_ACCOUNT_ATTEMPTS").unwrap_or(5);
+    let login_max_ip_attempts: u32 = load_optional_env("LOGIN_MAX_IP_ATTEMPTS").unwrap_or(20);
+    let login_lockout_seconds: u64 = load_optional_env("LOGIN_LOCKOUT_SECONDS").unwrap_or(15 * 60);
 
     Config {
         database_url,
@@ -38,5 +220,17 @@ pub fn load_config() -> Config {
         server_host_address,
         jwt_signing_key,
         frontend_url,
+        vault_reconcile_interval_seconds,
+        job_workers,
+        refresh_token_lifetime_seconds,
+        refresh_token_idle_timeout_seconds,
+        oidc,
+        ldap,
+        smtp,
+        password_reset_token_lifetime_seconds,
+        invite_lifetime_seconds,
+        login_max_account_attempts,
+        login_max_ip_attempts,
+        login_lockout_seconds,
     }
 }
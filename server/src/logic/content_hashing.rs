@@ -0,0 +1,96 @@
+use std::{error::Error, fs::File, io, os::unix::fs::MetadataExt, path::Path, time::Duration};
+
+use sha2::{Digest, Sha256};
+
+use crate::{logic::indexing::local_folder::to_indexed_timestamp, utils::xid::Xid};
+
+const HASHER_BATCH_SIZE: i64 = 100;
+const HASHER_IDLE_INTERVAL: Duration = Duration::from_secs(30);
+
+pub fn sha256_file(path: &Path) -> Result<Vec<u8>, io::Error> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+
+    io::copy(&mut file, &mut hasher)?;
+
+    Ok(hasher.finalize().to_vec())
+}
+
+/// Hashes a single vault file and stores the result, returns whether the hash was stored. The hash isn't stored if the
+/// file changed since it was indexed or while it was being hashed, as it'll be reindexed (and have its hash cleared)
+/// anyway. Its size & modification time are read before hashing, so a write during hashing can't slip past the check.
+pub async fn hash_vault_file(
+    db: &sqlx::Pool<sqlx::Postgres>,
+    file_id: Xid,
+    path_id: String,
+) -> Result<bool, Box<dyn Error>> {
+    let (size, modified_at, content_sha256) = tokio::task::spawn_blocking(move || {
+        let path = Path::new(&path_id);
+        let metadata = path.metadata()?;
+        let size = metadata.size() as i64;
+        let modified_at = to_indexed_timestamp(metadata.modified());
+
+        sha256_file(path).map(|hash| (size, modified_at, hash))
+    })
+    .await??;
+
+    let result = sqlx::query!(
+        "UPDATE vault_files SET content_sha256 = $2 WHERE id = $1 AND size = $3 AND modified_at IS NOT DISTINCT FROM $4",
+        file_id.as_bytes(),
+        content_sha256,
+        size,
+        modified_at,
+    )
+    .execute(db)
+    .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+/// Lazily fills in the content hashes of local folder vault files which don't have one yet, forever
+pub async fn run_content_hasher(db: sqlx::Pool<sqlx::Postgres>) {
+    let mut after_id = vec![0_u8; 12];
+
+    loop {
+        let files = sqlx::query!(
+            "SELECT vault_files.id, vault_files.path_id FROM vault_files \
+                INNER JOIN vaults ON vaults.id = vault_files.vault_id \
+            WHERE \
+                vaults.provider = 'local_folder' AND vault_files.file_type = 'file' \
+                AND vault_files.content_sha256 IS NULL AND vault_files.id > $1 \
+            ORDER BY vault_files.id LIMIT $2",
+            after_id,
+            HASHER_BATCH_SIZE,
+        )
+        .fetch_all(&db)
+        .await;
+
+        let files = match files {
+            Ok(files) => files,
+            Err(error) => {
+                println!("An error occurred while fetching files to hash: {error}");
+                tokio::time::sleep(HASHER_IDLE_INTERVAL).await;
+                continue;
+            }
+        };
+
+        // Files which failed to hash are skipped until the next pass
+        if files.is_empty() {
+            after_id = vec![0_u8; 12];
+            tokio::time::sleep(HASHER_IDLE_INTERVAL).await;
+            continue;
+        }
+
+        for file in files {
+            after_id = file.id.clone();
+
+            if let Err(error) = hash_vault_file(&db, Xid::from(file.id), file.path_id.clone()).await
+            {
+                println!(
+                    "An error occurred while hashing file {:?}: {error}",
+                    file.path_id
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,74 @@
+use std::error::Error;
+
+use lettre::{
+    message::header::ContentType, transport::smtp::authentication::Credentials, AsyncSmtpTransport,
+    AsyncTransport, Message, Tokio1Executor,
+};
+
+use crate::config::{Config, SmtpConfig, SmtpSecurity};
+
+pub struct Email {
+    pub to: String,
+    pub subject: String,
+    /// Plain text
+    pub body: String,
+}
+
+fn build_transport(
+    config: &SmtpConfig,
+) -> Result<AsyncSmtpTransport<Tokio1Executor>, Box<dyn Error + Send + Sync>> {
+    let builder = match config.security {
+        SmtpSecurity::Tls => AsyncSmtpTransport::<Tokio1Executor>::relay(&config.host)?,
+        SmtpSecurity::StartTls => {
+            AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&config.host)?
+        }
+        SmtpSecurity::None => AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&config.host),
+    }
+    .port(config.port);
+
+    let builder = match (&config.username, &config.password) {
+        (Some(username), Some(password)) => {
+            builder.credentials(Credentials::new(username.clone(), password.clone()))
+        }
+        _ => builder,
+    };
+
+    Ok(builder.build())
+}
+
+/// Sends an email through the configured SMTP server, or prints it if there is none so it can still be read during
+/// development
+pub async fn send_email(config: &Config, email: Email) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let Some(smtp) = &config.smtp else {
+        println!(
+            "SMTP isn't configured, not sending email to {} ({}):\n{}",
+            email.to, email.subject, email.body
+        );
+        return Ok(());
+    };
+
+    let message = Message::builder()
+        .from(smtp.from.parse()?)
+        .to(email.to.parse()?)
+        .subject(email.subject)
+        .header(ContentType::TEXT_PLAIN)
+        .body(email.body)?;
+
+    build_transport(smtp)?.send(message).await?;
+
+    Ok(())
+}
+
+/// Sends an email without waiting for it to be delivered, so how long a request takes doesn't depend on whether an
+/// email was sent
+pub fn send_email_in_background(config: &Config, email: Email) {
+    let config = config.clone();
+
+    tokio::spawn(async move {
+        let to = email.to.clone();
+
+        if let Err(error) = send_email(&config, email).await {
+            println!("Failed to send email to {to}: {error}");
+        }
+    });
+}
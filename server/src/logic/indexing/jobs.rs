@@ -0,0 +1,354 @@
+use std::{
+    error::Error,
+    fmt::Display,
+    time::{Duration, Instant},
+};
+
+use chrono::Utc;
+use serde::Deserialize;
+use tokio::task::JoinHandle;
+
+use crate::{models::vaults::Vault, utils::xid::Xid};
+
+use super::local_folder::{reconcile_local_folder_vault, reindex_local_folder_vault};
+
+const PROGRESS_REPORT_INTERVAL: Duration = Duration::from_secs(1);
+const MAX_RECORDED_ERRORS: usize = 100;
+/// How long a running job stays locked to whoever runs it without a heartbeat, after which it's considered interrupted
+const INDEX_JOB_LOCK_DURATION: Duration = Duration::from_secs(60);
+const INDEX_JOB_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(20);
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IndexJobKind {
+    /// Throws away the index of the vault and builds it again from scratch
+    Reindex,
+    /// Brings the index of the vault in line with what's on disk, keeping the ids of unchanged files
+    #[default]
+    Reconcile,
+}
+
+impl IndexJobKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            IndexJobKind::Reindex => "reindex",
+            IndexJobKind::Reconcile => "reconcile",
+        }
+    }
+
+    fn parse(kind: &str) -> Option<Self> {
+        match kind {
+            "reindex" => Some(IndexJobKind::Reindex),
+            "reconcile" => Some(IndexJobKind::Reconcile),
+            _ => None,
+        }
+    }
+}
+
+/// A run of indexing over a vault, recorded in `vault_index_jobs`. Progress is written out at most once every
+/// [`PROGRESS_REPORT_INTERVAL`] so it can be polled without slowing the run down.
+pub struct IndexJob {
+    db: sqlx::Pool<sqlx::Postgres>,
+    pub id: Xid,
+    pub kind: IndexJobKind,
+    files_total: Option<i64>,
+    files_seen: i64,
+    error_count: i64,
+    errors: Vec<String>,
+    last_reported_at: Instant,
+    heartbeat: JoinHandle<()>,
+}
+
+impl IndexJob {
+    fn new(db: &sqlx::Pool<sqlx::Postgres>, id: Xid, kind: IndexJobKind) -> IndexJob {
+        IndexJob {
+            db: db.clone(),
+            id,
+            kind,
+            files_total: None,
+            files_seen: 0,
+            error_count: 0,
+            errors: vec![],
+            last_reported_at: Instant::now(),
+            heartbeat: tokio::spawn(send_index_job_heartbeats(db.clone(), id)),
+        }
+    }
+
+    /// Records a job which is run right away, rather than through the job queue
+    pub async fn start(
+        db: &sqlx::Pool<sqlx::Postgres>,
+        vault_id: Xid,
+        kind: IndexJobKind,
+    ) -> Result<IndexJob, sqlx::Error> {
+        let id = Xid::new();
+
+        sqlx::query!(
+            "INSERT INTO vault_index_jobs (id, vault_id, kind, state, started_at, locked_until) VALUES ($1, $2, $3, 'running', NOW(), $4)",
+            id.as_bytes(),
+            vault_id.as_bytes(),
+            kind.as_str(),
+            Utc::now() + INDEX_JOB_LOCK_DURATION,
+        )
+        .execute(db)
+        .await?;
+
+        Ok(IndexJob::new(db, id, kind))
+    }
+
+    /// Records a job which is left pending until it's picked up from the job queue, returning its id
+    pub async fn queue<'e>(
+        db: impl sqlx::PgExecutor<'e>,
+        vault_id: Xid,
+        kind: IndexJobKind,
+    ) -> Result<Xid, sqlx::Error> {
+        let id = Xid::new();
+
+        sqlx::query!(
+            "INSERT INTO vault_index_jobs (id, vault_id, kind) VALUES ($1, $2, $3)",
+            id.as_bytes(),
+            vault_id.as_bytes(),
+            kind.as_str(),
+        )
+        .execute(db)
+        .await?;
+
+        Ok(id)
+    }
+
+    /// Starts (or restarts, when retried) a queued job from scratch, returning `None` if it's gone
+    pub async fn resume(
+        db: &sqlx::Pool<sqlx::Postgres>,
+        id: Xid,
+    ) -> Result<Option<IndexJob>, Box<dyn Error>> {
+        let record = sqlx::query!(
+            "UPDATE vault_index_jobs SET \
+                state = 'running', started_at = NOW(), finished_at = NULL, locked_until = $2, \
+                files_total = NULL, files_seen = 0, error_count = 0, errors = '{}' \
+            WHERE id = $1 \
+            RETURNING kind",
+            id.as_bytes(),
+            Utc::now() + INDEX_JOB_LOCK_DURATION,
+        )
+        .fetch_optional(db)
+        .await?;
+
+        match record {
+            None => Ok(None),
+            Some(record) => {
+                let kind = IndexJobKind::parse(&record.kind)
+                    .ok_or_else(|| format!("Unknown index job kind {:?}", record.kind))?;
+
+                Ok(Some(IndexJob::new(db, id, kind)))
+            }
+        }
+    }
+
+    pub async fn set_files_total(&mut self, files_total: usize) -> Result<(), sqlx::Error> {
+        self.files_total = Some(files_total as i64);
+        self.report().await
+    }
+
+    /// Sets a guess of how many files the run will go through until [`IndexJob::set_files_total`] is called with the
+    /// actual number, so progress can be shown in the meantime. Nothing is set without anything to go on.
+    pub async fn estimate_files_total(&mut self, estimate: usize) -> Result<(), sqlx::Error> {
+        if estimate == 0 {
+            return Ok(());
+        }
+
+        self.set_files_total(estimate).await
+    }
+
+    pub async fn file_seen(&mut self) -> Result<(), sqlx::Error> {
+        self.files_seen += 1;
+        // The estimate was too low, progress stays at 99% until the run is done
+        self.files_total = self
+            .files_total
+            .map(|files_total| files_total.max(self.files_seen));
+
+        if self.last_reported_at.elapsed() >= PROGRESS_REPORT_INTERVAL {
+            self.report().await?;
+        }
+
+        Ok(())
+    }
+
+    /// Records an error which didn't stop the run
+    pub fn error(&mut self, error: impl Display) {
+        self.error_count += 1;
+
+        if self.errors.len() < MAX_RECORDED_ERRORS {
+            self.errors.push(error.to_string());
+        }
+    }
+
+    async fn report(&mut self) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            "UPDATE vault_index_jobs SET files_total = $2, files_seen = $3, error_count = $4, errors = $5 WHERE id = $1",
+            self.id.as_bytes(),
+            self.files_total,
+            self.files_seen,
+            self.error_count,
+            &self.errors,
+        )
+        .execute(&self.db)
+        .await?;
+
+        self.last_reported_at = Instant::now();
+
+        Ok(())
+    }
+
+    /// Records the outcome of the run, the error being the one which stopped it (if any)
+    pub async fn finish(mut self, error: Option<String>) -> Result<(), sqlx::Error> {
+        let state = match error {
+            None => "succeeded",
+            Some(error) => {
+                self.error(error);
+                "failed"
+            }
+        };
+
+        sqlx::query!(
+            "UPDATE vault_index_jobs SET \
+                state = $2, finished_at = NOW(), locked_until = NULL, \
+                files_total = $3, files_seen = $4, error_count = $5, errors = $6 \
+            WHERE id = $1 AND state = 'running'",
+            self.id.as_bytes(),
+            state,
+            self.files_total,
+            self.files_seen,
+            self.error_count,
+            &self.errors,
+        )
+        .execute(&self.db)
+        .await?;
+
+        Ok(())
+    }
+}
+
+impl Drop for IndexJob {
+    /// A job which is dropped without being finished was interrupted, which is noticed once its lock runs out
+    fn drop(&mut self) {
+        self.heartbeat.abort();
+    }
+}
+
+/// Keeps extending the lock on a running job for as long as whoever runs it is still around
+async fn send_index_job_heartbeats(db: sqlx::Pool<sqlx::Postgres>, id: Xid) {
+    loop {
+        tokio::time::sleep(INDEX_JOB_HEARTBEAT_INTERVAL).await;
+
+        let result = sqlx::query!(
+            "UPDATE vault_index_jobs SET locked_until = $2 WHERE id = $1 AND state = 'running'",
+            id.as_bytes(),
+            Utc::now() + INDEX_JOB_LOCK_DURATION,
+        )
+        .execute(&db)
+        .await;
+
+        match result {
+            // Finished or cancelled
+            Ok(result) if result.rows_affected() == 0 => return,
+            Ok(_) => {}
+            Err(error) => println!(
+                "An error occurred while sending a heartbeat for index job {}: {error}",
+                id.to_string()
+            ),
+        }
+    }
+}
+
+/// Runs an index job to completion and records its outcome
+pub async fn run_index_job(
+    db: &sqlx::Pool<sqlx::Postgres>,
+    vault: &Vault,
+    mut job: IndexJob,
+) -> Result<(), Box<dyn Error>> {
+    let result = match job.kind {
+        IndexJobKind::Reindex => reindex_local_folder_vault(db, vault, &mut job)
+            .await
+            .map(|_| ()),
+        IndexJobKind::Reconcile => reconcile_local_folder_vault(db, vault, &mut job)
+            .await
+            .map(|_| ()),
+    };
+    let error = result.err().map(|error| error.to_string());
+
+    job.finish(error.clone()).await?;
+
+    match error {
+        None => Ok(()),
+        Some(error) => Err(error.into()),
+    }
+}
+
+/// Runs an index job which was picked up from the job queue
+pub async fn run_queued_index_job(
+    db: &sqlx::Pool<sqlx::Postgres>,
+    vault_id: Xid,
+    index_job_id: Xid,
+) -> Result<(), Box<dyn Error>> {
+    let vault = sqlx::query_as!(
+        Vault,
+        "SELECT id, name, provider, data FROM vaults WHERE id = $1",
+        vault_id.as_bytes(),
+    )
+    .fetch_optional(db)
+    .await?;
+
+    // Deleting the vault deletes its index jobs too, so there's nothing left to do either way
+    let Some(vault) = vault else {
+        return Ok(());
+    };
+    let Some(job) = IndexJob::resume(db, index_job_id).await? else {
+        return Ok(());
+    };
+
+    run_index_job(db, &vault, job).await
+}
+
+pub async fn cancel_index_job(
+    db: &sqlx::Pool<sqlx::Postgres>,
+    index_job_id: Xid,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "UPDATE vault_index_jobs SET state = 'cancelled', finished_at = NOW() WHERE id = $1 AND state IN ('pending', 'running')",
+        index_job_id.as_bytes(),
+    )
+    .execute(db)
+    .await?;
+
+    Ok(())
+}
+
+/// Marks running jobs whose lock ran out as failed, as whoever ran them stopped (e.g. the server or a CLI run being
+/// killed) and nothing will ever finish them. Returns how many jobs were failed.
+pub async fn fail_interrupted_index_jobs(
+    db: &sqlx::Pool<sqlx::Postgres>,
+) -> Result<u64, sqlx::Error> {
+    let result = sqlx::query!(
+        "UPDATE vault_index_jobs SET \
+            state = 'failed', finished_at = NOW(), locked_until = NULL, error_count = error_count + 1, \
+            errors = ARRAY_APPEND(errors, 'Interrupted before it could finish') \
+        WHERE state = 'running' AND (locked_until IS NULL OR locked_until < NOW())"
+    )
+    .execute(db)
+    .await?;
+
+    Ok(result.rows_affected())
+}
+
+pub async fn run_interrupted_index_job_cleanup(db: sqlx::Pool<sqlx::Postgres>) {
+    loop {
+        match fail_interrupted_index_jobs(&db).await {
+            Ok(0) => {}
+            Ok(count) => println!("Failed {count} interrupted index jobs"),
+            Err(error) => {
+                println!("An error occurred while failing interrupted index jobs: {error}")
+            }
+        }
+
+        tokio::time::sleep(INDEX_JOB_LOCK_DURATION).await;
+    }
+}
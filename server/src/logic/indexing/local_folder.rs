@@ -1,128 +1,417 @@
 use std::{
-    collections::HashMap, error::Error, fs, os::unix::fs::MetadataExt, path::PathBuf, str::FromStr,
-    sync::mpsc, thread,
+    collections::HashMap,
+    error::Error,
+    fs, io,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    sync::mpsc::{self, RecvTimeoutError},
+    thread,
+    time::{Duration, Instant, SystemTime},
 };
 
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, SubsecRound, Utc};
 use notify::{EventKind, Watcher};
+use serde::Deserialize;
 
 use crate::{
-    models::vaults::{Vault, VaultFile},
+    config::Config,
+    models::vaults::{Vault, VaultFileMediaMetadata},
     utils::{
-        folders::{walk_directory, FileType},
+        folders::{
+            exceeds_max_depth, resolve_file_type, walk_directory, DirectoryWalk, FileType,
+            SymlinkPolicy, WalkOptions,
+        },
+        ignore_rules::{IgnoreRules, IGNORE_FILE_NAME},
+        media_metadata::{can_extract_media_metadata, extract_media_metadata},
+        mime::detect_mime_type,
+        text_extraction::{can_extract_text, extract_text},
         xid::Xid,
     },
 };
 
+use super::jobs::{IndexJob, IndexJobKind};
+
+struct LocalFileMetadata {
+    created_at: Option<DateTime<Utc>>,
+    modified_at: Option<DateTime<Utc>>,
+    size: Option<i64>,
+    mode: Option<i32>,
+    symlink_target: Option<String>,
+}
+
+// Postgres only stores timestamps with microsecond precision, so they're truncated up front to be comparable with what's
+// already indexed
+pub fn to_indexed_timestamp(time: io::Result<SystemTime>) -> Option<DateTime<Utc>> {
+    time.ok()
+        .map(|time| DateTime::<Utc>::from(time).trunc_subsecs(6))
+}
+
+fn read_local_file_metadata(path: &Path, file_type: &FileType) -> LocalFileMetadata {
+    let symlink_target = match path.symlink_metadata() {
+        Ok(metadata) if metadata.is_symlink() => fs::read_link(path)
+            .ok()
+            .map(|target| target.to_string_lossy().to_string()),
+        _ => None,
+    };
+
+    // Links which are shown as links describe themselves, everything else describes what it points to
+    let metadata = match file_type {
+        FileType::Symlink => path.symlink_metadata(),
+        _ => path.metadata(),
+    };
+
+    match metadata {
+        Err(_) => LocalFileMetadata {
+            created_at: None,
+            modified_at: None,
+            size: None,
+            mode: None,
+            symlink_target,
+        },
+        Ok(metadata) => LocalFileMetadata {
+            created_at: to_indexed_timestamp(metadata.created()),
+            modified_at: to_indexed_timestamp(metadata.modified()),
+            size: Some(metadata.size() as i64),
+            mode: Some(metadata.mode() as i32),
+            symlink_target,
+        },
+    }
+}
+
+fn is_hidden_file_name(name: &str) -> bool {
+    name.starts_with('.')
+}
+
+fn detect_local_file_mime_type(path: &Path, file_type: &FileType) -> Option<String> {
+    match file_type {
+        FileType::File => Some(detect_mime_type(path)),
+        FileType::Folder | FileType::Symlink => None,
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LocalFolderWatcherKind {
+    /// The platform's native watcher (inotify on Linux)
+    #[default]
+    Native,
+    /// Periodically scans the folder, for filesystems which don't deliver native events (NFS, SMB, etc.)
+    Poll,
+}
+
+/// Polling any more often than this would keep the watcher busy walking the folder
+const MIN_POLL_INTERVAL_SECONDS: u64 = 1;
+
+fn default_poll_interval_seconds() -> u64 {
+    30
+}
+
+fn deserialize_poll_interval_seconds<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<u64, D::Error> {
+    Ok(u64::deserialize(deserializer)?.max(MIN_POLL_INTERVAL_SECONDS))
+}
+
+/// The `data` of a vault with the `local_folder` provider
+#[derive(Debug, Deserialize)]
+pub struct LocalFolderVaultData {
+    pub path: PathBuf,
+    #[serde(default)]
+    pub watcher: LocalFolderWatcherKind,
+    #[serde(
+        default = "default_poll_interval_seconds",
+        deserialize_with = "deserialize_poll_interval_seconds"
+    )]
+    pub poll_interval_seconds: u64,
+    #[serde(default)]
+    pub symlinks: SymlinkPolicy,
+    /// Gitignore-style patterns applied to the whole vault, on top of any `.floppyignore` files inside it
+    #[serde(default)]
+    pub ignore: Vec<String>,
+    /// How many levels of folders below the root are indexed, no limit if not set
+    #[serde(default)]
+    pub max_depth: Option<usize>,
+}
+
+impl LocalFolderVaultData {
+    pub fn from_vault(vault: &Vault) -> Result<Self, serde_json::Error> {
+        Self::from_data(&vault.data)
+    }
+
+    pub fn from_data(data: &serde_json::Value) -> Result<Self, serde_json::Error> {
+        serde_json::from_value(data.clone())
+    }
+
+    pub fn ignore_rules(&self) -> Result<IgnoreRules, ignore::Error> {
+        IgnoreRules::new(&self.path, &self.ignore)
+    }
+
+    pub fn walk(&self) -> Result<DirectoryWalk, Box<dyn Error>> {
+        Ok(walk_directory(
+            self.path.clone(),
+            WalkOptions::new(self.symlinks, self.max_depth),
+            self.ignore_rules()?,
+        )?)
+    }
+}
+
+/// Resolves the path of an indexed file to where it actually lives on disk, returning `None` if that's outside of the
+/// vault root (which following a symlink could otherwise lead to)
+pub async fn resolve_local_folder_vault_file_path(
+    vault_data: &serde_json::Value,
+    path_id: &str,
+) -> Result<Option<PathBuf>, Box<dyn Error>> {
+    let vault_data = LocalFolderVaultData::from_data(vault_data)?;
+
+    let vault_root = tokio::fs::canonicalize(&vault_data.path).await?;
+    let path = tokio::fs::canonicalize(path_id).await?;
+
+    match path.starts_with(&vault_root) {
+        true => Ok(Some(path)),
+        false => Ok(None),
+    }
+}
+
+// inotify_init fails with EMFILE once fs.inotify.max_user_instances is reached
+const EMFILE: i32 = 24;
+
+fn is_watch_limit_error(error: &notify::Error) -> bool {
+    match &error.kind {
+        notify::ErrorKind::MaxFilesWatch => true,
+        notify::ErrorKind::Io(io_error) => io_error.raw_os_error() == Some(EMFILE),
+        _ => false,
+    }
+}
+
+fn create_local_folder_vault_poll_watcher(
+    vault_data: &LocalFolderVaultData,
+    fs_events_tx: mpsc::Sender<notify::Result<notify::Event>>,
+) -> notify::Result<Box<dyn Watcher>> {
+    let watcher_config = notify::Config::default()
+        .with_poll_interval(Duration::from_secs(vault_data.poll_interval_seconds));
+
+    let mut watcher = notify::PollWatcher::new(fs_events_tx, watcher_config)?;
+    watcher.watch(&vault_data.path, notify::RecursiveMode::Recursive)?;
+
+    Ok(Box::new(watcher))
+}
+
+/// Creates the watcher configured for the vault, returning which kind it ended up being since the native one falls
+/// back to polling
+fn create_local_folder_vault_watcher(
+    vault: &Vault,
+    vault_data: &LocalFolderVaultData,
+    fs_events_tx: mpsc::Sender<notify::Result<notify::Event>>,
+) -> notify::Result<(Box<dyn Watcher>, LocalFolderWatcherKind)> {
+    if vault_data.watcher == LocalFolderWatcherKind::Poll {
+        let watcher = create_local_folder_vault_poll_watcher(vault_data, fs_events_tx)?;
+        return Ok((watcher, LocalFolderWatcherKind::Poll));
+    }
+
+    let native_watcher =
+        notify::recommended_watcher(fs_events_tx.clone()).and_then(|mut watcher| {
+            watcher.watch(&vault_data.path, notify::RecursiveMode::Recursive)?;
+            Ok(watcher)
+        });
+
+    match native_watcher {
+        Ok(watcher) => Ok((Box::new(watcher), LocalFolderWatcherKind::Native)),
+        Err(error) if is_watch_limit_error(&error) => {
+            println!(
+                "Warning: Native watcher for vault {} hit the OS watch limit ({error}), falling back to polling every {} seconds",
+                vault.id.to_string(),
+                vault_data.poll_interval_seconds
+            );
+            let watcher = create_local_folder_vault_poll_watcher(vault_data, fs_events_tx)?;
+            Ok((watcher, LocalFolderWatcherKind::Poll))
+        }
+        Err(error) => Err(error),
+    }
+}
+
+async fn handle_local_folder_vault_event(
+    db: &sqlx::Pool<sqlx::Postgres>,
+    vault: &Vault,
+    vault_data: &LocalFolderVaultData,
+    vault_root: &Path,
+    ignore_rules: &mut IgnoreRules,
+    event: notify::Event,
+) -> Result<(), Box<dyn Error>> {
+    // The polling watcher reports the vault root itself as modified whenever its children change
+    let paths = event.paths.into_iter().filter(|p| p != &vault_data.path);
+
+    // We get two modifies for a rename... one to yeet the old file and one for the new one, reindexing checks whether
+    // the path still exists & removes/adds it accordingly.
+    match event.kind {
+        EventKind::Create(_) | EventKind::Modify(_) => {
+            // Anything too deep to be walked was never indexed in the first place
+            let paths =
+                paths.filter(|p| !exceeds_max_depth(p, &vault_data.path, vault_data.max_depth));
+
+            for path in paths {
+                reindex_local_folder_vault_file(
+                    db,
+                    vault.id,
+                    vault_root,
+                    vault_data.symlinks,
+                    ignore_rules,
+                    path,
+                )
+                .await?;
+            }
+        }
+        EventKind::Remove(_) => {
+            sqlx::query!(
+                "DELETE FROM vault_files WHERE vault_id = $1 AND path_id = ANY($2)",
+                vault.id.as_bytes(),
+                &paths
+                    .map(|p| p.to_string_lossy().to_string())
+                    .collect::<Vec<_>>()
+            )
+            .execute(db)
+            .await?;
+        }
+        _ => {}
+    }
+
+    Ok(())
+}
+
 fn watch_local_folder_vault(
     db: sqlx::Pool<sqlx::Postgres>,
+    async_runtime: tokio::runtime::Handle,
     vault: Vault,
-) -> Result<(), Box<dyn Error + Send>> {
-    let vault_path = PathBuf::from_str(vault.data["path"].as_str().unwrap()).unwrap();
-    fs::create_dir_all(&vault_path).unwrap();
-
-    let async_runtime = tokio::runtime::Builder::new_current_thread()
-        .enable_all()
-        .build()
-        .unwrap();
+    reconcile_interval: Option<Duration>,
+) -> Result<(), Box<dyn Error>> {
+    let vault_data = LocalFolderVaultData::from_vault(&vault)?;
+    let vault_path = vault_data.path.clone();
+    fs::create_dir_all(&vault_path)?;
+    let vault_root = vault_path.canonicalize()?;
+    let mut ignore_rules = vault_data.ignore_rules()?;
 
     let (fs_events_tx, fs_events_rx) = mpsc::channel::<notify::Result<notify::Event>>();
 
-    let mut watcher = notify::recommended_watcher(fs_events_tx).unwrap();
+    // Dropping the watcher stops it, so it has to be kept around for as long as we're receiving events
+    let (mut watcher, mut watcher_kind) =
+        create_local_folder_vault_watcher(&vault, &vault_data, fs_events_tx.clone())?;
 
-    watcher
-        .watch(&vault_path, notify::RecursiveMode::Recursive)
-        .unwrap();
+    // Anything that changed while the server was down is only caught by reconciling, this is done after the watcher is
+    // set up so changes made during the scan aren't missed either.
+    async_runtime.block_on(reconcile_local_folder_vault_and_log(&db, &vault));
 
-    for event in fs_events_rx {
-        let result: Result<(), Box<dyn Error>> = match event {
-            Err(error) => {
-                Err(error.into())
+    let mut next_reconcile_at = reconcile_interval.map(|interval| Instant::now() + interval);
+
+    loop {
+        let event = match next_reconcile_at {
+            None => match fs_events_rx.recv() {
+                Ok(event) => Some(event),
+                Err(_) => break,
             },
-            Ok(event) => async_runtime.block_on(async {
-                struct EventFile {
-                    pathbuf: PathBuf,
-                    path: String,
-                    name: String,
-                    file_type: FileType,
+            Some(reconcile_at) => {
+                match fs_events_rx
+                    .recv_timeout(reconcile_at.saturating_duration_since(Instant::now()))
+                {
+                    Ok(event) => Some(event),
+                    Err(RecvTimeoutError::Timeout) => None,
+                    Err(RecvTimeoutError::Disconnected) => break,
                 }
+            }
+        };
 
-                let event_files = event.paths.iter().filter(|f| f != &&vault_path).filter_map(|p| {
-                    let file_type: FileType;
-                    if p.is_dir() {
-                        file_type = FileType::Folder;
-                    } else if p.is_file() {
-                        file_type = FileType::File;
-                    } else {
-                        return None;
-                    }
+        // Folders created since the native watcher started can run into the OS watch limit as well
+        let mut watch_limit_error = None;
+
+        let needs_reconcile = match event {
+            // The reconcile interval has elapsed
+            None => true,
+            Some(Err(error)) if is_watch_limit_error(&error) => {
+                watch_limit_error = Some(error);
+                true
+            }
+            Some(Err(error)) => {
+                println!(
+                    "An error occurred while watching path {vault_path:?} for vault {}: {error}",
+                    vault.id.to_string()
+                );
+                true
+            }
+            // The watcher missed events (e.g. the inotify queue overflowed)
+            Some(Ok(event)) if event.need_rescan() => true,
+            Some(Ok(event)) => {
+                // Changing an ignore file can (un)ignore anything below it, which is only caught by reconciling
+                let changed_ignore_files = match event.kind {
+                    EventKind::Create(_) | EventKind::Modify(_) | EventKind::Remove(_) => event
+                        .paths
+                        .iter()
+                        .filter(|p| p.file_name().is_some_and(|name| name == IGNORE_FILE_NAME))
+                        .collect::<Vec<_>>(),
+                    _ => vec![],
+                };
+
+                for ignore_file in &changed_ignore_files {
+                    ignore_rules.reload(ignore_file.parent().unwrap());
+                }
 
-                    Some(EventFile {
-                        pathbuf: p.to_path_buf(),
-                        path: p.as_os_str().to_string_lossy().to_string(),
-                        name: p.file_name().unwrap().to_string_lossy().to_string(),
-                        file_type,
-                    })
-                }).collect::<Vec<_>>();
-
-                // We get two modifies for a rename... one to yeet the old file and one for the new one, you have to
-                // run some code to check if the path exists & remove/add it.
-                match event.kind {
-                    EventKind::Create(_) => {
-                        for file in event_files {
-                            let file_id = Xid::new();
-
-                            let (created_at, file_size) = match file.pathbuf.metadata() {
-                                Err(_) => (None, None),
-                                Ok(metadata) => (
-                                    metadata
-                                        .created()
-                                        .map(|created_at| Some(DateTime::<Utc>::from(created_at)))
-                                        .unwrap_or(None),
-                                    Some(metadata.size() as i64),
-                                ),
-                            };
-
-                            let parent_id = sqlx::query!(
-                                "SELECT id FROM vault_files WHERE vault_id = $1 AND path_id = $2",
-                                vault.id.as_bytes(), file.pathbuf.parent().unwrap().to_string_lossy().to_string(),
-                            ).fetch_optional(&db)
-                            .await?;
-                            let parent_id = parent_id.map(|r| r.id);
-
-                            let file_type = match file.file_type {
-                                FileType::Folder => "folder",
-                                FileType::File => "file",
-                            };
-
-                            sqlx::query!(
-                                "INSERT INTO vault_files (id, vault_id, path_id, name, file_type, parent_id, created_at, size) VALUES ($1, $2, $3, $4, $5, $6, $7, $8)",
-                                file_id.as_bytes(), vault.id.as_bytes(), file.path, file.name, file_type, parent_id, created_at, file_size,
-                            ).execute(&db)
-                            .await?;
-                        }
-                    }
-                    EventKind::Remove(_) => {
-                        sqlx::query!(
-                            "DELETE FROM vault_files WHERE vault_id = $1 AND path_id = ANY($2)",
-                            vault.id.as_bytes(), &event_files.into_iter().map(|f| f.path).collect::<Vec<_>>()
-                        ).execute(&db)
-                        .await?;
-                    },
-                    EventKind::Modify(_) => {
-                        for path in event.paths {
-                            reindex_local_folder_vault_file(&db, vault.id, path).await?;
+                let needs_reconcile = !changed_ignore_files.is_empty();
+
+                // The native watcher adds watches for new folders by itself but swallows any errors, watching them
+                // again is a no-op that reports whether the limit was hit
+                if watcher_kind == LocalFolderWatcherKind::Native
+                    && matches!(event.kind, EventKind::Create(_))
+                {
+                    for path in event.paths.iter().filter(|p| p.is_dir()) {
+                        match watcher.watch(path, notify::RecursiveMode::Recursive) {
+                            Err(error) if is_watch_limit_error(&error) => {
+                                watch_limit_error = Some(error);
+                                break;
+                            }
+                            _ => {}
                         }
                     }
-                    _ => {},
                 }
 
-                Ok(())
-            })
+                let result = async_runtime.block_on(handle_local_folder_vault_event(
+                    &db,
+                    &vault,
+                    &vault_data,
+                    &vault_root,
+                    &mut ignore_rules,
+                    event,
+                ));
+
+                if let Err(error) = result {
+                    println!("An error occurred while handling event for path {vault_path:?} for vault {}: {error}", vault.id.to_string())
+                }
+
+                needs_reconcile || watch_limit_error.is_some()
+            }
         };
 
-        if let Err(error) = result {
-            println!("An error occurred while handling event for path {vault_path:?} for vault {}: {error}", vault.id.to_string())
+        if let Some(error) = watch_limit_error {
+            if watcher_kind == LocalFolderWatcherKind::Native {
+                println!(
+                    "Warning: Native watcher for vault {} hit the OS watch limit ({error}), switching to polling every {} seconds",
+                    vault.id.to_string(),
+                    vault_data.poll_interval_seconds
+                );
+
+                // Replacing the watcher drops the native one, which frees up its watches
+                match create_local_folder_vault_poll_watcher(&vault_data, fs_events_tx.clone()) {
+                    Ok(poll_watcher) => {
+                        watcher = poll_watcher;
+                        watcher_kind = LocalFolderWatcherKind::Poll;
+                    }
+                    Err(error) => println!(
+                        "An error occurred while switching vault {} to polling: {error}",
+                        vault.id.to_string()
+                    ),
+                }
+            }
+        }
+
+        if needs_reconcile {
+            async_runtime.block_on(reconcile_local_folder_vault_and_log(&db, &vault));
+            next_reconcile_at = reconcile_interval.map(|interval| Instant::now() + interval);
         }
     }
 
@@ -130,6 +419,7 @@ fn watch_local_folder_vault(
 }
 
 pub async fn setup_local_folder_vault_watchers(
+    config: &Config,
     db: sqlx::Pool<sqlx::Postgres>,
 ) -> Result<(), Box<dyn Error>> {
     let vaults = sqlx::query_as!(
@@ -139,47 +429,255 @@ pub async fn setup_local_folder_vault_watchers(
     .fetch_all(&db)
     .await?;
 
+    let reconcile_interval = match config.vault_reconcile_interval_seconds {
+        0 => None,
+        seconds => Some(Duration::from_secs(seconds)),
+    };
+
+    // Database work has to run on the server's runtime, pooled connections opened on a runtime of the watcher thread
+    // would stall once they're used elsewhere while that thread is waiting for events
+    let async_runtime = tokio::runtime::Handle::current();
+
     for vault in vaults {
         let db = db.clone();
-        thread::spawn(|| watch_local_folder_vault(db, vault));
+        let async_runtime = async_runtime.clone();
+        thread::spawn(move || {
+            let vault_id = vault.id;
+
+            if let Err(error) =
+                watch_local_folder_vault(db, async_runtime, vault, reconcile_interval)
+            {
+                println!(
+                    "Stopped watching vault {} due to an error: {error}",
+                    vault_id.to_string()
+                );
+            }
+        });
     }
 
     Ok(())
 }
 
+/// Everything indexed about a file which has to be read from disk
+struct LocalFileContents {
+    metadata: LocalFileMetadata,
+    mime_type: Option<String>,
+    content: Option<String>,
+    media_metadata: Option<VaultFileMediaMetadata>,
+}
+
+fn read_local_file_contents(path: &Path, file_type: &FileType) -> LocalFileContents {
+    let metadata = read_local_file_metadata(path, file_type);
+    let mime_type = detect_local_file_mime_type(path, file_type);
+
+    // Only files whose contents might have changed are upserted, so their text is always extracted again
+    let content = mime_type
+        .as_deref()
+        .filter(|mime_type| can_extract_text(path, mime_type))
+        .map(|mime_type| {
+            extract_text(path, mime_type).unwrap_or_else(|error| {
+                println!("An error occurred while extracting the text of {path:?}: {error}");
+                String::new()
+            })
+        });
+    let media_metadata = mime_type
+        .as_deref()
+        .filter(|mime_type| can_extract_media_metadata(mime_type))
+        .map(|mime_type| {
+            extract_media_metadata(path, mime_type).unwrap_or_else(|error| {
+                println!(
+                    "An error occurred while extracting the media metadata of {path:?}: {error}"
+                );
+                Default::default()
+            })
+        });
+
+    LocalFileContents {
+        metadata,
+        mime_type,
+        content,
+        media_metadata,
+    }
+}
+
+/// Inserts or updates the index entry for a file on disk, returning its id
+async fn upsert_local_vault_file(
+    db: &mut sqlx::PgConnection,
+    vault_id: Xid,
+    path: &Path,
+    file_type: &FileType,
+    parent_id: Option<&[u8]>,
+) -> Result<Xid, Box<dyn Error>> {
+    let id = Xid::new();
+    let path_id = path.to_string_lossy().to_string();
+    let name = path.file_name().unwrap().to_string_lossy().to_string();
+
+    // Detecting the MIME type & extracting text or metadata reads the file, which would hold up the other tasks of
+    // the runtime (like the heartbeat of the job doing the indexing)
+    let LocalFileContents {
+        metadata,
+        mime_type,
+        content,
+        media_metadata,
+    } = {
+        let path = path.to_path_buf();
+        let file_type = file_type.clone();
+        tokio::task::spawn_blocking(move || read_local_file_contents(&path, &file_type)).await?
+    };
+
+    // The hash is cleared for the background hasher to recompute if the contents look like they've changed
+    let record = sqlx::query!(
+        "INSERT INTO vault_files (id, vault_id, path_id, name, file_type, parent_id, created_at, modified_at, size, mode, is_hidden, symlink_target, mime_type) \
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13) \
+        ON CONFLICT (vault_id, path_id) DO UPDATE SET \
+            file_type = EXCLUDED.file_type, created_at = EXCLUDED.created_at, modified_at = EXCLUDED.modified_at, size = EXCLUDED.size, \
+            mode = EXCLUDED.mode, symlink_target = EXCLUDED.symlink_target, mime_type = EXCLUDED.mime_type, \
+            content_sha256 = CASE \
+                WHEN vault_files.size IS DISTINCT FROM EXCLUDED.size OR vault_files.modified_at IS DISTINCT FROM EXCLUDED.modified_at THEN NULL \
+                ELSE vault_files.content_sha256 \
+            END \
+        RETURNING id",
+        id.as_bytes(),
+        vault_id.as_bytes(),
+        path_id,
+        name,
+        file_type.to_string(),
+        parent_id,
+        metadata.created_at,
+        metadata.modified_at,
+        metadata.size,
+        metadata.mode,
+        is_hidden_file_name(&name),
+        metadata.symlink_target,
+        mime_type,
+    )
+    .fetch_one(&mut *db)
+    .await?;
+
+    match content {
+        Some(content) => {
+            sqlx::query!(
+                "INSERT INTO vault_file_contents (vault_file_id, content) VALUES ($1, $2) \
+                ON CONFLICT (vault_file_id) DO UPDATE SET content = EXCLUDED.content",
+                record.id,
+                content,
+            )
+            .execute(&mut *db)
+            .await?;
+        }
+        None => {
+            sqlx::query!(
+                "DELETE FROM vault_file_contents WHERE vault_file_id = $1",
+                record.id,
+            )
+            .execute(&mut *db)
+            .await?;
+        }
+    }
+
+    // Files without any readable metadata still get a row, so reconciling doesn't keep trying to extract it
+    match media_metadata {
+        Some(media_metadata) => {
+            sqlx::query!(
+                "INSERT INTO vault_file_media_metadata \
+                    (vault_file_id, width, height, orientation, captured_at, camera_make, camera_model, latitude, longitude, altitude, duration_seconds) \
+                    VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11) \
+                ON CONFLICT (vault_file_id) DO UPDATE SET \
+                    width = EXCLUDED.width, height = EXCLUDED.height, orientation = EXCLUDED.orientation, captured_at = EXCLUDED.captured_at, \
+                    camera_make = EXCLUDED.camera_make, camera_model = EXCLUDED.camera_model, latitude = EXCLUDED.latitude, \
+                    longitude = EXCLUDED.longitude, altitude = EXCLUDED.altitude, duration_seconds = EXCLUDED.duration_seconds",
+                record.id,
+                media_metadata.width,
+                media_metadata.height,
+                media_metadata.orientation,
+                media_metadata.captured_at,
+                media_metadata.camera_make,
+                media_metadata.camera_model,
+                media_metadata.latitude,
+                media_metadata.longitude,
+                media_metadata.altitude,
+                media_metadata.duration_seconds,
+            )
+            .execute(&mut *db)
+            .await?;
+        }
+        None => {
+            sqlx::query!(
+                "DELETE FROM vault_file_media_metadata WHERE vault_file_id = $1",
+                record.id,
+            )
+            .execute(&mut *db)
+            .await?;
+        }
+    }
+
+    Ok(Xid::from(record.id))
+}
+
+/// How many entries indexing the whole vault writes per transaction. Each entry written locks the folders above it up to
+/// the root (to update their subtree totals), so one transaction for all of them would hold up everything else writing
+/// to the vault until it's done.
+const INDEX_BATCH_SIZE: usize = 200;
+
+/// Counts an entry written in the transaction, committing it & starting the next one once it has a full batch
+async fn commit_full_batch(
+    db: &sqlx::Pool<sqlx::Postgres>,
+    tx: &mut sqlx::Transaction<'static, sqlx::Postgres>,
+    batch_entries: &mut usize,
+) -> Result<(), sqlx::Error> {
+    *batch_entries += 1;
+    if *batch_entries < INDEX_BATCH_SIZE {
+        return Ok(());
+    }
+
+    std::mem::replace(tx, db.begin().await?).commit().await?;
+    *batch_entries = 0;
+
+    Ok(())
+}
+
+/// Reindexes a single path inside a local folder vault, removing it from the index if it no longer exists (or shouldn't
+/// be indexed). The vault root is expected to be canonical.
 pub async fn reindex_local_folder_vault_file(
     db: &sqlx::Pool<sqlx::Postgres>,
     vault_id: Xid,
+    vault_root: &Path,
+    symlinks: SymlinkPolicy,
+    ignore_rules: &mut IgnoreRules,
     path: PathBuf,
 ) -> Result<(), Box<dyn Error>> {
-    let path_id = path.as_os_str().to_string_lossy().to_string();
-
-    match path.try_exists()? {
-        true => {
-            let id = Xid::new();
-            let name = path.file_name().unwrap().to_string_lossy().to_string();
-
-            let parent = sqlx::query_as!(
-                VaultFile,
-                "SELECT id, vault_id, path_id, name, file_type, parent_id, created_at, size FROM vault_files WHERE vault_id = $1 AND path_id = $2",
-                vault_id.as_bytes(), path_id,
+    let file_type = match resolve_file_type(&path, vault_root, symlinks) {
+        Err(error) if error.kind() == io::ErrorKind::NotFound => None,
+        result => result?,
+    };
+    let file_type = file_type
+        .filter(|file_type| !ignore_rules.is_ignored(&path, *file_type == FileType::Folder));
+
+    match file_type {
+        Some(file_type) => {
+            let parent_id = sqlx::query!(
+                "SELECT id FROM vault_files WHERE vault_id = $1 AND path_id = $2",
+                vault_id.as_bytes(),
+                path.parent().unwrap().to_string_lossy().to_string(),
             )
             .fetch_optional(db)
             .await?;
-
-            let parent_id = parent.map(|p: VaultFile| p.id.as_bytes().to_vec());
-
-            sqlx::query!(
-                "INSERT INTO vault_files (id, vault_id, path_id, name, file_type, parent_id) VALUES ($1, $2, $3, $4, $5, $6) ON CONFLICT DO NOTHING",
-                id.as_bytes(), vault_id.as_bytes(), path_id, name, "file".to_string(), parent_id
-            ).execute(db)
+            let parent_id = parent_id.map(|r| r.id);
+
+            upsert_local_vault_file(
+                &mut *db.acquire().await?,
+                vault_id,
+                &path,
+                &file_type,
+                parent_id.as_deref(),
+            )
             .await?;
         }
-        false => {
+        None => {
             sqlx::query!(
                 "DELETE FROM vault_files WHERE vault_id = $1 AND path_id = $2",
                 vault_id.as_bytes(),
-                path_id,
+                path.to_string_lossy().to_string(),
             )
             .execute(db)
             .await?;
@@ -189,70 +687,298 @@ pub async fn reindex_local_folder_vault_file(
     Ok(())
 }
 
+/// Throws away the index of a local folder vault and builds it again. The index is written in batches, so the vault
+/// is only partly indexed while this runs.
 pub async fn reindex_local_folder_vault(
-    db: sqlx::Pool<sqlx::Postgres>,
-    vault: Vault,
+    db: &sqlx::Pool<sqlx::Postgres>,
+    vault: &Vault,
+    job: &mut IndexJob,
 ) -> Result<usize, Box<dyn Error>> {
-    let mut db = db.begin().await?;
+    let mut tx = db.begin().await?;
+    let mut batch_entries: usize = 0;
+
+    // The walk streams its entries, so the total is only known once it's done. Until then, how many files were
+    // indexed before is the best guess.
+    let indexed_file_count = sqlx::query_scalar!(
+        "SELECT COUNT(*) AS \"count!\" FROM vault_files WHERE vault_id = $1",
+        vault.id.as_bytes(),
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+    job.estimate_files_total(indexed_file_count as usize)
+        .await?;
 
-    println!("Deleting old index...");
     sqlx::query!(
         "DELETE FROM vault_files WHERE vault_id = $1",
         vault.id.as_bytes(),
     )
-    .execute(&mut *db)
+    .execute(&mut *tx)
     .await?;
 
     let mut file_count: usize = 0;
     let mut parent_map = HashMap::<PathBuf, Xid>::new();
 
-    println!("Walking tree...");
-    let files = walk_directory(PathBuf::from_str(vault.data["path"].as_str().unwrap())?)?;
+    let mut walk = LocalFolderVaultData::from_vault(vault)?.walk()?;
 
-    println!("Inserting db entries...");
-    for (file_path, file_type) in files {
-        let id = Xid::new();
-        let path_id = file_path.to_string_lossy().to_string();
-        let name = file_path.file_name().unwrap().to_string_lossy().to_string();
+    while let Some(entry) = walk.next().await {
+        let (file_path, file_type) = match entry {
+            Ok(entry) => entry,
+            Err(error) => {
+                job.error(error);
+                continue;
+            }
+        };
 
-        // Assumes walk_directory is in order
+        // The walk gives parents before their children
         let parent_id = parent_map
             .get(&file_path.parent().unwrap().to_path_buf())
             .map(|v| v.as_bytes() as &[u8]);
 
-        let (created_at, file_size) = match file_path.metadata() {
-            Err(_) => (None, None),
-            Ok(metadata) => (
-                metadata
-                    .created()
-                    .map(|created_at| Some(DateTime::<Utc>::from(created_at)))
-                    .unwrap_or(None),
-                Some(metadata.size() as i64),
-            ),
-        };
-
-        sqlx::query!(
-            "INSERT INTO vault_files (id, vault_id, path_id, name, file_type, parent_id, created_at, size) VALUES ($1, $2, $3, $4, $5, $6, $7, $8)",
-            id.as_bytes(),
-            vault.id.as_bytes(),
-            path_id,
-            name,
-            file_type.to_string(),
-            parent_id,
-            created_at,
-            file_size,
-        )
-        .execute(&mut *db)
-        .await?;
+        let id =
+            upsert_local_vault_file(&mut tx, vault.id, &file_path, &file_type, parent_id).await?;
+        commit_full_batch(db, &mut tx, &mut batch_entries).await?;
 
         if file_type == FileType::Folder {
             parent_map.insert(file_path, id);
         }
 
         file_count += 1;
+        job.file_seen().await?;
     }
 
-    db.commit().await?;
+    job.set_files_total(file_count).await?;
+
+    tx.commit().await?;
 
     Ok(file_count)
 }
+
+struct IndexedFile {
+    id: Xid,
+    has_content: bool,
+    has_media_metadata: bool,
+    file_type: String,
+    modified_at: Option<DateTime<Utc>>,
+    size: Option<i64>,
+    mode: Option<i32>,
+    symlink_target: Option<String>,
+    mime_type: Option<String>,
+}
+
+impl IndexedFile {
+    fn is_outdated(&self, path: &Path, file_type: &FileType, metadata: &LocalFileMetadata) -> bool {
+        // Files indexed before MIME types were detected or text & media metadata were extracted are filled in here too
+        let needs_mime_type = *file_type == FileType::File && self.mime_type.is_none();
+        let needs_content = !self.has_content
+            && self
+                .mime_type
+                .as_deref()
+                .is_some_and(|mime_type| can_extract_text(path, mime_type));
+        let needs_media_metadata = !self.has_media_metadata
+            && self
+                .mime_type
+                .as_deref()
+                .is_some_and(can_extract_media_metadata);
+
+        self.modified_at != metadata.modified_at
+            || self.size != metadata.size
+            || self.mode != metadata.mode
+            || self.symlink_target != metadata.symlink_target
+            || needs_mime_type
+            || needs_content
+            || needs_media_metadata
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ReconcileSummary {
+    pub added: usize,
+    pub updated: usize,
+    pub removed: usize,
+}
+
+/// Brings the index of a local folder vault in line with what's on disk, without throwing away the ids of files that
+/// haven't changed (unlike [`reindex_local_folder_vault`]).
+pub async fn reconcile_local_folder_vault(
+    db: &sqlx::Pool<sqlx::Postgres>,
+    vault: &Vault,
+    job: &mut IndexJob,
+) -> Result<ReconcileSummary, Box<dyn Error>> {
+    let mut walk = LocalFolderVaultData::from_vault(vault)?.walk()?;
+
+    let indexed_files = sqlx::query!(
+        "SELECT \
+            id, path_id, file_type, modified_at, size, mode, symlink_target, mime_type, \
+            EXISTS(SELECT FROM vault_file_contents WHERE vault_file_id = vault_files.id) AS \"has_content!\", \
+            EXISTS(SELECT FROM vault_file_media_metadata WHERE vault_file_id = vault_files.id) AS \"has_media_metadata!\" \
+        FROM vault_files WHERE vault_id = $1",
+        vault.id.as_bytes(),
+    )
+    .fetch_all(db)
+    .await?;
+
+    // The walk streams its entries, so the total is only known once it's done. Until then, how many files are indexed
+    // is the best guess.
+    job.estimate_files_total(indexed_files.len()).await?;
+
+    let mut indexed_files = indexed_files
+        .into_iter()
+        .map(|r| {
+            (
+                PathBuf::from(r.path_id),
+                IndexedFile {
+                    id: Xid::from(r.id),
+                    has_content: r.has_content,
+                    has_media_metadata: r.has_media_metadata,
+                    file_type: r.file_type,
+                    modified_at: r.modified_at,
+                    size: r.size,
+                    mode: r.mode,
+                    symlink_target: r.symlink_target,
+                    mime_type: r.mime_type,
+                },
+            )
+        })
+        .collect::<HashMap<_, _>>();
+
+    // Written in batches, like a reindex
+    let mut tx = db.begin().await?;
+    let mut batch_entries: usize = 0;
+
+    let mut summary = ReconcileSummary::default();
+    let mut file_count: usize = 0;
+    // Paths which couldn't be read, anything indexed at or below them is kept as it was
+    let mut unreadable_paths = vec![];
+
+    let mut parent_map = indexed_files
+        .iter()
+        .map(|(path, file)| (path.clone(), file.id))
+        .collect::<HashMap<_, _>>();
+
+    while let Some(entry) = walk.next().await {
+        let (file_path, file_type) = match entry {
+            Ok(entry) => entry,
+            Err(error) => {
+                job.error(&error);
+                unreadable_paths.push(error.path);
+                continue;
+            }
+        };
+
+        file_count += 1;
+
+        // Files which are still on disk are taken out of the index as we go, so whatever's left at the end is stale
+        let indexed_file = indexed_files.remove(&file_path);
+
+        match indexed_file {
+            Some(indexed_file) if indexed_file.file_type == file_type.to_string() => {
+                if indexed_file.is_outdated(
+                    &file_path,
+                    &file_type,
+                    &read_local_file_metadata(&file_path, &file_type),
+                ) {
+                    // The walk gives parents before their children
+                    let parent_id = parent_map
+                        .get(&file_path.parent().unwrap().to_path_buf())
+                        .map(|v| v.as_bytes() as &[u8]);
+
+                    upsert_local_vault_file(&mut tx, vault.id, &file_path, &file_type, parent_id)
+                        .await?;
+                    commit_full_batch(db, &mut tx, &mut batch_entries).await?;
+
+                    summary.updated += 1;
+                }
+            }
+            indexed_file => {
+                // A path which changed type is indexed as a new file, anything below it is cleaned up by the cascade on
+                // parent_id
+                if let Some(indexed_file) = indexed_file {
+                    let result = sqlx::query!(
+                        "DELETE FROM vault_files WHERE id = $1",
+                        indexed_file.id.as_bytes(),
+                    )
+                    .execute(&mut *tx)
+                    .await?;
+
+                    parent_map.remove(&file_path);
+                    summary.removed += result.rows_affected() as usize;
+                }
+
+                let parent_id = parent_map
+                    .get(&file_path.parent().unwrap().to_path_buf())
+                    .map(|v| v.as_bytes() as &[u8]);
+
+                let id =
+                    upsert_local_vault_file(&mut tx, vault.id, &file_path, &file_type, parent_id)
+                        .await?;
+                commit_full_batch(db, &mut tx, &mut batch_entries).await?;
+
+                if file_type == FileType::Folder {
+                    parent_map.insert(file_path, id);
+                }
+
+                summary.added += 1;
+            }
+        }
+
+        job.file_seen().await?;
+    }
+
+    job.set_files_total(file_count).await?;
+
+    let stale_paths = indexed_files
+        .into_keys()
+        .filter(|path| {
+            !unreadable_paths
+                .iter()
+                .any(|unreadable_path| path.starts_with(unreadable_path))
+        })
+        .map(|path| path.to_string_lossy().to_string())
+        .collect::<Vec<_>>();
+
+    if !stale_paths.is_empty() {
+        // Children of removed folders are cleaned up by the cascade on parent_id
+        let result = sqlx::query!(
+            "DELETE FROM vault_files WHERE vault_id = $1 AND path_id = ANY($2)",
+            vault.id.as_bytes(),
+            &stale_paths,
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        summary.removed += result.rows_affected() as usize;
+    }
+
+    tx.commit().await?;
+
+    Ok(summary)
+}
+
+async fn reconcile_local_folder_vault_and_log(db: &sqlx::Pool<sqlx::Postgres>, vault: &Vault) {
+    let result = match IndexJob::start(db, vault.id, IndexJobKind::Reconcile).await {
+        Err(error) => Err(error.into()),
+        Ok(mut job) => {
+            let result = reconcile_local_folder_vault(db, vault, &mut job).await;
+            let error = result.as_ref().err().map(|error| error.to_string());
+
+            match job.finish(error).await {
+                Ok(()) => result,
+                Err(error) => Err(error.into()),
+            }
+        }
+    };
+
+    match result {
+        Ok(summary) => println!(
+            "Reconciled vault {} (added {}, updated {}, removed {})",
+            vault.id.to_string(),
+            summary.added,
+            summary.updated,
+            summary.removed
+        ),
+        Err(error) => println!(
+            "An error occurred while reconciling vault {}: {error}",
+            vault.id.to_string()
+        ),
+    }
+}
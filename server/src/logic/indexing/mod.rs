@@ -1 +1,2 @@
+pub mod jobs;
 pub mod local_folder;
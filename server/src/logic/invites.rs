@@ -0,0 +1,199 @@
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use poem::{error::ResponseError, http::StatusCode};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::Config,
+    logic::passwords::{set_user_password, validate_new_password, PasswordError},
+    utils::{
+        security::{hash_secret_token, random_string},
+        xid::Xid,
+    },
+};
+
+/// A vault the user who redeems an invite is given access to
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct InviteVaultLink {
+    pub vault_id: Xid,
+    #[serde(default)]
+    pub is_admin: bool,
+}
+
+pub struct CreatedInvite {
+    pub id: Xid,
+    /// Only shown once, just its hash is stored
+    pub code: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+/// Creates an invite giving access to the given vaults, `created_by` isn't set for invites created through the CLI
+pub async fn create_invite(
+    db: &sqlx::Pool<sqlx::Postgres>,
+    config: &Config,
+    created_by: Option<Xid>,
+    vault_links: &[InviteVaultLink],
+) -> Result<CreatedInvite, sqlx::Error> {
+    let id = Xid::new();
+    let code = random_string(32);
+    let expires_at = Utc::now() + Duration::from_secs(config.invite_lifetime_seconds);
+
+    let mut tx = db.begin().await?;
+
+    sqlx::query!(
+        "INSERT INTO user_invites (id, code_hash, created_by, expires_at) VALUES ($1, $2, $3, $4)",
+        id.as_bytes(),
+        hash_secret_token(&code),
+        created_by
+            .as_ref()
+            .map(|user_id| user_id.as_bytes().as_slice()),
+        expires_at,
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    // The same vault given twice makes the user an admin of it if either link does
+    for link in vault_links {
+        sqlx::query!(
+            "INSERT INTO user_invite_vault_links (invite_id, vault_id, is_admin) VALUES ($1, $2, $3) \
+            ON CONFLICT (invite_id, vault_id) DO UPDATE SET is_admin = user_invite_vault_links.is_admin OR EXCLUDED.is_admin",
+            id.as_bytes(),
+            link.vault_id.as_bytes(),
+            link.is_admin,
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    tx.commit().await?;
+
+    Ok(CreatedInvite {
+        id,
+        code,
+        expires_at,
+    })
+}
+
+/// Where the frontend lets someone sign up with an invite code
+pub fn invite_signup_url(config: &Config, code: &str) -> String {
+    format!(
+        "{}/signup?code={code}",
+        config.frontend_url.trim_end_matches('/')
+    )
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum SignupError {
+    #[error("This invite has expired or was already used")]
+    InvalidInvite,
+    #[error("Please enter your name")]
+    MissingName,
+    #[error("Please enter a valid email address")]
+    InvalidEmail,
+    #[error("Email address already in use")]
+    EmailInUse,
+    #[error(transparent)]
+    Password(#[from] PasswordError),
+    #[error("Database error: {0}")]
+    Database(#[from] sqlx::Error),
+}
+
+impl ResponseError for SignupError {
+    fn status(&self) -> StatusCode {
+        match self {
+            SignupError::InvalidInvite => StatusCode::FORBIDDEN,
+            SignupError::MissingName | SignupError::InvalidEmail | SignupError::Password(_) => {
+                StatusCode::BAD_REQUEST
+            }
+            SignupError::EmailInUse => StatusCode::CONFLICT,
+            SignupError::Database(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+/// Creates a user who logs in with their email address & password, linked to the vaults of the invite they redeemed
+pub async fn redeem_invite(
+    db: &sqlx::Pool<sqlx::Postgres>,
+    code: &str,
+    name: &str,
+    email: &str,
+    password: &str,
+) -> Result<Xid, SignupError> {
+    let name = name.trim();
+    let email = email.trim();
+
+    if name.is_empty() {
+        return Err(SignupError::MissingName);
+    }
+    if !email
+        .split_once('@')
+        .is_some_and(|(local, domain)| !local.is_empty() && !domain.is_empty())
+    {
+        return Err(SignupError::InvalidEmail);
+    }
+    validate_new_password(password)?;
+
+    let mut tx = db.begin().await?;
+
+    let invite_id = sqlx::query!(
+        "SELECT id FROM user_invites WHERE code_hash = $1 AND redeemed_at IS NULL AND expires_at > NOW() FOR UPDATE",
+        hash_secret_token(code),
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    .map(|invite| Xid::from(invite.id))
+    .ok_or(SignupError::InvalidInvite)?;
+
+    let email_already_in_use = sqlx::query_scalar!(
+        "SELECT EXISTS(SELECT FROM users WHERE LOWER(email) = LOWER($1)) AS \"exists!\"",
+        email,
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+
+    if email_already_in_use {
+        return Err(SignupError::EmailInUse);
+    }
+
+    let user_id = Xid::new();
+
+    // The email address stays unverified until the user follows a link sent to it, see `reset_password`
+    sqlx::query!(
+        "INSERT INTO users (id, created_at, last_login_at, name, email) VALUES ($1, NOW(), NULL, $2, $3)",
+        user_id.as_bytes(),
+        name,
+        email,
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    set_user_password(&mut *tx, user_id, password).await?;
+
+    sqlx::query!(
+        "INSERT INTO user_vault_links (user_id, vault_id, is_admin) \
+        SELECT $1, vault_id, is_admin FROM user_invite_vault_links WHERE invite_id = $2",
+        user_id.as_bytes(),
+        invite_id.as_bytes(),
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    sqlx::query!(
+        "UPDATE user_invites SET redeemed_at = NOW(), redeemed_by = $2 WHERE id = $1",
+        invite_id.as_bytes(),
+        user_id.as_bytes(),
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+
+    println!(
+        "Created user {} for {email} with invite {}",
+        user_id.to_string(),
+        invite_id.to_string()
+    );
+
+    Ok(user_id)
+}
@@ -0,0 +1,277 @@
+use std::{error::Error, time::Duration};
+
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::{config::Config, utils::xid::Xid};
+
+use super::indexing::jobs::{cancel_index_job, run_queued_index_job};
+
+/// How long a claimed job stays locked to its worker without a heartbeat, after which it's considered interrupted (e.g.
+/// by the server stopping) and can be claimed again
+const JOB_LOCK_DURATION: Duration = Duration::from_secs(60);
+const JOB_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(20);
+const JOB_POLL_INTERVAL: Duration = Duration::from_secs(2);
+const JOB_RETRY_BASE_DELAY: Duration = Duration::from_secs(10);
+const JOB_RETRY_MAX_DELAY: Duration = Duration::from_secs(60 * 60);
+const DEFAULT_JOB_MAX_ATTEMPTS: i32 = 5;
+
+/// Everything the job queue knows how to run, stored as the `kind` and `payload` columns of `jobs`
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "kind", content = "payload", rename_all = "snake_case")]
+pub enum JobPayload {
+    /// Runs a queued index job, which has already been recorded in `vault_index_jobs`
+    IndexVault { vault_id: Xid, index_job_id: Xid },
+}
+
+impl JobPayload {
+    fn from_row(kind: &str, payload: serde_json::Value) -> Result<Self, serde_json::Error> {
+        serde_json::from_value(json!({ "kind": kind, "payload": payload }))
+    }
+
+    async fn run(&self, db: &sqlx::Pool<sqlx::Postgres>) -> Result<(), Box<dyn Error>> {
+        match self {
+            JobPayload::IndexVault {
+                vault_id,
+                index_job_id,
+            } => run_queued_index_job(db, *vault_id, *index_job_id).await,
+        }
+    }
+
+    /// Cleans up after a job which was cancelled before it could finish
+    async fn cancelled(&self, db: &sqlx::Pool<sqlx::Postgres>) -> Result<(), sqlx::Error> {
+        match self {
+            JobPayload::IndexVault { index_job_id, .. } => {
+                cancel_index_job(db, *index_job_id).await
+            }
+        }
+    }
+}
+
+pub async fn enqueue_job<'e>(
+    db: impl sqlx::PgExecutor<'e>,
+    payload: &JobPayload,
+) -> Result<Xid, Box<dyn Error>> {
+    let id = Xid::new();
+
+    let mut job = serde_json::to_value(payload)?;
+    let kind = job["kind"].as_str().unwrap_or_default().to_string();
+    let payload = job["payload"].take();
+
+    sqlx::query!(
+        "INSERT INTO jobs (id, kind, payload, max_attempts) VALUES ($1, $2, $3, $4)",
+        id.as_bytes(),
+        kind,
+        payload,
+        DEFAULT_JOB_MAX_ATTEMPTS,
+    )
+    .execute(db)
+    .await?;
+
+    Ok(id)
+}
+
+/// Puts a failed or cancelled job back in the queue with a fresh set of attempts, returns whether there was such a job
+pub async fn retry_job(db: &sqlx::Pool<sqlx::Postgres>, id: Xid) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query!(
+        "UPDATE jobs SET \
+            state = 'pending', attempts = 0, run_at = NOW(), locked_until = NULL, finished_at = NULL \
+        WHERE id = $1 AND state IN ('failed', 'cancelled')",
+        id.as_bytes(),
+    )
+    .execute(db)
+    .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+/// Cancels a pending or running job, returns whether there was such a job. Running jobs are stopped by their worker at
+/// its next heartbeat.
+pub async fn cancel_job(db: &sqlx::Pool<sqlx::Postgres>, id: Xid) -> Result<bool, sqlx::Error> {
+    let job = sqlx::query!(
+        "UPDATE jobs SET state = 'cancelled', locked_until = NULL, finished_at = NOW() \
+        WHERE id = $1 AND state IN ('pending', 'running') \
+        RETURNING kind, payload",
+        id.as_bytes(),
+    )
+    .fetch_optional(db)
+    .await?;
+
+    let Some(job) = job else {
+        return Ok(false);
+    };
+
+    // Jobs with a payload we can't make sense of have nothing to clean up either
+    if let Ok(payload) = JobPayload::from_row(&job.kind, job.payload) {
+        payload.cancelled(db).await?;
+    }
+
+    Ok(true)
+}
+
+struct ClaimedJob {
+    id: Xid,
+    kind: String,
+    payload: serde_json::Value,
+    attempts: i32,
+    max_attempts: i32,
+}
+
+/// Claims the next job which is due, including running jobs whose worker stopped sending heartbeats
+async fn claim_job(db: &sqlx::Pool<sqlx::Postgres>) -> Result<Option<ClaimedJob>, sqlx::Error> {
+    let lock_until = Utc::now() + JOB_LOCK_DURATION;
+
+    let job = sqlx::query!(
+        "UPDATE jobs SET state = 'running', attempts = attempts + 1, locked_until = $1 \
+        WHERE id = ( \
+            SELECT id FROM jobs \
+            WHERE (state = 'pending' AND run_at <= NOW()) OR (state = 'running' AND locked_until < NOW()) \
+            ORDER BY run_at LIMIT 1 \
+            FOR UPDATE SKIP LOCKED \
+        ) \
+        RETURNING id, kind, payload, attempts, max_attempts",
+        lock_until,
+    )
+    .fetch_optional(db)
+    .await?;
+
+    Ok(job.map(|job| ClaimedJob {
+        id: Xid::from(job.id),
+        kind: job.kind,
+        payload: job.payload,
+        attempts: job.attempts,
+        max_attempts: job.max_attempts,
+    }))
+}
+
+/// Extends the lock on a running job, returns `false` if the job isn't running anymore (i.e. it was cancelled)
+async fn send_job_heartbeat(db: &sqlx::Pool<sqlx::Postgres>, id: Xid) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query!(
+        "UPDATE jobs SET locked_until = $2 WHERE id = $1 AND state = 'running'",
+        id.as_bytes(),
+        Utc::now() + JOB_LOCK_DURATION,
+    )
+    .execute(db)
+    .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+fn job_retry_delay(attempts: i32) -> Duration {
+    let exponent = attempts.saturating_sub(1).clamp(0, 16) as u32;
+
+    JOB_RETRY_BASE_DELAY
+        .saturating_mul(2_u32.pow(exponent))
+        .min(JOB_RETRY_MAX_DELAY)
+}
+
+async fn run_claimed_job(
+    db: &sqlx::Pool<sqlx::Postgres>,
+    job: ClaimedJob,
+) -> Result<(), sqlx::Error> {
+    let run = async {
+        let payload = JobPayload::from_row(&job.kind, job.payload.clone())
+            .map_err(|error| format!("Invalid payload: {error}"))?;
+
+        payload.run(db).await.map_err(|error| error.to_string())
+    };
+    tokio::pin!(run);
+
+    let mut heartbeat = tokio::time::interval(JOB_HEARTBEAT_INTERVAL);
+    heartbeat.tick().await;
+
+    let result = loop {
+        tokio::select! {
+            result = &mut run => break result,
+            _ = heartbeat.tick() => {
+                // Dropping the job's future stops it at whatever it's currently waiting on
+                if !send_job_heartbeat(db, job.id).await? {
+                    println!("Stopped job {} as it was cancelled", job.id.to_string());
+                    return Ok(());
+                }
+            }
+        }
+    };
+
+    match result {
+        Ok(()) => {
+            sqlx::query!(
+                "UPDATE jobs SET state = 'succeeded', locked_until = NULL, finished_at = NOW(), last_error = NULL \
+                WHERE id = $1 AND state = 'running'",
+                job.id.as_bytes(),
+            )
+            .execute(db)
+            .await?;
+        }
+        Err(error) if job.attempts < job.max_attempts => {
+            let retry_delay = job_retry_delay(job.attempts);
+            println!(
+                "Job {} ({}) failed, retrying in {} seconds: {error}",
+                job.id.to_string(),
+                job.kind,
+                retry_delay.as_secs()
+            );
+
+            sqlx::query!(
+                "UPDATE jobs SET state = 'pending', locked_until = NULL, run_at = $2, last_error = $3 \
+                WHERE id = $1 AND state = 'running'",
+                job.id.as_bytes(),
+                Utc::now() + retry_delay,
+                error,
+            )
+            .execute(db)
+            .await?;
+        }
+        Err(error) => {
+            println!(
+                "Job {} ({}) failed after {} attempts: {error}",
+                job.id.to_string(),
+                job.kind,
+                job.attempts
+            );
+
+            sqlx::query!(
+                "UPDATE jobs SET state = 'failed', locked_until = NULL, finished_at = NOW(), last_error = $2 \
+                WHERE id = $1 AND state = 'running'",
+                job.id.as_bytes(),
+                error,
+            )
+            .execute(db)
+            .await?;
+        }
+    }
+
+    Ok(())
+}
+
+async fn run_job_worker(db: sqlx::Pool<sqlx::Postgres>) {
+    loop {
+        let job = match claim_job(&db).await {
+            Ok(Some(job)) => job,
+            Ok(None) => {
+                tokio::time::sleep(JOB_POLL_INTERVAL).await;
+                continue;
+            }
+            Err(error) => {
+                println!("An error occurred while claiming a job: {error}");
+                tokio::time::sleep(JOB_POLL_INTERVAL).await;
+                continue;
+            }
+        };
+
+        let job_id = job.id;
+        if let Err(error) = run_claimed_job(&db, job).await {
+            println!(
+                "An error occurred while running job {}: {error}",
+                job_id.to_string()
+            );
+        }
+    }
+}
+
+pub fn start_job_workers(config: &Config, db: sqlx::Pool<sqlx::Postgres>) {
+    for _ in 0..config.job_workers {
+        tokio::spawn(run_job_worker(db.clone()));
+    }
+}
@@ -0,0 +1,911 @@
+use std::{collections::HashMap, time::Duration};
+
+use ldap3::{ldap_escape, LdapConnAsync, LdapConnSettings, LdapError, Scope, SearchEntry};
+use poem::{error::ResponseError, http::StatusCode};
+use serde::{Deserialize, Serialize};
+
+use crate::{config::LdapConfig, utils::xid::Xid};
+
+pub const LDAP_IDENTITY_PROVIDER: &str = "ldap";
+const LDAP_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, thiserror::Error)]
+pub enum LdapLoginError {
+    /// Has the DN of the user's entry if only their password was wrong
+    #[error("Forbidden")]
+    InvalidCredentials(Option<String>),
+    #[error("The directory entry of the user has no {0} attribute")]
+    MissingAttribute(String),
+    #[error("The account with this email address hasn't verified it, so it can't be linked to the directory")]
+    UnverifiedAccount,
+    #[error("The directory server returned an error: {0}")]
+    Ldap(#[from] LdapError),
+    #[error("Database error: {0}")]
+    Database(#[from] sqlx::Error),
+}
+
+impl ResponseError for LdapLoginError {
+    fn status(&self) -> StatusCode {
+        match self {
+            LdapLoginError::InvalidCredentials(_)
+            | LdapLoginError::MissingAttribute(_)
+            | LdapLoginError::UnverifiedAccount => StatusCode::FORBIDDEN,
+            LdapLoginError::Ldap(_) => StatusCode::BAD_GATEWAY,
+            LdapLoginError::Database(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+/// What's known about a user from their directory entry
+pub struct LdapUser {
+    pub dn: String,
+    pub name: String,
+    pub email: String,
+    pub group_dns: Vec<String>,
+}
+
+/// Finds the user's entry with the configured search, then checks their password by binding as them
+pub async fn authenticate_ldap_user(
+    config: &LdapConfig,
+    username: &str,
+    password: &str,
+) -> Result<LdapUser, LdapLoginError> {
+    // Binding with an empty password is an unauthenticated bind, which most servers allow for any DN
+    if username.is_empty() || password.is_empty() {
+        return Err(LdapLoginError::InvalidCredentials(None));
+    }
+
+    let settings = LdapConnSettings::new()
+        .set_conn_timeout(LDAP_CONNECT_TIMEOUT)
+        .set_starttls(config.starttls);
+    let (conn, mut ldap) = LdapConnAsync::with_settings(settings, &config.url).await?;
+    ldap3::drive!(conn);
+
+    if let Some(bind_dn) = &config.bind_dn {
+        ldap.simple_bind(bind_dn, config.bind_password.as_deref().unwrap_or(""))
+            .await?
+            .success()?;
+    }
+
+    let filter = config
+        .user_filter
+        .replace("{username}", &ldap_escape(username));
+    let attributes = [
+        config.name_attribute.as_str(),
+        config.email_attribute.as_str(),
+        config.group_attribute.as_str(),
+    ];
+
+    let (entries, _) = ldap
+        .search(&config.user_base_dn, Scope::Subtree, &filter, attributes)
+        .await?
+        .success()?;
+
+    // A filter matching several entries can't tell which user is logging in
+    let [entry] =
+        <[_; 1]>::try_from(entries).map_err(|_| LdapLoginError::InvalidCredentials(None))?;
+    let entry = SearchEntry::construct(entry);
+
+    let bind = ldap.simple_bind(&entry.dn, password).await?;
+    let _ = ldap.unbind().await;
+
+    if bind.success().is_err() {
+        return Err(LdapLoginError::InvalidCredentials(Some(entry.dn)));
+    }
+
+    // Attribute names aren't case sensitive, but the server returns them as it has them stored
+    let mut attrs = entry
+        .attrs
+        .into_iter()
+        .map(|(name, values)| (name.to_lowercase(), values))
+        .collect::<HashMap<_, _>>();
+    let first_value = |attribute: &str| {
+        attrs
+            .get(&attribute.to_lowercase())
+            .and_then(|values| values.first())
+            .map(|value| value.trim().to_string())
+            .filter(|value| !value.is_empty())
+    };
+
+    let email = first_value(&config.email_attribute)
+        .ok_or_else(|| LdapLoginError::MissingAttribute(config.email_attribute.clone()))?;
+    let name = first_value(&config.name_attribute).unwrap_or_else(|| email.clone());
+    let group_dns = attrs
+        .remove(&config.group_attribute.to_lowercase())
+        .unwrap_or_default();
+
+    Ok(LdapUser {
+        dn: entry.dn,
+        name,
+        email,
+        group_dns,
+    })
+}
+
+/// A vault link the group sync is responsible for
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct LdapVaultLink {
+    pub vault_id: Xid,
+    /// Whether the sync made the link. Otherwise the link was made by hand & the sync only made it an admin one, so
+    /// that's all it takes back.
+    pub created: bool,
+    /// The role the sync gave the user
+    pub is_admin: bool,
+}
+
+/// The `data` of a user's `ldap` identity
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct LdapIdentityData {
+    pub dn: String,
+    /// Vault links the user got through their groups, only these are changed when their groups change
+    #[serde(default)]
+    pub vault_links: Vec<LdapVaultLink>,
+}
+
+/// Finds the user who logged in before with a directory entry
+pub async fn find_ldap_user_id(
+    db: &sqlx::Pool<sqlx::Postgres>,
+    dn: &str,
+) -> Result<Option<Xid>, sqlx::Error> {
+    let identity = sqlx::query!(
+        "SELECT user_id FROM user_identities WHERE provider = $1 AND LOWER(data->>'dn') = LOWER($2)",
+        LDAP_IDENTITY_PROVIDER,
+        dn,
+    )
+    .fetch_optional(db)
+    .await?;
+
+    Ok(identity.map(|identity| Xid::from(identity.user_id)))
+}
+
+/// Finds or creates the user for a directory entry, keeping their name, email & group vault access in sync with it
+pub async fn sync_ldap_user(
+    db: &sqlx::Pool<sqlx::Postgres>,
+    config: &LdapConfig,
+    ldap_user: &LdapUser,
+) -> Result<Xid, LdapLoginError> {
+    let mut tx = db.begin().await?;
+
+    let identity = sqlx::query!(
+        "SELECT user_id, data FROM user_identities WHERE provider = $1 AND LOWER(data->>'dn') = LOWER($2) FOR UPDATE",
+        LDAP_IDENTITY_PROVIDER,
+        ldap_user.dn,
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+
+    let (user_id, mut identity_data) = match identity {
+        Some(identity) => (
+            Xid::from(identity.user_id),
+            serde_json::from_value(identity.data).unwrap_or_default(),
+        ),
+        None => {
+            // The directory is trusted with email addresses, so existing users are linked by theirs. Unless they
+            // never verified it, as anyone could have signed up with it.
+            let user = sqlx::query!(
+                "SELECT id, email_verified_at IS NOT NULL AS \"is_verified!\" FROM users WHERE LOWER(email) = LOWER($1)",
+                ldap_user.email
+            )
+            .fetch_optional(&mut *tx)
+            .await?;
+
+            let user_id = match user {
+                Some(user) if !user.is_verified => return Err(LdapLoginError::UnverifiedAccount),
+                Some(user) => Xid::from(user.id),
+                None => {
+                    let user_id = Xid::new();
+
+                    sqlx::query!(
+                        "INSERT INTO users (id, created_at, last_login_at, name, email, email_verified_at) VALUES ($1, NOW(), NULL, $2, $3, NOW())",
+                        user_id.as_bytes(),
+                        ldap_user.name,
+                        ldap_user.email,
+                    )
+                    .execute(&mut *tx)
+                    .await?;
+
+                    println!(
+                        "Created user {} for {} on their first LDAP login",
+                        user_id.to_string(),
+                        ldap_user.dn
+                    );
+                    user_id
+                }
+            };
+
+            (user_id, LdapIdentityData::default())
+        }
+    };
+
+    // The email is only updated if no other user has it, which would be a conflict only an admin can resolve
+    sqlx::query!(
+        "UPDATE users SET name = $2, \
+            email = CASE WHEN EXISTS(SELECT FROM users other WHERE LOWER(other.email) = LOWER($3) AND other.id != $1) THEN email ELSE $3 END \
+        WHERE id = $1",
+        user_id.as_bytes(),
+        ldap_user.name,
+        ldap_user.email,
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    identity_data.dn = ldap_user.dn.clone();
+    identity_data.vault_links =
+        sync_group_vault_links(&mut tx, config, user_id, ldap_user, &identity_data).await?;
+
+    let identity_id = Xid::new();
+    sqlx::query!(
+        "INSERT INTO user_identities (id, user_id, provider, data) VALUES ($1, $2, $3, $4) \
+        ON CONFLICT (user_id, provider) DO UPDATE SET data = EXCLUDED.data",
+        identity_id.as_bytes(),
+        user_id.as_bytes(),
+        LDAP_IDENTITY_PROVIDER,
+        serde_json::to_value(&identity_data).unwrap(),
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+
+    Ok(user_id)
+}
+
+/// Gives the user the vault access of the groups they're in & takes back what the groups they left gave them, returning
+/// the links the sync is now responsible for. Links the sync made always have exactly the role of the user's groups.
+/// Links which already existed (e.g. made by hand) are only made admin ones, which is undone once no group makes the
+/// user an admin anymore, & they're never removed.
+async fn sync_group_vault_links(
+    db: &mut sqlx::PgConnection,
+    config: &LdapConfig,
+    user_id: Xid,
+    ldap_user: &LdapUser,
+    identity_data: &LdapIdentityData,
+) -> Result<Vec<LdapVaultLink>, sqlx::Error> {
+    // A vault can be mapped to several groups, the user is an admin if any of their groups makes them one
+    let mut group_links: Vec<(Xid, bool)> = vec![];
+    for link in &config.group_vault_links {
+        let is_member = ldap_user
+            .group_dns
+            .iter()
+            .any(|group_dn| group_dn.eq_ignore_ascii_case(&link.group_dn));
+        if !is_member {
+            continue;
+        }
+
+        match group_links
+            .iter_mut()
+            .find(|(vault_id, _)| vault_id.as_bytes() == link.vault_id.as_bytes())
+        {
+            Some((_, is_admin)) => *is_admin |= link.is_admin,
+            None => group_links.push((link.vault_id, link.is_admin)),
+        }
+    }
+
+    let mut vault_links = vec![];
+
+    for &(vault_id, is_admin) in &group_links {
+        let previous = identity_data
+            .vault_links
+            .iter()
+            .find(|link| link.vault_id.as_bytes() == vault_id.as_bytes());
+
+        if previous.is_some_and(|link| link.created) {
+            let updated = sqlx::query!(
+                "INSERT INTO user_vault_links (user_id, vault_id, is_admin) \
+                SELECT $1, id, $3 FROM vaults WHERE id = $2 \
+                ON CONFLICT (user_id, vault_id) DO UPDATE SET is_admin = EXCLUDED.is_admin",
+                user_id.as_bytes(),
+                vault_id.as_bytes(),
+                is_admin,
+            )
+            .execute(&mut *db)
+            .await?
+            .rows_affected()
+                > 0;
+
+            if updated {
+                vault_links.push(LdapVaultLink {
+                    vault_id,
+                    created: true,
+                    is_admin,
+                });
+            }
+            continue;
+        }
+
+        let inserted = sqlx::query!(
+            "INSERT INTO user_vault_links (user_id, vault_id, is_admin) \
+            SELECT $1, id, $3 FROM vaults WHERE id = $2 \
+            ON CONFLICT (user_id, vault_id) DO NOTHING",
+            user_id.as_bytes(),
+            vault_id.as_bytes(),
+            is_admin,
+        )
+        .execute(&mut *db)
+        .await?
+        .rows_affected()
+            > 0;
+
+        if inserted {
+            vault_links.push(LdapVaultLink {
+                vault_id,
+                created: true,
+                is_admin,
+            });
+        } else if is_admin {
+            let promoted = sqlx::query!(
+                "UPDATE user_vault_links SET is_admin = TRUE WHERE user_id = $1 AND vault_id = $2 AND NOT is_admin",
+                user_id.as_bytes(),
+                vault_id.as_bytes(),
+            )
+            .execute(&mut *db)
+            .await?
+            .rows_affected()
+                > 0;
+
+            // Links which were admin ones already aren't the sync's to demote later
+            if promoted || previous.is_some() {
+                vault_links.push(LdapVaultLink {
+                    vault_id,
+                    created: false,
+                    is_admin: true,
+                });
+            }
+        }
+    }
+
+    // Also demotes links the sync made admin ones when no group makes the user an admin anymore
+    for previous in &identity_data.vault_links {
+        let still_linked = vault_links
+            .iter()
+            .any(|link| link.vault_id.as_bytes() == previous.vault_id.as_bytes());
+        if still_linked {
+            continue;
+        }
+
+        if previous.created {
+            sqlx::query!(
+                "DELETE FROM user_vault_links WHERE user_id = $1 AND vault_id = $2",
+                user_id.as_bytes(),
+                previous.vault_id.as_bytes(),
+            )
+            .execute(&mut *db)
+            .await?;
+        } else {
+            demote_vault_link(&mut *db, user_id, previous.vault_id).await?;
+        }
+    }
+
+    Ok(vault_links)
+}
+
+/// Takes back admin rights the sync gave on a link which was made by hand
+async fn demote_vault_link(
+    db: &mut sqlx::PgConnection,
+    user_id: Xid,
+    vault_id: Xid,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "UPDATE user_vault_links SET is_admin = FALSE WHERE user_id = $1 AND vault_id = $2",
+        user_id.as_bytes(),
+        vault_id.as_bytes(),
+    )
+    .execute(db)
+    .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use ldap3::asn1::{parse_tag, StructureTag, TagClass, PL};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    use super::*;
+    use crate::config::LdapGroupVaultLink;
+
+    const ADMIN_DN: &str = "cn=admin,dc=example,dc=org";
+    const ALICE_DN: &str = "uid=alice,ou=people,dc=example,dc=org";
+    const ALICE_PASSWORD: &str = "wonderland";
+    const EDITORS_DN: &str = "cn=editors,ou=groups,dc=example,dc=org";
+    const ADMINS_DN: &str = "cn=admins,ou=groups,dc=example,dc=org";
+
+    struct MockEntry {
+        dn: String,
+        password: String,
+        attributes: Vec<(String, Vec<String>)>,
+    }
+
+    /// A directory server on a local port speaking just enough LDAP for logins: simple binds, searches & unbinds
+    #[derive(Clone, Default)]
+    struct MockDirectory {
+        entries: Arc<Mutex<Vec<MockEntry>>>,
+        /// The DNs of all binds, in the order they were made
+        binds: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl MockDirectory {
+        fn add_entry(&self, dn: &str, password: &str, attributes: &[(&str, &[&str])]) {
+            self.entries.lock().unwrap().push(MockEntry {
+                dn: dn.to_string(),
+                password: password.to_string(),
+                attributes: attributes
+                    .iter()
+                    .map(|(name, values)| {
+                        let values = values.iter().map(|value| value.to_string()).collect();
+                        (name.to_string(), values)
+                    })
+                    .collect(),
+            });
+        }
+
+        fn set_groups(&self, dn: &str, group_dns: &[&str]) {
+            let mut entries = self.entries.lock().unwrap();
+            let entry = entries.iter_mut().find(|entry| entry.dn == dn).unwrap();
+            entry.attributes.retain(|(name, _)| name != "memberOf");
+            let group_dns = group_dns.iter().map(|dn| dn.to_string()).collect();
+            entry.attributes.push(("memberOf".to_string(), group_dns));
+        }
+
+        async fn serve(self, mut stream: TcpStream) {
+            let mut buffer = vec![];
+            let mut chunk = [0; 4096];
+
+            loop {
+                match stream.read(&mut chunk).await {
+                    Ok(0) | Err(_) => return,
+                    Ok(read) => buffer.extend_from_slice(&chunk[..read]),
+                }
+
+                while let Ok((rest, message)) = parse_tag(&buffer) {
+                    let consumed = buffer.len() - rest.len();
+                    let response = self.respond(message);
+                    buffer.drain(..consumed);
+
+                    match response {
+                        Some(response) => stream.write_all(&response).await.unwrap(),
+                        None => return,
+                    }
+                }
+            }
+        }
+
+        /// Answers an LDAP message, or returns `None` once the client unbinds
+        fn respond(&self, message: StructureTag) -> Option<Vec<u8>> {
+            let mut parts = message.expect_constructed()?.into_iter();
+            let message_id = parts.next()?.expect_primitive()?;
+            let operation = parts.next()?;
+            let reply =
+                |operation: Vec<u8>| ber(0x30, &[ber(0x02, &message_id), operation].concat());
+
+            match (operation.class, operation.id) {
+                // Bind
+                (TagClass::Application, 0) => {
+                    let mut fields = operation.expect_constructed()?.into_iter().skip(1);
+                    let dn = text(&fields.next()?);
+                    let password = text(&fields.next()?);
+                    self.binds.lock().unwrap().push(dn.clone());
+
+                    // Like most servers, binds without a password are unauthenticated ones & always succeed
+                    let is_valid = password.is_empty()
+                        || self.entries.lock().unwrap().iter().any(|entry| {
+                            entry.dn.eq_ignore_ascii_case(&dn) && entry.password == password
+                        });
+                    Some(reply(ldap_result(0x61, if is_valid { 0 } else { 49 })))
+                }
+                // Unbind
+                (TagClass::Application, 2) => None,
+                // Search
+                (TagClass::Application, 3) => {
+                    let mut fields = operation.expect_constructed()?.into_iter();
+                    let base_dn = text(&fields.next()?).to_lowercase();
+                    // Skips the scope, alias dereferencing, size & time limits & whether to only return types
+                    let filter = fields.nth(5)?;
+                    let requested_attributes = fields
+                        .next()?
+                        .expect_constructed()?
+                        .iter()
+                        .map(text)
+                        .collect::<Vec<_>>();
+
+                    let mut response = vec![];
+                    for entry in self.entries.lock().unwrap().iter() {
+                        if !entry.dn.to_lowercase().ends_with(&base_dn) || !matches(entry, &filter)
+                        {
+                            continue;
+                        }
+
+                        let attributes = entry
+                            .attributes
+                            .iter()
+                            .filter(|(name, _)| {
+                                requested_attributes
+                                    .iter()
+                                    .any(|requested| requested.eq_ignore_ascii_case(name))
+                            })
+                            .map(|(name, values)| {
+                                let values =
+                                    values.iter().map(|value| octets(value)).collect::<Vec<_>>();
+                                ber(0x30, &[octets(name), ber(0x31, &values.concat())].concat())
+                            })
+                            .collect::<Vec<_>>();
+                        response.extend(reply(ber(
+                            0x64,
+                            &[octets(&entry.dn), ber(0x30, &attributes.concat())].concat(),
+                        )));
+                    }
+                    response.extend(reply(ldap_result(0x65, 0)));
+
+                    Some(response)
+                }
+                _ => None,
+            }
+        }
+    }
+
+    fn ber(tag: u8, content: &[u8]) -> Vec<u8> {
+        let mut encoded = vec![tag];
+        if content.len() < 0x80 {
+            encoded.push(content.len() as u8);
+        } else {
+            encoded.push(0x84);
+            encoded.extend((content.len() as u32).to_be_bytes());
+        }
+        encoded.extend_from_slice(content);
+        encoded
+    }
+
+    fn octets(value: &str) -> Vec<u8> {
+        ber(0x04, value.as_bytes())
+    }
+
+    fn ldap_result(tag: u8, result_code: u8) -> Vec<u8> {
+        ber(
+            tag,
+            &[ber(0x0a, &[result_code]), octets(""), octets("")].concat(),
+        )
+    }
+
+    fn text(tag: &StructureTag) -> String {
+        match &tag.payload {
+            PL::P(bytes) => String::from_utf8_lossy(bytes).into_owned(),
+            PL::C(_) => String::new(),
+        }
+    }
+
+    /// Evaluates a search filter, attribute values are compared case insensitively
+    fn matches(entry: &MockEntry, filter: &StructureTag) -> bool {
+        let values = |attribute: &str| {
+            entry
+                .attributes
+                .iter()
+                .filter(|(name, _)| name.eq_ignore_ascii_case(attribute))
+                .flat_map(|(_, values)| values.iter().map(|value| value.to_lowercase()))
+                .collect::<Vec<_>>()
+        };
+
+        match (filter.id, &filter.payload) {
+            (0, PL::C(filters)) => filters.iter().all(|filter| matches(entry, filter)),
+            (1, PL::C(filters)) => filters.iter().any(|filter| matches(entry, filter)),
+            (2, PL::C(filters)) => !matches(entry, &filters[0]),
+            (3, PL::C(assertion)) => {
+                values(&text(&assertion[0])).contains(&text(&assertion[1]).to_lowercase())
+            }
+            (4, PL::C(assertion)) => match &assertion[1].payload {
+                PL::C(substrings) => values(&text(&assertion[0]))
+                    .iter()
+                    .any(|value| matches_substrings(value, substrings)),
+                PL::P(_) => false,
+            },
+            (7, PL::P(attribute)) => {
+                let attribute = String::from_utf8_lossy(attribute);
+                attribute.eq_ignore_ascii_case("objectClass") || !values(&attribute).is_empty()
+            }
+            _ => false,
+        }
+    }
+
+    fn matches_substrings(value: &str, substrings: &[StructureTag]) -> bool {
+        let mut rest = value;
+
+        for substring in substrings {
+            let part = text(substring).to_lowercase();
+            match substring.id {
+                // Initial
+                0 if rest.starts_with(&part) => rest = &rest[part.len()..],
+                // Any
+                1 => match rest.find(&part) {
+                    Some(start) => rest = &rest[start + part.len()..],
+                    None => return false,
+                },
+                // Final
+                2 => return rest.ends_with(&part),
+                _ => return false,
+            }
+        }
+
+        true
+    }
+
+    async fn start_mock_directory() -> (MockDirectory, LdapConfig) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let directory = MockDirectory::default();
+        directory.add_entry(ADMIN_DN, "admin", &[("cn", &["admin"])]);
+        directory.add_entry(
+            ALICE_DN,
+            ALICE_PASSWORD,
+            &[
+                ("uid", &["alice"]),
+                ("cn", &["Alice Liddell"]),
+                ("mail", &["alice@example.org"]),
+                ("memberOf", &[EDITORS_DN]),
+            ],
+        );
+        directory.add_entry(
+            "uid=bob,ou=people,dc=example,dc=org",
+            "builder",
+            &[
+                ("uid", &["bob"]),
+                ("cn", &["Bob"]),
+                ("mail", &["bob@example.org"]),
+            ],
+        );
+
+        let server = directory.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(server.clone().serve(stream));
+            }
+        });
+
+        let config = LdapConfig {
+            url: format!("ldap://{address}"),
+            starttls: false,
+            bind_dn: Some(ADMIN_DN.to_string()),
+            bind_password: Some("admin".to_string()),
+            user_base_dn: "ou=people,dc=example,dc=org".to_string(),
+            user_filter: "(|(uid={username})(mail={username}))".to_string(),
+            name_attribute: "cn".to_string(),
+            email_attribute: "mail".to_string(),
+            group_attribute: "memberOf".to_string(),
+            group_vault_links: vec![],
+        };
+
+        (directory, config)
+    }
+
+    async fn create_vault(db: &sqlx::Pool<sqlx::Postgres>, name: &str) -> Xid {
+        let id = Xid::new();
+
+        sqlx::query!(
+            "INSERT INTO vaults (id, name, provider, data) VALUES ($1, $2, 'local_folder', '{}')",
+            id.as_bytes(),
+            name,
+        )
+        .execute(db)
+        .await
+        .unwrap();
+
+        id
+    }
+
+    /// The user's vault links as `(vault_id, is_admin)`
+    async fn vault_links(db: &sqlx::Pool<sqlx::Postgres>, user_id: Xid) -> Vec<(Vec<u8>, bool)> {
+        sqlx::query!(
+            "SELECT vault_id, is_admin FROM user_vault_links WHERE user_id = $1 ORDER BY vault_id",
+            user_id.as_bytes(),
+        )
+        .fetch_all(db)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|link| (link.vault_id, link.is_admin))
+        .collect()
+    }
+
+    /// Vault links as [`vault_links`] returns them
+    fn links(links: &[(Xid, bool)]) -> Vec<(Vec<u8>, bool)> {
+        let mut links = links
+            .iter()
+            .map(|(vault_id, is_admin)| (vault_id.as_bytes().to_vec(), *is_admin))
+            .collect::<Vec<_>>();
+        links.sort();
+        links
+    }
+
+    async fn log_in(
+        db: &sqlx::Pool<sqlx::Postgres>,
+        config: &LdapConfig,
+    ) -> Result<Xid, LdapLoginError> {
+        let ldap_user = authenticate_ldap_user(config, "alice", ALICE_PASSWORD).await?;
+        sync_ldap_user(db, config, &ldap_user).await
+    }
+
+    #[tokio::test]
+    async fn searches_for_the_user_then_binds_as_them() {
+        let (directory, config) = start_mock_directory().await;
+
+        for username in ["alice", "ALICE@example.org"] {
+            let ldap_user = authenticate_ldap_user(&config, username, ALICE_PASSWORD)
+                .await
+                .unwrap();
+            assert_eq!(ldap_user.dn, ALICE_DN);
+            assert_eq!(ldap_user.name, "Alice Liddell");
+            assert_eq!(ldap_user.email, "alice@example.org");
+            assert_eq!(ldap_user.group_dns, [EDITORS_DN]);
+        }
+
+        assert_eq!(
+            *directory.binds.lock().unwrap(),
+            [ADMIN_DN, ALICE_DN, ADMIN_DN, ALICE_DN]
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_a_wrong_password_with_the_dn() {
+        let (_, config) = start_mock_directory().await;
+
+        let result = authenticate_ldap_user(&config, "alice", "looking-glass").await;
+        assert!(
+            matches!(result, Err(LdapLoginError::InvalidCredentials(Some(dn))) if dn == ALICE_DN)
+        );
+
+        let result = authenticate_ldap_user(&config, "carol", ALICE_PASSWORD).await;
+        assert!(matches!(
+            result,
+            Err(LdapLoginError::InvalidCredentials(None))
+        ));
+    }
+
+    #[tokio::test]
+    async fn rejects_an_empty_password_without_binding() {
+        let (directory, config) = start_mock_directory().await;
+
+        // The mock directory, like real ones, would accept it as an unauthenticated bind
+        let result = authenticate_ldap_user(&config, "alice", "").await;
+        assert!(matches!(
+            result,
+            Err(LdapLoginError::InvalidCredentials(None))
+        ));
+        assert!(directory.binds.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn escapes_the_username_in_the_filter() {
+        let (directory, config) = start_mock_directory().await;
+
+        // Unescaped, these would be substring & presence filters matching Alice's entry alone
+        for username in ["ali*", "alice)(uid=*", "*)(!(uid=bob)"] {
+            let result = authenticate_ldap_user(&config, username, ALICE_PASSWORD).await;
+            assert!(
+                matches!(result, Err(LdapLoginError::InvalidCredentials(None))),
+                "{username} logged in"
+            );
+        }
+        assert!(!directory
+            .binds
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|dn| dn == ALICE_DN));
+    }
+
+    #[sqlx::test]
+    async fn syncs_group_vault_access(db: sqlx::PgPool) {
+        let (directory, mut config) = start_mock_directory().await;
+        let editors_vault_id = create_vault(&db, "Editors").await;
+        let admins_vault_id = create_vault(&db, "Admins").await;
+        config.group_vault_links = vec![
+            LdapGroupVaultLink {
+                group_dn: EDITORS_DN.to_string(),
+                vault_id: editors_vault_id,
+                is_admin: false,
+            },
+            LdapGroupVaultLink {
+                group_dn: ADMINS_DN.to_string(),
+                vault_id: admins_vault_id,
+                is_admin: true,
+            },
+            LdapGroupVaultLink {
+                group_dn: ADMINS_DN.to_string(),
+                vault_id: editors_vault_id,
+                is_admin: true,
+            },
+        ];
+        let user_id = log_in(&db, &config).await.unwrap();
+        assert_eq!(
+            vault_links(&db, user_id).await,
+            links(&[(editors_vault_id, false)])
+        );
+
+        directory.set_groups(ALICE_DN, &[EDITORS_DN, ADMINS_DN]);
+        log_in(&db, &config).await.unwrap();
+        assert_eq!(
+            vault_links(&db, user_id).await,
+            links(&[(editors_vault_id, true), (admins_vault_id, true)])
+        );
+
+        // Leaving the admins group demotes the user on the vault they're still an editor of
+        directory.set_groups(ALICE_DN, &[EDITORS_DN]);
+        log_in(&db, &config).await.unwrap();
+        assert_eq!(
+            vault_links(&db, user_id).await,
+            links(&[(editors_vault_id, false)])
+        );
+
+        directory.set_groups(ALICE_DN, &[]);
+        log_in(&db, &config).await.unwrap();
+        assert_eq!(vault_links(&db, user_id).await, links(&[]));
+    }
+
+    #[sqlx::test]
+    async fn only_takes_back_what_it_gave_on_links_made_by_hand(db: sqlx::PgPool) {
+        let (directory, mut config) = start_mock_directory().await;
+        let vault_id = create_vault(&db, "Shared").await;
+        let owned_vault_id = create_vault(&db, "Owned").await;
+        config.group_vault_links = vec![
+            LdapGroupVaultLink {
+                group_dn: ADMINS_DN.to_string(),
+                vault_id,
+                is_admin: true,
+            },
+            LdapGroupVaultLink {
+                group_dn: ADMINS_DN.to_string(),
+                vault_id: owned_vault_id,
+                is_admin: true,
+            },
+        ];
+
+        directory.set_groups(ALICE_DN, &[]);
+        let user_id = log_in(&db, &config).await.unwrap();
+        for (vault_id, is_admin) in [(vault_id, false), (owned_vault_id, true)] {
+            sqlx::query!(
+                "INSERT INTO user_vault_links (user_id, vault_id, is_admin) VALUES ($1, $2, $3)",
+                user_id.as_bytes(),
+                vault_id.as_bytes(),
+                is_admin,
+            )
+            .execute(&db)
+            .await
+            .unwrap();
+        }
+        let before = vault_links(&db, user_id).await;
+
+        directory.set_groups(ALICE_DN, &[ADMINS_DN]);
+        log_in(&db, &config).await.unwrap();
+        assert!(vault_links(&db, user_id)
+            .await
+            .iter()
+            .all(|(_, is_admin)| *is_admin));
+
+        // The promotion is undone, but the links stay & the one that was an admin one before remains one
+        directory.set_groups(ALICE_DN, &[]);
+        log_in(&db, &config).await.unwrap();
+        assert_eq!(vault_links(&db, user_id).await, before);
+    }
+
+    #[sqlx::test]
+    async fn doesnt_link_an_unverified_account(db: sqlx::PgPool) {
+        let (_, config) = start_mock_directory().await;
+        let user_id = Xid::new();
+        sqlx::query!(
+            "INSERT INTO users (id, created_at, name, email) VALUES ($1, NOW(), 'Alice', 'alice@example.org')",
+            user_id.as_bytes(),
+        )
+        .execute(&db)
+        .await
+        .unwrap();
+
+        assert!(matches!(
+            log_in(&db, &config).await,
+            Err(LdapLoginError::UnverifiedAccount)
+        ));
+    }
+}
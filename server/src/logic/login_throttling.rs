@@ -0,0 +1,344 @@
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use poem::{
+    error::ResponseError,
+    http::{header, StatusCode},
+    web::RemoteAddr,
+    IntoResponse, Response,
+};
+
+use crate::{config::Config, utils::xid::Xid};
+
+/// Failures older than this are forgotten when counting how often an account or IP address failed to log in
+const FAILED_LOGIN_WINDOW: Duration = Duration::from_secs(60 * 60 * 24);
+/// How long failed logins are kept around for looking into attacks
+const FAILED_LOGIN_RETENTION: Duration = Duration::from_secs(60 * 60 * 24 * 30);
+const FAILED_LOGIN_CLEANUP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+/// Lockout after the first failure over the limit, which doubles with each further one
+const BASE_LOCKOUT: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy)]
+pub enum LoginMethod {
+    EmailAndPassword,
+    Ldap,
+    Totp,
+    /// The current password entered to change it
+    PasswordChange,
+    /// Counted when the login is started, as anyone can start as many as they like
+    Passkey,
+}
+
+impl LoginMethod {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LoginMethod::EmailAndPassword => "email_and_password",
+            LoginMethod::Ldap => "ldap",
+            LoginMethod::Totp => "totp",
+            LoginMethod::PasswordChange => "password_change",
+            LoginMethod::Passkey => "passkey",
+        }
+    }
+}
+
+/// What failed logins are counted by, stored as the `kind` of `login_lockouts`
+#[derive(Debug, Clone, Copy)]
+enum LockoutKind {
+    Account,
+    RemoteAddress,
+}
+
+impl LockoutKind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            LockoutKind::Account => "account",
+            LockoutKind::RemoteAddress => "remote_address",
+        }
+    }
+}
+
+/// The IP address of the client without its port, so all of its connections are counted together
+pub fn remote_ip(remote_address: &RemoteAddr) -> String {
+    match remote_address.as_socket_addr() {
+        Some(address) => address.ip().to_string(),
+        None => remote_address.to_string(),
+    }
+}
+
+pub struct LoginAttempt<'a> {
+    /// Email address or username the user entered. Attempts for accounts which don't exist are counted the same way,
+    /// so lockouts don't give away who has an account. Empty for passkey logins.
+    pub identifier: &'a str,
+    pub method: LoginMethod,
+    /// IP address, see `remote_ip`
+    pub remote_address: &'a str,
+    pub user_agent: Option<&'a str>,
+}
+
+impl LoginAttempt<'_> {
+    fn account_key(&self) -> String {
+        self.identifier.trim().to_lowercase()
+    }
+}
+
+/// The counters a login attempt counts against with their limits, always in the same order so concurrent attempts
+/// can't deadlock on each other's rows
+fn login_counters<'a>(
+    config: &Config,
+    attempt: &'a LoginAttempt<'_>,
+    account_key: &'a str,
+) -> Vec<(LockoutKind, &'a str, u32)> {
+    let mut counters = vec![];
+
+    // Passkey logins don't say which account they're for until they're finished
+    if !matches!(attempt.method, LoginMethod::Passkey) {
+        counters.push((
+            LockoutKind::Account,
+            account_key,
+            config.login_max_account_attempts,
+        ));
+    }
+    counters.push((
+        LockoutKind::RemoteAddress,
+        attempt.remote_address,
+        config.login_max_ip_attempts,
+    ));
+
+    counters
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum LoginThrottleError {
+    #[error("Too many failed login attempts, please try again later")]
+    LockedOut(Duration),
+    #[error("Database error: {0}")]
+    Database(#[from] sqlx::Error),
+}
+
+impl ResponseError for LoginThrottleError {
+    fn status(&self) -> StatusCode {
+        match self {
+            LoginThrottleError::LockedOut(_) => StatusCode::TOO_MANY_REQUESTS,
+            LoginThrottleError::Database(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn as_response(&self) -> Response {
+        let mut response = self.to_string().with_status(self.status()).into_response();
+
+        if let LoginThrottleError::LockedOut(retry_after) = self {
+            response.headers_mut().insert(
+                header::RETRY_AFTER,
+                retry_after.as_secs().max(1).to_string().parse().unwrap(),
+            );
+        }
+
+        response
+    }
+}
+
+async fn insert_failed_attempt(
+    db: &sqlx::Pool<sqlx::Postgres>,
+    attempt: &LoginAttempt<'_>,
+    user_id: Option<Xid>,
+    reason: &str,
+) -> Result<(), sqlx::Error> {
+    let id = Xid::new();
+
+    sqlx::query!(
+        "INSERT INTO failed_login_attempts (id, user_id, identifier, method, reason, remote_address, user_agent) \
+        VALUES ($1, $2, $3, $4, $5, $6, $7)",
+        id.as_bytes(),
+        user_id.as_ref().map(|user_id| user_id.as_bytes().as_slice()),
+        attempt.account_key(),
+        attempt.method.as_str(),
+        reason,
+        attempt.remote_address,
+        attempt.user_agent,
+    )
+    .execute(db)
+    .await?;
+
+    Ok(())
+}
+
+/// Counts a login attempt against an account or IP address before its credentials are checked, so parallel attempts
+/// can't get past the limit together. Returns until when the counter is locked out if it already is, otherwise locks
+/// it out for longer with every attempt over the limit.
+async fn count_attempt(
+    tx: &mut sqlx::PgConnection,
+    config: &Config,
+    kind: LockoutKind,
+    key: &str,
+    max_attempts: u32,
+) -> Result<Option<DateTime<Utc>>, sqlx::Error> {
+    // The upsert keeps the row locked until the transaction ends, which is what makes counting & checking atomic
+    let counter = sqlx::query!(
+        "INSERT INTO login_lockouts (kind, key, failed_attempts, last_failed_at) VALUES ($1, $2, 1, NOW()) \
+        ON CONFLICT (kind, key) DO UPDATE SET \
+            failed_attempts = CASE WHEN login_lockouts.last_failed_at < $3 THEN 1 ELSE login_lockouts.failed_attempts + 1 END, \
+            last_failed_at = NOW() \
+        RETURNING failed_attempts, locked_until",
+        kind.as_str(),
+        key,
+        Utc::now() - FAILED_LOGIN_WINDOW,
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+
+    if let Some(locked_until) = counter
+        .locked_until
+        .filter(|locked_until| *locked_until > Utc::now())
+    {
+        return Ok(Some(locked_until));
+    }
+
+    let Some(over_limit) = (counter.failed_attempts as u32).checked_sub(max_attempts + 1) else {
+        return Ok(None);
+    };
+
+    let lockout = BASE_LOCKOUT
+        .saturating_mul(2u32.saturating_pow(over_limit))
+        .min(Duration::from_secs(config.login_lockout_seconds));
+
+    sqlx::query!(
+        "UPDATE login_lockouts SET locked_until = $3 WHERE kind = $1 AND key = $2",
+        kind.as_str(),
+        key,
+        Utc::now() + lockout,
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    Ok(None)
+}
+
+/// Counts a login attempt as failed before its credentials are checked, turning it away while the account or the IP
+/// address it comes from is locked out. Attempts which are turned away are recorded as failed but don't make the
+/// lockout any longer. Once the credentials turn out to be right, the attempt has to be given back with
+/// `forget_login_attempt`.
+pub async fn start_login_attempt(
+    db: &sqlx::Pool<sqlx::Postgres>,
+    config: &Config,
+    attempt: &LoginAttempt<'_>,
+) -> Result<(), LoginThrottleError> {
+    let account_key = attempt.account_key();
+    let mut tx = db.begin().await?;
+
+    for (kind, key, max_attempts) in login_counters(config, attempt, &account_key) {
+        let Some(locked_until) = count_attempt(&mut tx, config, kind, key, max_attempts).await?
+        else {
+            continue;
+        };
+
+        tx.rollback().await?;
+        insert_failed_attempt(db, attempt, None, "locked_out").await?;
+
+        return Err(LoginThrottleError::LockedOut(
+            (locked_until - Utc::now()).to_std().unwrap_or_default(),
+        ));
+    }
+
+    tx.commit().await?;
+
+    Ok(())
+}
+
+/// Records a login with wrong credentials, `user_id` is set if the account exists. The attempt was already counted by
+/// `start_login_attempt`.
+pub async fn record_failed_login(
+    db: &sqlx::Pool<sqlx::Postgres>,
+    attempt: &LoginAttempt<'_>,
+    user_id: Option<Xid>,
+) -> Result<(), sqlx::Error> {
+    insert_failed_attempt(db, attempt, user_id, "invalid_credentials").await
+}
+
+/// Takes back an attempt counted by `start_login_attempt` which didn't fail because of wrong credentials. If the
+/// attempt was the one to go over the limit, the lockout it caused is lifted too.
+pub async fn forget_login_attempt(
+    db: &sqlx::Pool<sqlx::Postgres>,
+    config: &Config,
+    attempt: &LoginAttempt<'_>,
+) -> Result<(), sqlx::Error> {
+    let account_key = attempt.account_key();
+
+    for (kind, key, max_attempts) in login_counters(config, attempt, &account_key) {
+        sqlx::query!(
+            "UPDATE login_lockouts SET \
+                failed_attempts = failed_attempts - 1, \
+                locked_until = CASE WHEN failed_attempts - 1 > $3 THEN locked_until END \
+            WHERE kind = $1 AND key = $2 AND failed_attempts > 0",
+            kind.as_str(),
+            key,
+            max_attempts as i32,
+        )
+        .execute(db)
+        .await?;
+    }
+
+    Ok(())
+}
+
+/// Forgets the account's failures once its user managed to log in. Failures of the IP address are kept, otherwise
+/// logging into an account of their own would let an attacker keep guessing the passwords of others.
+pub async fn record_successful_login(
+    db: &sqlx::Pool<sqlx::Postgres>,
+    attempt: &LoginAttempt<'_>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "DELETE FROM login_lockouts WHERE kind = $1 AND key = $2",
+        LockoutKind::Account.as_str(),
+        attempt.account_key(),
+    )
+    .execute(db)
+    .await?;
+
+    Ok(())
+}
+
+/// Lifts the lockout of an account (by email address or username) or IP address, returning whether there was one
+pub async fn unlock_login(db: &sqlx::Pool<sqlx::Postgres>, key: &str) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query!(
+        "DELETE FROM login_lockouts WHERE key = $1",
+        key.trim().to_lowercase(),
+    )
+    .execute(db)
+    .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+/// Deletes failed logins which are too old to be looked into & counters which were forgotten, returning how many
+/// failed logins were deleted
+pub async fn delete_old_failed_logins(db: &sqlx::Pool<sqlx::Postgres>) -> Result<u64, sqlx::Error> {
+    sqlx::query!(
+        "DELETE FROM login_lockouts WHERE last_failed_at < $1 AND (locked_until IS NULL OR locked_until <= NOW())",
+        Utc::now() - FAILED_LOGIN_WINDOW,
+    )
+    .execute(db)
+    .await?;
+
+    let result = sqlx::query!(
+        "DELETE FROM failed_login_attempts WHERE created_at < $1",
+        Utc::now() - FAILED_LOGIN_RETENTION,
+    )
+    .execute(db)
+    .await?;
+
+    Ok(result.rows_affected())
+}
+
+pub async fn run_failed_login_cleanup(db: sqlx::Pool<sqlx::Postgres>) {
+    loop {
+        match delete_old_failed_logins(&db).await {
+            Ok(0) => {}
+            Ok(count) => println!("Deleted {count} old failed login attempts"),
+            Err(error) => {
+                println!("An error occurred while deleting old failed login attempts: {error}")
+            }
+        }
+
+        tokio::time::sleep(FAILED_LOGIN_CLEANUP_INTERVAL).await;
+    }
+}
@@ -1 +1,13 @@
+pub mod content_hashing;
+pub mod email;
 pub mod indexing;
+pub mod invites;
+pub mod job_queue;
+pub mod ldap;
+pub mod login_throttling;
+pub mod oidc;
+pub mod passwords;
+pub mod refresh_tokens;
+pub mod security_events;
+pub mod totp;
+pub mod webauthn;
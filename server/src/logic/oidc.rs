@@ -0,0 +1,692 @@
+use std::time::Duration;
+
+use base64::{prelude::BASE64_URL_SAFE_NO_PAD, Engine};
+use chrono::Utc;
+use jsonwebtoken::{jwk::JwkSet, DecodingKey, Validation};
+use poem::{error::ResponseError, http::StatusCode};
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    config::OidcConfig,
+    utils::{security::random_string, xid::Xid},
+};
+
+pub const OIDC_IDENTITY_PROVIDER: &str = "oidc";
+/// How long the user has to log in at the provider before having to start over
+const OIDC_LOGIN_LIFETIME: Duration = Duration::from_secs(10 * 60);
+
+#[derive(Debug, thiserror::Error)]
+pub enum OidcLoginError {
+    #[error("This login has expired or was already used, please try again")]
+    UnknownLogin,
+    #[error("The identity provider rejected the login: {0}")]
+    Provider(String),
+    #[error("The identity provider returned an invalid ID token: {0}")]
+    InvalidIdToken(String),
+    #[error("The identity provider didn't confirm the email address of the account")]
+    UnverifiedEmail,
+    #[error("There's no account for this email address")]
+    NoAccount,
+    #[error("The account for this email address is already linked to another identity")]
+    AlreadyLinked,
+    #[error("The account for this email address hasn't verified it, so it can't be linked to this identity")]
+    UnverifiedAccount,
+    #[error("Failed to reach the identity provider: {0}")]
+    Request(#[from] reqwest::Error),
+    #[error("Database error: {0}")]
+    Database(#[from] sqlx::Error),
+}
+
+impl ResponseError for OidcLoginError {
+    fn status(&self) -> StatusCode {
+        match self {
+            OidcLoginError::Request(_) => StatusCode::BAD_GATEWAY,
+            OidcLoginError::Database(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            _ => StatusCode::FORBIDDEN,
+        }
+    }
+}
+
+/// The parts of the provider's discovery document which are needed to log in
+#[derive(Deserialize)]
+struct ProviderMetadata {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+}
+
+async fn discover_provider(
+    client: &reqwest::Client,
+    config: &OidcConfig,
+) -> Result<ProviderMetadata, OidcLoginError> {
+    let metadata: ProviderMetadata = client
+        .get(format!(
+            "{}/.well-known/openid-configuration",
+            config.issuer_url
+        ))
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+
+    // ID tokens are checked against the issuer from the discovery document, so it has to be the one configured
+    if metadata.issuer.trim_end_matches('/') != config.issuer_url {
+        return Err(OidcLoginError::Provider(format!(
+            "Discovered issuer {} doesn't match the configured one",
+            metadata.issuer
+        )));
+    }
+
+    Ok(metadata)
+}
+
+/// The `data` of a user's `oidc` identity
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OidcIdentityData {
+    pub issuer: String,
+    pub subject: String,
+    /// The email address the identity was linked with
+    pub email: Option<String>,
+}
+
+/// Starts a login by creating the URL of the provider's authorization endpoint the user is sent to
+pub async fn start_oidc_login(
+    db: &sqlx::Pool<sqlx::Postgres>,
+    config: &OidcConfig,
+) -> Result<String, OidcLoginError> {
+    let client = reqwest::Client::new();
+    let metadata = discover_provider(&client, config).await?;
+
+    sqlx::query!("DELETE FROM oidc_login_attempts WHERE expires_at <= NOW()")
+        .execute(db)
+        .await?;
+
+    let id = Xid::new();
+    let state = random_string(32);
+    let nonce = random_string(32);
+    let code_verifier = random_string(64);
+
+    sqlx::query!(
+        "INSERT INTO oidc_login_attempts (id, state, nonce, code_verifier, expires_at) VALUES ($1, $2, $3, $4, $5)",
+        id.as_bytes(),
+        state,
+        nonce,
+        code_verifier,
+        Utc::now() + OIDC_LOGIN_LIFETIME,
+    )
+    .execute(db)
+    .await?;
+
+    let code_challenge = BASE64_URL_SAFE_NO_PAD.encode(Sha256::digest(code_verifier.as_bytes()));
+
+    let mut url = Url::parse(&metadata.authorization_endpoint)
+        .map_err(|error| OidcLoginError::Provider(error.to_string()))?;
+    url.query_pairs_mut()
+        .append_pair("response_type", "code")
+        .append_pair("client_id", &config.client_id)
+        .append_pair("redirect_uri", &config.redirect_url)
+        .append_pair("scope", &config.scopes)
+        .append_pair("state", &state)
+        .append_pair("nonce", &nonce)
+        .append_pair("code_challenge", &code_challenge)
+        .append_pair("code_challenge_method", "S256");
+
+    Ok(url.to_string())
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum TokenResponse {
+    Tokens {
+        id_token: String,
+    },
+    Error {
+        error: String,
+        error_description: Option<String>,
+    },
+}
+
+#[derive(Debug, Deserialize)]
+pub struct IdTokenClaims {
+    pub iss: String,
+    pub sub: String,
+    /// Either a single client id or a list of them
+    aud: serde_json::Value,
+    azp: Option<String>,
+    nonce: Option<String>,
+    pub email: Option<String>,
+    #[serde(default)]
+    pub email_verified: bool,
+    pub name: Option<String>,
+}
+
+/// Completes a login the provider redirected back with, returning the validated claims of its ID token
+pub async fn finish_oidc_login(
+    db: &sqlx::Pool<sqlx::Postgres>,
+    config: &OidcConfig,
+    state: &str,
+    code: &str,
+) -> Result<IdTokenClaims, OidcLoginError> {
+    // Each login can only be completed once
+    let attempt = sqlx::query!(
+        "DELETE FROM oidc_login_attempts WHERE state = $1 RETURNING nonce, code_verifier, expires_at > NOW() AS \"is_valid!\"",
+        state,
+    )
+    .fetch_optional(db)
+    .await?
+    .filter(|attempt| attempt.is_valid)
+    .ok_or(OidcLoginError::UnknownLogin)?;
+
+    let client = reqwest::Client::new();
+    let metadata = discover_provider(&client, config).await?;
+
+    let mut form = vec![
+        ("grant_type", "authorization_code"),
+        ("code", code),
+        ("redirect_uri", &config.redirect_url),
+        ("client_id", &config.client_id),
+        ("code_verifier", &attempt.code_verifier),
+    ];
+    if let Some(client_secret) = &config.client_secret {
+        form.push(("client_secret", client_secret));
+    }
+
+    let response: TokenResponse = client
+        .post(&metadata.token_endpoint)
+        .form(&form)
+        .send()
+        .await?
+        .json()
+        .await?;
+
+    let id_token = match response {
+        TokenResponse::Tokens { id_token } => id_token,
+        TokenResponse::Error {
+            error,
+            error_description,
+        } => return Err(OidcLoginError::Provider(error_description.unwrap_or(error))),
+    };
+
+    let claims = validate_id_token(&client, config, &metadata, &id_token).await?;
+
+    if claims.nonce.as_deref() != Some(attempt.nonce.as_str()) {
+        return Err(OidcLoginError::InvalidIdToken("nonce mismatch".to_string()));
+    }
+
+    Ok(claims)
+}
+
+async fn validate_id_token(
+    client: &reqwest::Client,
+    config: &OidcConfig,
+    metadata: &ProviderMetadata,
+    id_token: &str,
+) -> Result<IdTokenClaims, OidcLoginError> {
+    let invalid =
+        |error: jsonwebtoken::errors::Error| OidcLoginError::InvalidIdToken(error.to_string());
+
+    let header = jsonwebtoken::decode_header(id_token).map_err(invalid)?;
+    let jwks: JwkSet = client
+        .get(&metadata.jwks_uri)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+
+    // Providers with a single signing key don't always set a key id
+    let jwk = match &header.kid {
+        Some(kid) => jwks.find(kid),
+        None if jwks.keys.len() == 1 => jwks.keys.first(),
+        None => None,
+    }
+    .ok_or_else(|| OidcLoginError::InvalidIdToken("unknown signing key".to_string()))?;
+
+    // The algorithm has to belong to the key's family, so a token can't be "signed" with the public key as a secret
+    let mut validation = Validation::new(header.alg);
+    validation.set_issuer(&[&metadata.issuer]);
+    validation.set_audience(&[&config.client_id]);
+    validation.set_required_spec_claims(&["exp", "iss", "aud", "sub"]);
+
+    let claims = jsonwebtoken::decode::<IdTokenClaims>(
+        id_token,
+        &DecodingKey::from_jwk(jwk).map_err(invalid)?,
+        &validation,
+    )
+    .map_err(invalid)?
+    .claims;
+
+    // Tokens issued to several clients name the one they were issued for
+    if claims.aud.is_array()
+        && claims.aud.as_array().is_some_and(|aud| aud.len() > 1)
+        && claims.azp.as_deref() != Some(config.client_id.as_str())
+    {
+        return Err(OidcLoginError::InvalidIdToken(
+            "issued for another client".to_string(),
+        ));
+    }
+
+    Ok(claims)
+}
+
+/// Finds the user the provider's account belongs to. Accounts which haven't logged in before are linked to the user
+/// with the same email address, or get a new user if auto-provisioning is enabled.
+pub async fn find_or_link_user(
+    db: &sqlx::Pool<sqlx::Postgres>,
+    config: &OidcConfig,
+    claims: &IdTokenClaims,
+) -> Result<Xid, OidcLoginError> {
+    let identity = sqlx::query!(
+        "SELECT user_id FROM user_identities WHERE provider = $1 AND data->>'issuer' = $2 AND data->>'subject' = $3",
+        OIDC_IDENTITY_PROVIDER,
+        claims.iss,
+        claims.sub,
+    )
+    .fetch_optional(db)
+    .await?;
+
+    if let Some(identity) = identity {
+        return Ok(Xid::from(identity.user_id));
+    }
+
+    // Linking by an unverified email address would let anyone who can sign up at the provider take over an account
+    let Some(email) = claims.email.as_ref().filter(|_| claims.email_verified) else {
+        return Err(OidcLoginError::UnverifiedEmail);
+    };
+
+    let mut tx = db.begin().await?;
+
+    // The account's email address has to be verified as well, otherwise whoever signed up with it could be let in
+    let user = sqlx::query!(
+        "SELECT id, email_verified_at IS NOT NULL AS \"is_verified!\" FROM users WHERE LOWER(email) = LOWER($1)",
+        email
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+
+    let user_id = match user {
+        Some(user) if !user.is_verified => return Err(OidcLoginError::UnverifiedAccount),
+        Some(user) => Xid::from(user.id),
+        None if config.auto_provision_users => {
+            let user_id = Xid::new();
+            let name = claims
+                .name
+                .clone()
+                .unwrap_or_else(|| email.split('@').next().unwrap_or(email).to_string());
+
+            sqlx::query!(
+                "INSERT INTO users (id, created_at, last_login_at, name, email, email_verified_at) VALUES ($1, NOW(), NULL, $2, $3, NOW())",
+                user_id.as_bytes(),
+                name,
+                email,
+            )
+            .execute(&mut *tx)
+            .await?;
+
+            println!(
+                "Created user {} for {email} on their first OIDC login",
+                user_id.to_string()
+            );
+            user_id
+        }
+        None => return Err(OidcLoginError::NoAccount),
+    };
+
+    let data = OidcIdentityData {
+        issuer: claims.iss.clone(),
+        subject: claims.sub.clone(),
+        email: Some(email.clone()),
+    };
+
+    let identity_id = Xid::new();
+    let linked = sqlx::query!(
+        "INSERT INTO user_identities (id, user_id, provider, data) VALUES ($1, $2, $3, $4) \
+        ON CONFLICT (user_id, provider) DO NOTHING",
+        identity_id.as_bytes(),
+        user_id.as_bytes(),
+        OIDC_IDENTITY_PROVIDER,
+        serde_json::to_value(&data).unwrap(),
+    )
+    .execute(&mut *tx)
+    .await?
+    .rows_affected();
+
+    if linked == 0 {
+        return Err(OidcLoginError::AlreadyLinked);
+    }
+
+    tx.commit().await?;
+
+    Ok(user_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        sync::{Arc, Mutex},
+    };
+
+    use jsonwebtoken::{Algorithm, EncodingKey, Header};
+    use poem::{
+        get, handler,
+        listener::{Acceptor, Listener, TcpListener},
+        post,
+        web::{Data, Json},
+        EndpointExt, Route, Server,
+    };
+    use serde_json::{json, Value};
+
+    use super::*;
+
+    const CLIENT_ID: &str = "floppy";
+    const SIGNING_KEY_ID: &str = "mock-key";
+    const SIGNING_SECRET: &[u8] = b"secret the mock issuer signs its ID tokens with";
+
+    /// An identity provider serving its discovery document, keys & token endpoint on a local port
+    #[derive(Clone)]
+    struct MockIssuer {
+        url: String,
+        /// What the token endpoint hands out next
+        id_token: Arc<Mutex<String>>,
+    }
+
+    #[handler]
+    fn discovery(issuer: Data<&MockIssuer>) -> Json<Value> {
+        Json(json!({
+            "issuer": issuer.url,
+            "authorization_endpoint": format!("{}/authorize", issuer.url),
+            "token_endpoint": format!("{}/token", issuer.url),
+            "jwks_uri": format!("{}/jwks", issuer.url),
+        }))
+    }
+
+    #[handler]
+    fn jwks() -> Json<Value> {
+        Json(json!({
+            "keys": [{
+                "kty": "oct",
+                "kid": SIGNING_KEY_ID,
+                "alg": "HS256",
+                "k": BASE64_URL_SAFE_NO_PAD.encode(SIGNING_SECRET),
+            }],
+        }))
+    }
+
+    #[handler]
+    fn token(issuer: Data<&MockIssuer>) -> Json<Value> {
+        Json(json!({ "id_token": *issuer.id_token.lock().unwrap() }))
+    }
+
+    async fn start_mock_issuer() -> (MockIssuer, OidcConfig) {
+        let acceptor = TcpListener::bind("127.0.0.1:0")
+            .into_acceptor()
+            .await
+            .unwrap();
+        let address = *acceptor.local_addr()[0].as_socket_addr().unwrap();
+
+        let issuer = MockIssuer {
+            url: format!("http://{address}"),
+            id_token: Arc::default(),
+        };
+        let app = Route::new()
+            .at("/.well-known/openid-configuration", get(discovery))
+            .at("/jwks", get(jwks))
+            .at("/token", post(token))
+            .data(issuer.clone());
+        tokio::spawn(Server::new_with_acceptor(acceptor).run(app));
+
+        let config = OidcConfig {
+            issuer_url: issuer.url.clone(),
+            client_id: CLIENT_ID.to_string(),
+            client_secret: None,
+            redirect_url: "http://localhost:3000/login/oidc".to_string(),
+            scopes: "openid email profile".to_string(),
+            provider_name: "Mock".to_string(),
+            auto_provision_users: false,
+        };
+
+        (issuer, config)
+    }
+
+    /// Goes through a login at the mock issuer, whose ID token has valid claims for Alice apart from the given ones
+    async fn log_in(
+        db: &sqlx::Pool<sqlx::Postgres>,
+        issuer: &MockIssuer,
+        config: &OidcConfig,
+        claims: Value,
+    ) -> Result<IdTokenClaims, OidcLoginError> {
+        let authorization_url = Url::parse(&start_oidc_login(db, config).await.unwrap()).unwrap();
+        let query = authorization_url
+            .query_pairs()
+            .into_owned()
+            .collect::<HashMap<_, _>>();
+        assert_eq!(query["client_id"], CLIENT_ID);
+        assert_eq!(query["code_challenge_method"], "S256");
+
+        let now = Utc::now().timestamp();
+        let mut id_token_claims = json!({
+            "iss": issuer.url,
+            "sub": "alice",
+            "aud": CLIENT_ID,
+            "iat": now,
+            "exp": now + 300,
+            "nonce": query["nonce"],
+            "email": "alice@example.org",
+            "email_verified": true,
+            "name": "Alice",
+        });
+        for (name, value) in claims.as_object().unwrap() {
+            id_token_claims[name] = value.clone();
+        }
+
+        let mut header = Header::new(Algorithm::HS256);
+        header.kid = Some(SIGNING_KEY_ID.to_string());
+        *issuer.id_token.lock().unwrap() = jsonwebtoken::encode(
+            &header,
+            &id_token_claims,
+            &EncodingKey::from_secret(SIGNING_SECRET),
+        )
+        .unwrap();
+
+        finish_oidc_login(db, config, &query["state"], "code").await
+    }
+
+    async fn create_user(db: &sqlx::Pool<sqlx::Postgres>, email: &str, is_verified: bool) -> Xid {
+        let id = Xid::new();
+
+        sqlx::query!(
+            "INSERT INTO users (id, created_at, name, email, email_verified_at) \
+            VALUES ($1, NOW(), $2, $2, CASE WHEN $3 THEN NOW() END)",
+            id.as_bytes(),
+            email,
+            is_verified,
+        )
+        .execute(db)
+        .await
+        .unwrap();
+
+        id
+    }
+
+    #[sqlx::test]
+    async fn links_the_account_with_a_verified_email(db: sqlx::PgPool) {
+        let (issuer, config) = start_mock_issuer().await;
+        let user_id = create_user(&db, "alice@example.org", true).await;
+
+        let claims = log_in(&db, &issuer, &config, json!({})).await.unwrap();
+        assert_eq!(claims.sub, "alice");
+        let linked_user_id = find_or_link_user(&db, &config, &claims).await.unwrap();
+        assert_eq!(linked_user_id.as_bytes(), user_id.as_bytes());
+
+        // Later logins find the user by their identity, even with another email address
+        let claims = log_in(
+            &db,
+            &issuer,
+            &config,
+            json!({ "email": "alice@example.com" }),
+        )
+        .await
+        .unwrap();
+        let linked_user_id = find_or_link_user(&db, &config, &claims).await.unwrap();
+        assert_eq!(linked_user_id.as_bytes(), user_id.as_bytes());
+    }
+
+    #[sqlx::test]
+    async fn logins_can_only_be_finished_once(db: sqlx::PgPool) {
+        let (issuer, config) = start_mock_issuer().await;
+
+        let authorization_url = Url::parse(&start_oidc_login(&db, &config).await.unwrap()).unwrap();
+        let (_, state) = authorization_url
+            .query_pairs()
+            .find(|(name, _)| name == "state")
+            .unwrap();
+        *issuer.id_token.lock().unwrap() = "not used".to_string();
+
+        let _ = finish_oidc_login(&db, &config, &state, "code").await;
+        assert!(matches!(
+            finish_oidc_login(&db, &config, &state, "code").await,
+            Err(OidcLoginError::UnknownLogin)
+        ));
+    }
+
+    #[sqlx::test]
+    async fn rejects_a_nonce_mismatch(db: sqlx::PgPool) {
+        let (issuer, config) = start_mock_issuer().await;
+
+        let result = log_in(&db, &issuer, &config, json!({ "nonce": "another login" })).await;
+        assert!(matches!(result, Err(OidcLoginError::InvalidIdToken(_))));
+
+        let result = log_in(&db, &issuer, &config, json!({ "nonce": null })).await;
+        assert!(matches!(result, Err(OidcLoginError::InvalidIdToken(_))));
+    }
+
+    #[sqlx::test]
+    async fn rejects_tokens_issued_for_other_clients(db: sqlx::PgPool) {
+        let (issuer, config) = start_mock_issuer().await;
+
+        let result = log_in(&db, &issuer, &config, json!({ "aud": "someone-else" })).await;
+        assert!(matches!(result, Err(OidcLoginError::InvalidIdToken(_))));
+
+        let claims = json!({ "aud": [CLIENT_ID, "someone-else"], "azp": "someone-else" });
+        let result = log_in(&db, &issuer, &config, claims).await;
+        assert!(matches!(result, Err(OidcLoginError::InvalidIdToken(_))));
+
+        let claims = json!({ "aud": [CLIENT_ID, "someone-else"] });
+        let result = log_in(&db, &issuer, &config, claims).await;
+        assert!(matches!(result, Err(OidcLoginError::InvalidIdToken(_))));
+
+        let claims = json!({ "aud": [CLIENT_ID, "someone-else"], "azp": CLIENT_ID });
+        assert!(log_in(&db, &issuer, &config, claims).await.is_ok());
+    }
+
+    #[sqlx::test]
+    async fn rejects_tokens_from_other_issuers(db: sqlx::PgPool) {
+        let (issuer, config) = start_mock_issuer().await;
+
+        let result = log_in(
+            &db,
+            &issuer,
+            &config,
+            json!({ "iss": "https://example.org" }),
+        )
+        .await;
+        assert!(matches!(result, Err(OidcLoginError::InvalidIdToken(_))));
+    }
+
+    #[sqlx::test]
+    async fn doesnt_link_by_unverified_emails(db: sqlx::PgPool) {
+        let (issuer, config) = start_mock_issuer().await;
+        create_user(&db, "alice@example.org", true).await;
+        create_user(&db, "mallory@example.org", false).await;
+
+        // The provider didn't verify the email address
+        let claims = log_in(&db, &issuer, &config, json!({ "email_verified": false }))
+            .await
+            .unwrap();
+        assert!(matches!(
+            find_or_link_user(&db, &config, &claims).await,
+            Err(OidcLoginError::UnverifiedEmail)
+        ));
+
+        // The account's owner didn't verify it
+        let claims = log_in(
+            &db,
+            &issuer,
+            &config,
+            json!({ "sub": "mallory", "email": "mallory@example.org" }),
+        )
+        .await
+        .unwrap();
+        assert!(matches!(
+            find_or_link_user(&db, &config, &claims).await,
+            Err(OidcLoginError::UnverifiedAccount)
+        ));
+
+        let identities = sqlx::query_scalar!(
+            "SELECT COUNT(*) AS \"count!\" FROM user_identities WHERE provider = $1",
+            OIDC_IDENTITY_PROVIDER,
+        )
+        .fetch_one(&db)
+        .await
+        .unwrap();
+        assert_eq!(identities, 0);
+    }
+
+    #[sqlx::test]
+    async fn only_creates_users_when_auto_provisioning(db: sqlx::PgPool) {
+        let (issuer, mut config) = start_mock_issuer().await;
+
+        let claims = log_in(&db, &issuer, &config, json!({})).await.unwrap();
+        assert!(matches!(
+            find_or_link_user(&db, &config, &claims).await,
+            Err(OidcLoginError::NoAccount)
+        ));
+
+        config.auto_provision_users = true;
+        let user_id = find_or_link_user(&db, &config, &claims).await.unwrap();
+
+        let user = sqlx::query!(
+            "SELECT name, email, email_verified_at IS NOT NULL AS \"is_verified!\" FROM users WHERE id = $1",
+            user_id.as_bytes(),
+        )
+        .fetch_one(&db)
+        .await
+        .unwrap();
+        assert_eq!(user.name, "Alice");
+        assert_eq!(user.email, "alice@example.org");
+        assert!(user.is_verified);
+    }
+
+    #[sqlx::test]
+    async fn doesnt_link_an_account_linked_to_another_identity(db: sqlx::PgPool) {
+        let (issuer, config) = start_mock_issuer().await;
+        let user_id = create_user(&db, "alice@example.org", true).await;
+
+        let claims = log_in(&db, &issuer, &config, json!({})).await.unwrap();
+        find_or_link_user(&db, &config, &claims).await.unwrap();
+
+        // Another account at the provider with the same email address
+        let claims = log_in(&db, &issuer, &config, json!({ "sub": "impostor" }))
+            .await
+            .unwrap();
+        assert!(matches!(
+            find_or_link_user(&db, &config, &claims).await,
+            Err(OidcLoginError::AlreadyLinked)
+        ));
+
+        let subject = sqlx::query_scalar!(
+            "SELECT data->>'subject' AS \"subject!\" FROM user_identities WHERE user_id = $1 AND provider = $2",
+            user_id.as_bytes(),
+            OIDC_IDENTITY_PROVIDER,
+        )
+        .fetch_one(&db)
+        .await
+        .unwrap();
+        assert_eq!(subject, "alice");
+    }
+}
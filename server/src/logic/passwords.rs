@@ -0,0 +1,153 @@
+use std::time::Duration;
+
+use argon2::{password_hash::SaltString, Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
+use chrono::Utc;
+use poem::{error::ResponseError, http::StatusCode};
+use rand::rngs::OsRng;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::Config,
+    utils::{
+        security::{hash_secret_token, random_string},
+        xid::Xid,
+    },
+};
+
+pub const PASSWORD_IDENTITY_PROVIDER: &str = "email_and_password";
+pub const MIN_PASSWORD_LENGTH: usize = 8;
+
+#[derive(Debug, thiserror::Error)]
+pub enum PasswordError {
+    #[error("The password has to be at least {MIN_PASSWORD_LENGTH} characters long")]
+    TooShort,
+}
+
+impl ResponseError for PasswordError {
+    fn status(&self) -> StatusCode {
+        StatusCode::BAD_REQUEST
+    }
+}
+
+/// Checks a password a user picked for themselves
+pub fn validate_new_password(password: &str) -> Result<(), PasswordError> {
+    if password.chars().count() < MIN_PASSWORD_LENGTH {
+        return Err(PasswordError::TooShort);
+    }
+
+    Ok(())
+}
+
+/// The `data` of a user's `email_and_password` identity
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PasswordIdentityData {
+    /// Argon2 hash in the PHC string format
+    password: String,
+}
+
+impl PasswordIdentityData {
+    pub fn new(password: &str) -> Self {
+        let salt = SaltString::generate(&mut OsRng);
+
+        PasswordIdentityData {
+            password: Argon2::default()
+                .hash_password(password.as_bytes(), &salt)
+                .unwrap()
+                .to_string(),
+        }
+    }
+
+    pub fn verify(&self, password: &str) -> bool {
+        let Ok(hashed_password) = PasswordHash::new(&self.password) else {
+            return false;
+        };
+
+        Argon2::default()
+            .verify_password(password.as_bytes(), &hashed_password)
+            .is_ok()
+    }
+}
+
+pub async fn load_password_identity<'e>(
+    db: impl sqlx::PgExecutor<'e>,
+    user_id: Xid,
+) -> Result<Option<PasswordIdentityData>, sqlx::Error> {
+    let identity = sqlx::query!(
+        "SELECT data FROM user_identities WHERE user_id = $1 AND provider = $2",
+        user_id.as_bytes(),
+        PASSWORD_IDENTITY_PROVIDER,
+    )
+    .fetch_optional(db)
+    .await?;
+
+    Ok(identity.and_then(|identity| serde_json::from_value(identity.data).ok()))
+}
+
+/// Sets the user's password, giving them an `email_and_password` identity if they don't have one yet
+pub async fn set_user_password<'e>(
+    db: impl sqlx::PgExecutor<'e>,
+    user_id: Xid,
+    password: &str,
+) -> Result<(), sqlx::Error> {
+    let id = Xid::new();
+
+    sqlx::query!(
+        "INSERT INTO user_identities (id, user_id, provider, data) VALUES ($1, $2, $3, $4) \
+        ON CONFLICT (user_id, provider) DO UPDATE SET data = EXCLUDED.data",
+        id.as_bytes(),
+        user_id.as_bytes(),
+        PASSWORD_IDENTITY_PROVIDER,
+        serde_json::to_value(PasswordIdentityData::new(password)).unwrap(),
+    )
+    .execute(db)
+    .await?;
+
+    Ok(())
+}
+
+/// Creates the token sent to the user in a password reset link, replacing any they were sent before
+pub async fn create_password_reset_token(
+    db: &sqlx::Pool<sqlx::Postgres>,
+    config: &Config,
+    user_id: Xid,
+) -> Result<String, sqlx::Error> {
+    sqlx::query!(
+        "DELETE FROM user_password_reset_tokens WHERE expires_at <= NOW() OR user_id = $1",
+        user_id.as_bytes(),
+    )
+    .execute(db)
+    .await?;
+
+    let id = Xid::new();
+    let token = random_string(64);
+
+    sqlx::query!(
+        "INSERT INTO user_password_reset_tokens (id, user_id, token_hash, expires_at) VALUES ($1, $2, $3, $4)",
+        id.as_bytes(),
+        user_id.as_bytes(),
+        hash_secret_token(&token),
+        Utc::now() + Duration::from_secs(config.password_reset_token_lifetime_seconds),
+    )
+    .execute(db)
+    .await?;
+
+    Ok(token)
+}
+
+/// Removes a password reset token which hasn't expired yet & returns the user it was for, so each one can only be
+/// used once
+pub async fn take_password_reset_token(
+    db: &mut sqlx::PgConnection,
+    token: &str,
+) -> Result<Option<Xid>, sqlx::Error> {
+    let reset_token = sqlx::query!(
+        "DELETE FROM user_password_reset_tokens WHERE token_hash = $1 RETURNING user_id, expires_at > NOW() AS \"is_valid!\"",
+        hash_secret_token(token),
+    )
+    .fetch_optional(db)
+    .await?;
+
+    Ok(reset_token
+        .filter(|reset_token| reset_token.is_valid)
+        .map(|reset_token| Xid::from(reset_token.user_id)))
+}
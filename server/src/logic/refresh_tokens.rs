@@ -0,0 +1,236 @@
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+
+use serde_json::json;
+
+use crate::{
+    config::Config,
+    models::users::UserRefreshToken,
+    utils::{
+        security::hash_secret_token,
+        user_security::{generate_tokens, UserTokens},
+        xid::Xid,
+    },
+};
+
+use super::security_events::{record_security_event, SecurityEventKind};
+
+const REFRESH_TOKEN_CLEANUP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Refresh tokens which haven't been used since this time have expired from going unused
+pub fn refresh_token_idle_since(config: &Config) -> DateTime<Utc> {
+    Utc::now() - Duration::from_secs(config.refresh_token_idle_timeout_seconds)
+}
+
+pub struct RefreshTokenClient<'a> {
+    pub user_agent: Option<&'a str>,
+    pub remote_address: &'a str,
+}
+
+/// Stores a newly issued refresh token, a token which starts a new family (i.e. on login) is its own family
+#[allow(clippy::too_many_arguments)]
+pub async fn store_refresh_token<'e>(
+    db: impl sqlx::PgExecutor<'e>,
+    id: Xid,
+    family_id: Xid,
+    user_id: Xid,
+    refresh_token: &str,
+    expires_at: DateTime<Utc>,
+    client: &RefreshTokenClient<'_>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "INSERT INTO user_refresh_tokens (id, family_id, user_id, token_hash, last_used_at, created_at, user_agent, remote_address, expires_at) \
+            VALUES ($1, $2, $3, $4, NULL, NOW(), $5, $6, $7)",
+        id.as_bytes(),
+        family_id.as_bytes(),
+        user_id.as_bytes(),
+        hash_secret_token(refresh_token),
+        client.user_agent,
+        client.remote_address,
+        expires_at,
+    )
+    .execute(db)
+    .await?;
+
+    Ok(())
+}
+
+/// Starts a new session for a user who has fully authenticated, regardless of how they logged in
+pub async fn issue_user_tokens(
+    db: &sqlx::Pool<sqlx::Postgres>,
+    config: &Config,
+    user_id: Xid,
+    client: &RefreshTokenClient<'_>,
+) -> Result<UserTokens, sqlx::Error> {
+    // The first token of a session starts its family
+    let refresh_token_id = Xid::new();
+    let tokens = generate_tokens(&config.jwt_signing_key, &user_id, &refresh_token_id);
+    let expires_at = Utc::now() + Duration::from_secs(config.refresh_token_lifetime_seconds);
+
+    store_refresh_token(
+        db,
+        refresh_token_id,
+        refresh_token_id,
+        user_id,
+        &tokens.refresh_token,
+        expires_at,
+        client,
+    )
+    .await?;
+
+    Ok(tokens)
+}
+
+/// Replaces a refresh token with the next one of its family, which keeps the family's absolute expiry. Returns `false`
+/// if the token was rotated or revoked in the meantime (i.e. by a concurrent refresh).
+pub async fn rotate_refresh_token(
+    db: &mut sqlx::PgConnection,
+    current: &UserRefreshToken,
+    next_refresh_token: &str,
+    client: &RefreshTokenClient<'_>,
+) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query!(
+        "UPDATE user_refresh_tokens SET rotated_at = NOW(), last_used_at = NOW() \
+        WHERE id = $1 AND rotated_at IS NULL AND revoked_at IS NULL",
+        current.id.as_bytes(),
+    )
+    .execute(&mut *db)
+    .await?;
+
+    if result.rows_affected() == 0 {
+        return Ok(false);
+    }
+
+    store_refresh_token(
+        &mut *db,
+        Xid::new(),
+        current.family_id,
+        current.user_id,
+        next_refresh_token,
+        current.expires_at,
+        client,
+    )
+    .await?;
+
+    Ok(true)
+}
+
+/// Handles a refresh token which was already rotated being presented again. Either the legitimate client or an
+/// attacker is holding on to a copy of it and there's no telling which, so the whole family is revoked and the
+/// suspected theft recorded.
+pub async fn revoke_reused_refresh_token_family(
+    db: &sqlx::Pool<sqlx::Postgres>,
+    reused: &UserRefreshToken,
+    client: &RefreshTokenClient<'_>,
+) -> Result<(), sqlx::Error> {
+    let mut db = db.begin().await?;
+
+    let result = sqlx::query!(
+        "UPDATE user_refresh_tokens SET revoked_at = NOW() WHERE family_id = $1 AND revoked_at IS NULL",
+        reused.family_id.as_bytes(),
+    )
+    .execute(&mut *db)
+    .await?;
+
+    record_security_event(
+        &mut *db,
+        reused.user_id,
+        SecurityEventKind::RefreshTokenReuse,
+        client.user_agent,
+        Some(client.remote_address),
+        json!({
+            "family_id": reused.family_id,
+            "refresh_token_id": reused.id,
+            "revoked_tokens": result.rows_affected(),
+        }),
+    )
+    .await?;
+
+    db.commit().await?;
+
+    println!(
+        "Warning: Refresh token {} of user {} was reused, revoked its session {}",
+        reused.id.to_string(),
+        reused.user_id.to_string(),
+        reused.family_id.to_string()
+    );
+
+    Ok(())
+}
+
+/// Revokes a single session of a user, returns whether there was such a session
+pub async fn revoke_user_session(
+    db: &sqlx::Pool<sqlx::Postgres>,
+    user_id: Xid,
+    session_id: Xid,
+) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query!(
+        "UPDATE user_refresh_tokens SET revoked_at = NOW() WHERE family_id = $1 AND user_id = $2 AND revoked_at IS NULL",
+        session_id.as_bytes(),
+        user_id.as_bytes(),
+    )
+    .execute(db)
+    .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+/// Revokes every session of a user, optionally except for one (i.e. the one doing the revoking), returning how many
+/// refresh tokens were revoked
+pub async fn revoke_user_sessions<'e>(
+    db: impl sqlx::PgExecutor<'e>,
+    user_id: Xid,
+    except_session_id: Option<Xid>,
+) -> Result<u64, sqlx::Error> {
+    let result = sqlx::query!(
+        "UPDATE user_refresh_tokens SET revoked_at = NOW() \
+        WHERE user_id = $1 AND revoked_at IS NULL AND ($2::BYTEA IS NULL OR family_id <> $2::BYTEA)",
+        user_id.as_bytes(),
+        except_session_id.map(|id| id.as_bytes().to_vec()),
+    )
+    .execute(db)
+    .await?;
+
+    Ok(result.rows_affected())
+}
+
+/// Deletes the refresh tokens of sessions which can't be used anymore, because they were revoked or their current token
+/// expired (either absolutely or from going unused), returning how many were deleted. Tokens which were rotated are
+/// kept as long as their session is alive, so presenting them again can be recognised as reuse.
+pub async fn delete_unusable_refresh_tokens(
+    db: &sqlx::Pool<sqlx::Postgres>,
+    config: &Config,
+) -> Result<u64, sqlx::Error> {
+    let idle_since = refresh_token_idle_since(config);
+
+    let result = sqlx::query!(
+        "DELETE FROM user_refresh_tokens WHERE NOT EXISTS( \
+            SELECT FROM user_refresh_tokens current \
+            WHERE \
+                current.family_id = user_refresh_tokens.family_id \
+                AND current.rotated_at IS NULL AND current.revoked_at IS NULL \
+                AND current.expires_at > NOW() AND COALESCE(current.last_used_at, current.created_at) > $1 \
+        )",
+        idle_since,
+    )
+    .execute(db)
+    .await?;
+
+    Ok(result.rows_affected())
+}
+
+/// Periodically cleans up unusable refresh tokens, forever
+pub async fn run_refresh_token_cleanup(config: Config, db: sqlx::Pool<sqlx::Postgres>) {
+    loop {
+        match delete_unusable_refresh_tokens(&db, &config).await {
+            Ok(0) => {}
+            Ok(count) => println!("Deleted {count} expired or revoked refresh tokens"),
+            Err(error) => {
+                println!("An error occurred while deleting expired refresh tokens: {error}")
+            }
+        }
+
+        tokio::time::sleep(REFRESH_TOKEN_CLEANUP_INTERVAL).await;
+    }
+}
@@ -0,0 +1,62 @@
+use crate::utils::xid::Xid;
+
+/// Security relevant things which happened to a user's account, stored as the `kind` of `user_security_events`
+#[derive(Debug, Clone, Copy)]
+pub enum SecurityEventKind {
+    /// A refresh token was used again after being rotated, suggesting it was stolen
+    RefreshTokenReuse,
+    /// Two-factor authentication with an authenticator app was turned on
+    TotpEnabled,
+    /// Two-factor authentication with an authenticator app was turned off
+    TotpDisabled,
+    /// A recovery code was used instead of a code from the authenticator app
+    TotpRecoveryCodeUsed,
+    /// A passkey was added to the account
+    WebauthnCredentialAdded,
+    /// A passkey was removed from the account
+    WebauthnCredentialRemoved,
+    /// The password was changed by the user while logged in
+    PasswordChanged,
+    /// The password was set through a link from a password reset email
+    PasswordReset,
+}
+
+impl SecurityEventKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SecurityEventKind::RefreshTokenReuse => "refresh_token_reuse",
+            SecurityEventKind::TotpEnabled => "totp_enabled",
+            SecurityEventKind::TotpDisabled => "totp_disabled",
+            SecurityEventKind::TotpRecoveryCodeUsed => "totp_recovery_code_used",
+            SecurityEventKind::WebauthnCredentialAdded => "webauthn_credential_added",
+            SecurityEventKind::WebauthnCredentialRemoved => "webauthn_credential_removed",
+            SecurityEventKind::PasswordChanged => "password_changed",
+            SecurityEventKind::PasswordReset => "password_reset",
+        }
+    }
+}
+
+pub async fn record_security_event<'e>(
+    db: impl sqlx::PgExecutor<'e>,
+    user_id: Xid,
+    kind: SecurityEventKind,
+    user_agent: Option<&str>,
+    remote_address: Option<&str>,
+    data: serde_json::Value,
+) -> Result<Xid, sqlx::Error> {
+    let id = Xid::new();
+
+    sqlx::query!(
+        "INSERT INTO user_security_events (id, user_id, kind, user_agent, remote_address, data) VALUES ($1, $2, $3, $4, $5, $6)",
+        id.as_bytes(),
+        user_id.as_bytes(),
+        kind.as_str(),
+        user_agent,
+        remote_address,
+        data,
+    )
+    .execute(db)
+    .await?;
+
+    Ok(id)
+}
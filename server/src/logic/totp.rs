@@ -0,0 +1,316 @@
+use std::{error::Error, time::Duration};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use totp_rs::{Builder, Secret, Totp};
+
+use crate::utils::{
+    hex::encode_hex,
+    security::{hash_secret_token, random_string},
+    xid::Xid,
+};
+
+pub const TOTP_IDENTITY_PROVIDER: &str = "totp";
+/// Shown next to the account name in authenticator apps
+const TOTP_ISSUER: &str = "Floppy";
+const RECOVERY_CODE_COUNT: usize = 10;
+const LOGIN_CHALLENGE_LIFETIME: Duration = Duration::from_secs(5 * 60);
+/// Wrong codes a login challenge allows before it's thrown away and the password has to be entered again
+const MAX_LOGIN_CHALLENGE_ATTEMPTS: i32 = 5;
+
+/// Recovery codes are shown grouped with a dash, but are accepted with any formatting or casing
+fn normalize_recovery_code(code: &str) -> String {
+    code.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
+
+/// The `data` of a user's `totp` identity
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TotpIdentityData {
+    /// Base32 encoded shared secret
+    secret: String,
+    /// Not set until the user has proven their authenticator app works by entering a code from it, until then the
+    /// identity isn't required to log in
+    pub confirmed_at: Option<DateTime<Utc>>,
+    /// Time step of the last accepted code, codes from it or any earlier step can't be used again
+    #[serde(default)]
+    last_used_step: Option<u64>,
+    /// Hex encoded hashes of the recovery codes which haven't been used yet
+    #[serde(default)]
+    recovery_code_hashes: Vec<String>,
+}
+
+impl TotpIdentityData {
+    pub fn new() -> TotpIdentityData {
+        TotpIdentityData {
+            secret: Secret::generate().to_base32(),
+            confirmed_at: None,
+            last_used_step: None,
+            recovery_code_hashes: vec![],
+        }
+    }
+
+    pub fn secret(&self) -> &str {
+        &self.secret
+    }
+
+    fn totp(&self, account_name: &str) -> Result<Totp, Box<dyn Error>> {
+        Ok(Builder::new()
+            .with_secret(Secret::try_from_base32(&self.secret)?)
+            .with_account_name(account_name)
+            .with_issuer(Some(TOTP_ISSUER))
+            .build()?)
+    }
+
+    /// The `otpauth://` URI authenticator apps are set up with, usually by scanning it as a QR code
+    pub fn provisioning_uri(&self, account_name: &str) -> Result<String, Box<dyn Error>> {
+        Ok(self.totp(account_name)?.to_url()?)
+    }
+
+    /// Checks a code from the authenticator app, a code which is accepted can't be used again
+    pub fn verify_code(&mut self, code: &str) -> bool {
+        // The account name is only used for the provisioning URI
+        let Ok(totp) = self.totp("") else {
+            return false;
+        };
+
+        match totp.check_current(code.trim()) {
+            Some(step)
+                if self
+                    .last_used_step
+                    .is_none_or(|last_used_step| step > last_used_step) =>
+            {
+                self.last_used_step = Some(step);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Replaces any existing recovery codes with new ones, which are only ever returned here
+    pub fn generate_recovery_codes(&mut self) -> Vec<String> {
+        let codes = (0..RECOVERY_CODE_COUNT)
+            .map(|_| {
+                let code = random_string(10).to_ascii_lowercase();
+                format!("{}-{}", &code[..5], &code[5..])
+            })
+            .collect::<Vec<_>>();
+
+        self.recovery_code_hashes = codes
+            .iter()
+            .map(|code| encode_hex(&hash_secret_token(&normalize_recovery_code(code))))
+            .collect();
+
+        codes
+    }
+
+    /// Checks a recovery code, using it up if it's valid
+    pub fn use_recovery_code(&mut self, code: &str) -> bool {
+        let hash = encode_hex(&hash_secret_token(&normalize_recovery_code(code)));
+        let count = self.recovery_code_hashes.len();
+
+        self.recovery_code_hashes
+            .retain(|unused_hash| *unused_hash != hash);
+        self.recovery_code_hashes.len() < count
+    }
+
+    pub fn remaining_recovery_codes(&self) -> usize {
+        self.recovery_code_hashes.len()
+    }
+
+    /// Checks either a code from the authenticator app or a recovery code, whichever was given
+    pub fn verify(&mut self, code: Option<&str>, recovery_code: Option<&str>) -> bool {
+        match (code, recovery_code) {
+            (Some(code), _) => self.verify_code(code),
+            (None, Some(recovery_code)) => self.use_recovery_code(recovery_code),
+            (None, None) => false,
+        }
+    }
+}
+
+/// Loads the `totp` identity of a user without locking it, for when it's only looked at
+pub async fn find_totp_identity<'e>(
+    db: impl sqlx::PgExecutor<'e>,
+    user_id: Xid,
+) -> Result<Option<TotpIdentityData>, Box<dyn Error>> {
+    let data = sqlx::query_scalar!(
+        "SELECT data FROM user_identities WHERE user_id = $1 AND provider = $2",
+        user_id.as_bytes(),
+        TOTP_IDENTITY_PROVIDER,
+    )
+    .fetch_optional(db)
+    .await?;
+
+    match data {
+        Some(data) => Ok(Some(serde_json::from_value(data)?)),
+        None => Ok(None),
+    }
+}
+
+/// Loads the `totp` identity of a user, locking it for the rest of the transaction so a code can't be used by two
+/// requests at once. Has to be called inside a transaction, see `find_totp_identity` otherwise.
+pub async fn load_totp_identity(
+    db: &mut sqlx::PgConnection,
+    user_id: Xid,
+) -> Result<Option<(Xid, TotpIdentityData)>, Box<dyn Error>> {
+    let identity = sqlx::query!(
+        "SELECT id, data FROM user_identities WHERE user_id = $1 AND provider = $2 FOR UPDATE",
+        user_id.as_bytes(),
+        TOTP_IDENTITY_PROVIDER,
+    )
+    .fetch_optional(db)
+    .await?;
+
+    match identity {
+        Some(identity) => Ok(Some((
+            Xid::from(identity.id),
+            serde_json::from_value(identity.data)?,
+        ))),
+        None => Ok(None),
+    }
+}
+
+pub async fn save_totp_identity(
+    db: &mut sqlx::PgConnection,
+    user_id: Xid,
+    data: &TotpIdentityData,
+) -> Result<(), Box<dyn Error>> {
+    let id = Xid::new();
+
+    sqlx::query!(
+        "INSERT INTO user_identities (id, user_id, provider, data) VALUES ($1, $2, $3, $4) \
+        ON CONFLICT (user_id, provider) DO UPDATE SET data = EXCLUDED.data",
+        id.as_bytes(),
+        user_id.as_bytes(),
+        TOTP_IDENTITY_PROVIDER,
+        serde_json::to_value(data)?,
+    )
+    .execute(db)
+    .await?;
+
+    Ok(())
+}
+
+/// Whether logging in as the user requires a code from their authenticator app
+pub async fn has_confirmed_totp_identity(
+    db: &sqlx::Pool<sqlx::Postgres>,
+    user_id: Xid,
+) -> Result<bool, sqlx::Error> {
+    let record = sqlx::query!(
+        "SELECT EXISTS(SELECT FROM user_identities WHERE user_id = $1 AND provider = $2 AND data->>'confirmed_at' IS NOT NULL) AS \"exists!\"",
+        user_id.as_bytes(),
+        TOTP_IDENTITY_PROVIDER,
+    )
+    .fetch_one(db)
+    .await?;
+
+    Ok(record.exists)
+}
+
+pub struct LoginChallenge {
+    pub token: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+/// Starts the second step of a login for a user whose password was correct, returning the token it's completed with
+pub async fn create_login_challenge(
+    db: &sqlx::Pool<sqlx::Postgres>,
+    user_id: Xid,
+) -> Result<LoginChallenge, sqlx::Error> {
+    sqlx::query!("DELETE FROM user_login_challenges WHERE expires_at <= NOW()")
+        .execute(db)
+        .await?;
+
+    let id = Xid::new();
+    let token = random_string(64);
+    let expires_at = Utc::now() + LOGIN_CHALLENGE_LIFETIME;
+
+    sqlx::query!(
+        "INSERT INTO user_login_challenges (id, user_id, token_hash, expires_at) VALUES ($1, $2, $3, $4)",
+        id.as_bytes(),
+        user_id.as_bytes(),
+        hash_secret_token(&token),
+        expires_at,
+    )
+    .execute(db)
+    .await?;
+
+    Ok(LoginChallenge { token, expires_at })
+}
+
+pub struct PendingLoginChallenge {
+    pub id: Xid,
+    pub user_id: Xid,
+}
+
+/// Looks up a login challenge which hasn't expired yet
+pub async fn find_login_challenge(
+    db: &mut sqlx::PgConnection,
+    token: &str,
+) -> Result<Option<PendingLoginChallenge>, sqlx::Error> {
+    let challenge = sqlx::query!(
+        "SELECT id, user_id FROM user_login_challenges WHERE token_hash = $1 AND expires_at > NOW() FOR UPDATE",
+        hash_secret_token(token),
+    )
+    .fetch_optional(db)
+    .await?;
+
+    Ok(challenge.map(|challenge| PendingLoginChallenge {
+        id: Xid::from(challenge.id),
+        user_id: Xid::from(challenge.user_id),
+    }))
+}
+
+/// Counts a wrong code against a login challenge, throwing the challenge away once it's had too many
+pub async fn fail_login_challenge(
+    db: &mut sqlx::PgConnection,
+    challenge_id: Xid,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "UPDATE user_login_challenges SET failed_attempts = failed_attempts + 1 WHERE id = $1",
+        challenge_id.as_bytes(),
+    )
+    .execute(&mut *db)
+    .await?;
+
+    sqlx::query!(
+        "DELETE FROM user_login_challenges WHERE id = $1 AND failed_attempts >= $2",
+        challenge_id.as_bytes(),
+        MAX_LOGIN_CHALLENGE_ATTEMPTS,
+    )
+    .execute(db)
+    .await?;
+
+    Ok(())
+}
+
+pub async fn complete_login_challenge(
+    db: &mut sqlx::PgConnection,
+    challenge_id: Xid,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "DELETE FROM user_login_challenges WHERE id = $1",
+        challenge_id.as_bytes(),
+    )
+    .execute(db)
+    .await?;
+
+    Ok(())
+}
+
+/// Throws away the user's unfinished logins, which got past a password that may not be theirs anymore
+pub async fn delete_login_challenges<'e>(
+    db: impl sqlx::PgExecutor<'e>,
+    user_id: Xid,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "DELETE FROM user_login_challenges WHERE user_id = $1",
+        user_id.as_bytes(),
+    )
+    .execute(db)
+    .await?;
+
+    Ok(())
+}
@@ -0,0 +1,202 @@
+use std::{error::Error, time::Duration};
+
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use webauthn_rs::{
+    prelude::{
+        AuthenticationResult, CreationChallengeResponse, DiscoverableAuthentication, Passkey,
+        PasskeyRegistration, Url, Uuid,
+    },
+    Webauthn, WebauthnBuilder,
+};
+use webauthn_rs_proto::ResidentKeyRequirement;
+
+use crate::{config::Config, utils::xid::Xid};
+
+pub const WEBAUTHN_IDENTITY_PROVIDER: &str = "webauthn";
+/// Shown by the browser when creating a passkey
+const WEBAUTHN_RP_NAME: &str = "Floppy";
+/// How long the user has to complete a registration or login after it was started
+const WEBAUTHN_CEREMONY_LIFETIME: Duration = Duration::from_secs(5 * 60);
+
+/// Passkeys are bound to the domain of the frontend, which is where the browser creates & uses them
+pub fn build_webauthn(config: &Config) -> Result<Webauthn, Box<dyn Error>> {
+    let origin = Url::parse(&config.frontend_url)?;
+    let rp_id = origin
+        .host_str()
+        .ok_or("FRONTEND_URL must have a host to be used for passkeys")?;
+
+    Ok(WebauthnBuilder::new(rp_id, &origin)?
+        .rp_name(WEBAUTHN_RP_NAME)
+        .build()?)
+}
+
+/// Asks the authenticator to store the passkey as a discoverable credential, logins are always started without saying
+/// whose passkeys are expected so any others couldn't be used
+pub fn require_discoverable_passkey(options: &mut CreationChallengeResponse) {
+    if let Some(selection) = &mut options.public_key.authenticator_selection {
+        selection.resident_key = Some(ResidentKeyRequirement::Required);
+        selection.require_resident_key = true;
+    }
+}
+
+/// The `data` of a user's `webauthn` identity
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WebauthnIdentityData {
+    /// Stored in every passkey of the user, which is how the user is found when logging in with a discoverable
+    /// credential. Unlike the user's id it doesn't reveal when the account was created.
+    pub user_handle: Uuid,
+}
+
+/// Returns the user handle of the user's `webauthn` identity, creating the identity if they don't have one yet
+pub async fn get_or_create_user_handle(
+    db: &mut sqlx::PgConnection,
+    user_id: Xid,
+) -> Result<Uuid, Box<dyn Error>> {
+    let id = Xid::new();
+    let data = WebauthnIdentityData {
+        user_handle: Uuid::new_v4(),
+    };
+
+    sqlx::query!(
+        "INSERT INTO user_identities (id, user_id, provider, data) VALUES ($1, $2, $3, $4) \
+        ON CONFLICT (user_id, provider) DO NOTHING",
+        id.as_bytes(),
+        user_id.as_bytes(),
+        WEBAUTHN_IDENTITY_PROVIDER,
+        serde_json::to_value(&data)?,
+    )
+    .execute(&mut *db)
+    .await?;
+
+    let identity = sqlx::query!(
+        "SELECT data FROM user_identities WHERE user_id = $1 AND provider = $2",
+        user_id.as_bytes(),
+        WEBAUTHN_IDENTITY_PROVIDER,
+    )
+    .fetch_one(db)
+    .await?;
+
+    Ok(serde_json::from_value::<WebauthnIdentityData>(identity.data)?.user_handle)
+}
+
+pub async fn find_user_by_handle(
+    db: &sqlx::Pool<sqlx::Postgres>,
+    user_handle: Uuid,
+) -> Result<Option<Xid>, sqlx::Error> {
+    let identity = sqlx::query!(
+        "SELECT user_id FROM user_identities WHERE provider = $1 AND data->>'user_handle' = $2",
+        WEBAUTHN_IDENTITY_PROVIDER,
+        user_handle.to_string(),
+    )
+    .fetch_optional(db)
+    .await?;
+
+    Ok(identity.map(|identity| Xid::from(identity.user_id)))
+}
+
+/// What has to be kept on the server between starting & finishing a ceremony, the library makes sure the challenge
+/// it contains can only be answered once
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "kind", content = "state", rename_all = "snake_case")]
+pub enum WebauthnCeremonyState {
+    Registration(PasskeyRegistration),
+    /// Logging in with whichever passkey the browser offers, the user is only known once it has answered
+    DiscoverableAuthentication(DiscoverableAuthentication),
+}
+
+pub async fn start_ceremony(
+    db: &sqlx::Pool<sqlx::Postgres>,
+    user_id: Option<Xid>,
+    state: &WebauthnCeremonyState,
+) -> Result<Xid, Box<dyn Error>> {
+    sqlx::query!("DELETE FROM user_webauthn_ceremonies WHERE expires_at <= NOW()")
+        .execute(db)
+        .await?;
+
+    let id = Xid::new();
+
+    sqlx::query!(
+        "INSERT INTO user_webauthn_ceremonies (id, user_id, state, expires_at) VALUES ($1, $2, $3, $4)",
+        id.as_bytes(),
+        user_id.as_ref().map(|user_id| user_id.as_bytes().as_slice()),
+        serde_json::to_value(state)?,
+        Utc::now() + WEBAUTHN_CEREMONY_LIFETIME,
+    )
+    .execute(db)
+    .await?;
+
+    Ok(id)
+}
+
+pub struct WebauthnCeremony {
+    pub user_id: Option<Xid>,
+    pub state: WebauthnCeremonyState,
+}
+
+/// Removes a ceremony which hasn't expired yet & returns its state, so each one can only be finished once
+pub async fn take_ceremony(
+    db: &sqlx::Pool<sqlx::Postgres>,
+    id: Xid,
+) -> Result<Option<WebauthnCeremony>, Box<dyn Error>> {
+    let ceremony = sqlx::query!(
+        "DELETE FROM user_webauthn_ceremonies WHERE id = $1 RETURNING user_id, state, expires_at > NOW() AS \"is_valid!\"",
+        id.as_bytes(),
+    )
+    .fetch_optional(db)
+    .await?;
+
+    match ceremony.filter(|ceremony| ceremony.is_valid) {
+        Some(ceremony) => Ok(Some(WebauthnCeremony {
+            user_id: ceremony.user_id.map(Xid::from),
+            state: serde_json::from_value(ceremony.state)?,
+        })),
+        None => Ok(None),
+    }
+}
+
+pub struct WebauthnCredential {
+    pub id: Xid,
+    pub passkey: Passkey,
+}
+
+pub async fn load_user_credentials(
+    db: &sqlx::Pool<sqlx::Postgres>,
+    user_id: Xid,
+) -> Result<Vec<WebauthnCredential>, Box<dyn Error>> {
+    let credentials = sqlx::query!(
+        "SELECT id, passkey FROM user_webauthn_credentials WHERE user_id = $1",
+        user_id.as_bytes(),
+    )
+    .fetch_all(db)
+    .await?;
+
+    credentials
+        .into_iter()
+        .map(|credential| {
+            Ok(WebauthnCredential {
+                id: Xid::from(credential.id),
+                passkey: serde_json::from_value(credential.passkey)?,
+            })
+        })
+        .collect()
+}
+
+/// Stores the signature counter & backup state reported by the authenticator, along with when the passkey was used
+pub async fn record_credential_use(
+    db: &sqlx::Pool<sqlx::Postgres>,
+    mut credential: WebauthnCredential,
+    result: &AuthenticationResult,
+) -> Result<(), Box<dyn Error>> {
+    credential.passkey.update_credential(result);
+
+    sqlx::query!(
+        "UPDATE user_webauthn_credentials SET passkey = $2, last_used_at = NOW() WHERE id = $1",
+        credential.id.as_bytes(),
+        serde_json::to_value(&credential.passkey)?,
+    )
+    .execute(db)
+    .await?;
+
+    Ok(())
+}
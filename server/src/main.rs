@@ -1,6 +1,14 @@
 use config::{load_config, Config};
 use core::panic;
-use logic::indexing::local_folder::setup_local_folder_vault_watchers;
+use logic::{
+    content_hashing::run_content_hasher,
+    indexing::{
+        jobs::run_interrupted_index_job_cleanup, local_folder::setup_local_folder_vault_watchers,
+    },
+    job_queue::start_job_workers,
+    login_throttling::run_failed_login_cleanup,
+    refresh_tokens::run_refresh_token_cleanup,
+};
 use poem::{
     listener::TcpListener,
     middleware::{AddData, CatchPanic, Cors, NormalizePath, TrailingSlash},
@@ -32,12 +40,24 @@ async fn main() -> Result<(), Box<dyn Error>> {
 
     match args.next().unwrap_or("".to_string()).as_str() {
         "" => {
-            println!("Please specify one of the following commands: serve, createuser, createvault")
+            println!("Please specify one of the following commands: serve, createuser, createvault, indexvault, listjobs, retryjob, canceljob, killsessions, resettotp, createinvite, failedlogins, unlocklogin")
         }
         "serve" => run_server(config, pool).await?,
         "createuser" | "create_user" => cli::create_user(config, pool, &mut args).await?,
         "createvault" | "create_vault" => cli::create_vault(config, pool, &mut args).await?,
         "indexvault" | "index_vault" => cli::index_vault(config, pool, &mut args).await?,
+        "listjobs" | "list_jobs" => cli::list_jobs(config, pool, &mut args).await?,
+        "retryjob" | "retry_job" => cli::retry_job(config, pool, &mut args).await?,
+        "canceljob" | "cancel_job" => cli::cancel_job(config, pool, &mut args).await?,
+        "killsessions" | "kill_sessions" => cli::kill_sessions(config, pool, &mut args).await?,
+        "resettotp" | "reset_totp" => cli::reset_totp(config, pool, &mut args).await?,
+        "createinvite" | "create_invite" => {
+            cli::create_user_invite(config, pool, &mut args).await?
+        }
+        "failedlogins" | "failed_logins" => {
+            cli::list_failed_logins(config, pool, &mut args).await?
+        }
+        "unlocklogin" | "unlock_login" => cli::unlock_logins(config, pool, &mut args).await?,
         cmd => panic!("Unknown command {:#?}", cmd),
     }
 
@@ -68,7 +88,13 @@ async fn run_server(
     config: Config,
     pool: sqlx::Pool<sqlx::Postgres>,
 ) -> Result<(), Box<dyn Error>> {
-    setup_local_folder_vault_watchers(pool.clone()).await?;
+    setup_local_folder_vault_watchers(&config, pool.clone()).await?;
+
+    tokio::spawn(run_interrupted_index_job_cleanup(pool.clone()));
+    tokio::spawn(run_content_hasher(pool.clone()));
+    tokio::spawn(run_refresh_token_cleanup(config.clone(), pool.clone()));
+    tokio::spawn(run_failed_login_cleanup(pool.clone()));
+    start_job_workers(&config, pool.clone());
 
     run_api(config, pool.clone()).await?;
 
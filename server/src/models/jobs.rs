@@ -0,0 +1,22 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::FromRow;
+
+use crate::utils::xid::Xid;
+
+#[allow(dead_code)]
+#[derive(Debug, FromRow, Serialize)]
+pub struct Job {
+    pub id: Xid,
+    pub kind: String,
+    pub payload: serde_json::Value,
+    /// One of `pending`, `running`, `succeeded`, `failed` or `cancelled`
+    pub state: String,
+    pub attempts: i32,
+    pub max_attempts: i32,
+    pub run_at: DateTime<Utc>,
+    pub locked_until: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+    pub finished_at: Option<DateTime<Utc>>,
+    pub last_error: Option<String>,
+}
@@ -25,10 +25,16 @@ pub struct UserIdentity {
 #[derive(Debug, FromRow)]
 pub struct UserRefreshToken {
     pub id: Xid,
+    /// Id of the first token issued on login, which each refresh replaces with the next token of the family
+    pub family_id: Xid,
     pub user_id: Xid,
     pub token_hash: Vec<u8>,
     pub created_at: DateTime<Utc>,
     pub last_used_at: Option<DateTime<Utc>>,
     pub user_agent: Option<String>,
     pub remote_address: String,
+    pub expires_at: DateTime<Utc>,
+    pub revoked_at: Option<DateTime<Utc>>,
+    /// When the token was exchanged for the next one of its family, after which it can't be used again
+    pub rotated_at: Option<DateTime<Utc>>,
 }
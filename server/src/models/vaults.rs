@@ -1,8 +1,8 @@
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, NaiveDateTime, Utc};
 use serde::Serialize;
 use sqlx::{types::Json, FromRow};
 
-use crate::utils::xid::Xid;
+use crate::utils::{hex::serialize_optional_hex, xid::Xid};
 
 #[allow(dead_code)]
 #[derive(Debug, FromRow, Serialize)]
@@ -31,5 +31,55 @@ pub struct VaultFile {
     pub file_type: String,
     pub parent_id: Option<Vec<u8>>,
     pub created_at: Option<DateTime<Utc>>,
+    pub modified_at: Option<DateTime<Utc>>,
     pub size: Option<i64>,
+    /// Unix permission & file type bits
+    pub mode: Option<i32>,
+    pub is_hidden: bool,
+    pub symlink_target: Option<String>,
+    #[serde(serialize_with = "serialize_optional_hex")]
+    pub content_sha256: Option<Vec<u8>>,
+    pub mime_type: Option<String>,
+    /// Total size of the files anywhere below this folder, always 0 for files
+    pub subtree_size: i64,
+    pub subtree_file_count: i64,
+    pub subtree_folder_count: i64,
+}
+
+/// Metadata read from the headers of images, audio and video files, anything which couldn't be read is left empty
+#[derive(Debug, Default, FromRow, Serialize)]
+pub struct VaultFileMediaMetadata {
+    pub width: Option<i32>,
+    pub height: Option<i32>,
+    /// EXIF orientation (1-8), the width & height are of the image as stored rather than as displayed
+    pub orientation: Option<i16>,
+    /// Local time of the camera, EXIF doesn't reliably record a time zone
+    pub captured_at: Option<NaiveDateTime>,
+    pub camera_make: Option<String>,
+    pub camera_model: Option<String>,
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+    /// Metres above sea level
+    pub altitude: Option<f64>,
+    pub duration_seconds: Option<f64>,
+}
+
+#[allow(dead_code)]
+#[derive(Debug, FromRow, Serialize)]
+pub struct VaultIndexJob {
+    pub id: Xid,
+    pub vault_id: Xid,
+    /// Either `reindex` or `reconcile`
+    pub kind: String,
+    /// One of `pending`, `running`, `succeeded`, `failed` or `cancelled`
+    pub state: String,
+    pub created_at: DateTime<Utc>,
+    pub started_at: Option<DateTime<Utc>>,
+    pub finished_at: Option<DateTime<Utc>>,
+    pub files_total: Option<i64>,
+    pub files_seen: i64,
+    pub error_count: i64,
+    pub errors: Vec<String>,
+    /// Percentage of the files which have been indexed so far
+    pub progress: i32,
 }
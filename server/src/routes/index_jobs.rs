@@ -0,0 +1,127 @@
+use crate::{
+    logic::{
+        indexing::jobs::{IndexJob, IndexJobKind},
+        job_queue::{enqueue_job, JobPayload},
+    },
+    models::vaults::VaultIndexJob,
+    utils::{response_errors::ForbiddenError, user_security::AuthenticatedUser, xid::Xid},
+};
+use poem::{
+    error::NotFoundError,
+    handler,
+    web::{Data, Json, Path},
+};
+use serde::Deserialize;
+
+const LISTED_INDEX_JOBS_LIMIT: i64 = 20;
+
+#[handler]
+pub async fn list_vault_index_jobs(
+    db: Data<&sqlx::Pool<sqlx::Postgres>>,
+    user: AuthenticatedUser,
+    Path((vault_id,)): Path<(Xid,)>,
+) -> poem::Result<Json<Vec<VaultIndexJob>>> {
+    let jobs = sqlx::query_as!(
+        VaultIndexJob,
+        "SELECT \
+            id, vault_id, kind, state, created_at, started_at, finished_at, files_total, files_seen, error_count, errors, progress \
+        FROM vault_index_jobs \
+        WHERE \
+            EXISTS(SELECT FROM user_vault_links WHERE user_vault_links.user_id = $1 AND user_vault_links.vault_id = vault_index_jobs.vault_id) \
+            AND vault_id = $2 \
+        ORDER BY created_at DESC LIMIT $3",
+        user.id.as_bytes(),
+        vault_id.as_bytes(),
+        LISTED_INDEX_JOBS_LIMIT,
+    )
+    .fetch_all(db.0)
+    .await
+    .unwrap();
+
+    Ok(Json(jobs))
+}
+
+#[handler]
+pub async fn get_vault_index_job(
+    db: Data<&sqlx::Pool<sqlx::Postgres>>,
+    user: AuthenticatedUser,
+    Path((vault_id, job_id)): Path<(Xid, Xid)>,
+) -> poem::Result<Json<VaultIndexJob>> {
+    let job = sqlx::query_as!(
+        VaultIndexJob,
+        "SELECT \
+            id, vault_id, kind, state, created_at, started_at, finished_at, files_total, files_seen, error_count, errors, progress \
+        FROM vault_index_jobs \
+        WHERE \
+            EXISTS(SELECT FROM user_vault_links WHERE user_vault_links.user_id = $1 AND user_vault_links.vault_id = vault_index_jobs.vault_id) \
+            AND vault_id = $2 AND id = $3",
+        user.id.as_bytes(),
+        vault_id.as_bytes(),
+        job_id.as_bytes(),
+    )
+    .fetch_optional(db.0)
+    .await
+    .unwrap();
+
+    Ok(Json(job.ok_or(NotFoundError)?))
+}
+
+#[derive(Deserialize)]
+pub struct StartVaultIndexJobRequest {
+    #[serde(default)]
+    kind: IndexJobKind,
+}
+
+#[handler]
+pub async fn start_vault_index_job(
+    db: Data<&sqlx::Pool<sqlx::Postgres>>,
+    user: AuthenticatedUser,
+    Path((vault_id,)): Path<(Xid,)>,
+    Json(request): Json<StartVaultIndexJobRequest>,
+) -> poem::Result<Json<VaultIndexJob>> {
+    let link = sqlx::query!(
+        "SELECT is_admin FROM user_vault_links WHERE user_id = $1 AND vault_id = $2",
+        user.id.as_bytes(),
+        vault_id.as_bytes(),
+    )
+    .fetch_optional(db.0)
+    .await
+    .unwrap();
+
+    match link {
+        None => return Err(NotFoundError.into()),
+        Some(link) if !link.is_admin => return Err(ForbiddenError.into()),
+        Some(_) => {}
+    }
+
+    // The job is run by the job queue, so it survives the server restarting
+    let mut tx = db.0.begin().await.unwrap();
+
+    let job_id = IndexJob::queue(&mut *tx, vault_id, request.kind)
+        .await
+        .unwrap();
+    enqueue_job(
+        &mut *tx,
+        &JobPayload::IndexVault {
+            vault_id,
+            index_job_id: job_id,
+        },
+    )
+    .await
+    .unwrap();
+
+    tx.commit().await.unwrap();
+
+    let job = sqlx::query_as!(
+        VaultIndexJob,
+        "SELECT \
+            id, vault_id, kind, state, created_at, started_at, finished_at, files_total, files_seen, error_count, errors, progress \
+        FROM vault_index_jobs WHERE id = $1",
+        job_id.as_bytes(),
+    )
+    .fetch_one(db.0)
+    .await
+    .unwrap();
+
+    Ok(Json(job))
+}
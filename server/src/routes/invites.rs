@@ -0,0 +1,203 @@
+use chrono::{DateTime, Utc};
+use poem::{
+    error::NotFoundError,
+    handler,
+    http::{HeaderMap, StatusCode},
+    web::{
+        headers::{HeaderMapExt, UserAgent},
+        Data, Json, Path, RemoteAddr,
+    },
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::Config,
+    logic::{
+        invites::{create_invite, invite_signup_url, redeem_invite, InviteVaultLink},
+        refresh_tokens::{issue_user_tokens, RefreshTokenClient},
+    },
+    utils::{
+        response_errors::ForbiddenError,
+        user_security::{AuthenticatedUser, UserTokens},
+        xid::Xid,
+    },
+};
+
+#[derive(Serialize)]
+struct InviteInfo {
+    id: Xid,
+    created_at: DateTime<Utc>,
+    expires_at: DateTime<Utc>,
+    redeemed_at: Option<DateTime<Utc>>,
+    redeemed_by: Option<Xid>,
+    vaults: Vec<InviteVaultLink>,
+}
+
+/// Lists the invites the user created, most recent first
+#[handler]
+pub async fn list_invites(
+    db: Data<&sqlx::Pool<sqlx::Postgres>>,
+    user: AuthenticatedUser,
+) -> poem::Result<Json<Vec<InviteInfo>>> {
+    let invites = sqlx::query!(
+        "SELECT id, created_at, expires_at, redeemed_at, redeemed_by FROM user_invites WHERE created_by = $1 ORDER BY created_at DESC",
+        user.id.as_bytes(),
+    )
+    .fetch_all(db.0)
+    .await
+    .unwrap();
+
+    let vault_links = sqlx::query!(
+        "SELECT invite_id, vault_id, is_admin FROM user_invite_vault_links WHERE invite_id = ANY($1)",
+        &invites
+            .iter()
+            .map(|invite| invite.id.clone())
+            .collect::<Vec<_>>(),
+    )
+    .fetch_all(db.0)
+    .await
+    .unwrap();
+
+    Ok(Json(
+        invites
+            .into_iter()
+            .map(|invite| InviteInfo {
+                vaults: vault_links
+                    .iter()
+                    .filter(|link| link.invite_id == invite.id)
+                    .map(|link| InviteVaultLink {
+                        vault_id: Xid::from(link.vault_id.clone()),
+                        is_admin: link.is_admin,
+                    })
+                    .collect(),
+                id: Xid::from(invite.id),
+                created_at: invite.created_at,
+                expires_at: invite.expires_at,
+                redeemed_at: invite.redeemed_at,
+                redeemed_by: invite.redeemed_by.map(Xid::from),
+            })
+            .collect(),
+    ))
+}
+
+#[derive(Deserialize)]
+struct CreateInviteData {
+    pub vaults: Vec<InviteVaultLink>,
+}
+
+#[derive(Serialize)]
+struct CreatedInviteInfo {
+    id: Xid,
+    /// Can't be retrieved again later
+    code: String,
+    signup_url: String,
+    expires_at: DateTime<Utc>,
+}
+
+/// Creates an invite to vaults the user is an admin of. Accounts can only be created without any vaults from the CLI,
+/// so an invite always has to give access to at least one vault.
+#[handler]
+pub async fn create_user_invite(
+    db: Data<&sqlx::Pool<sqlx::Postgres>>,
+    config: Data<&Config>,
+    user: AuthenticatedUser,
+    data: Json<CreateInviteData>,
+) -> poem::Result<Json<CreatedInviteInfo>> {
+    if data.vaults.is_empty() {
+        return Err(poem::Error::from_string(
+            "Invites have to give access to at least one vault",
+            StatusCode::BAD_REQUEST,
+        ));
+    }
+
+    for vault in &data.vaults {
+        let link = sqlx::query!(
+            "SELECT is_admin FROM user_vault_links WHERE user_id = $1 AND vault_id = $2",
+            user.id.as_bytes(),
+            vault.vault_id.as_bytes(),
+        )
+        .fetch_optional(db.0)
+        .await
+        .unwrap();
+
+        match link {
+            None => return Err(NotFoundError.into()),
+            Some(link) if !link.is_admin => return Err(ForbiddenError.into()),
+            Some(_) => {}
+        }
+    }
+
+    let invite = create_invite(db.0, &config, Some(user.id), &data.vaults)
+        .await
+        .unwrap();
+
+    Ok(Json(CreatedInviteInfo {
+        id: invite.id,
+        signup_url: invite_signup_url(&config, &invite.code),
+        code: invite.code,
+        expires_at: invite.expires_at,
+    }))
+}
+
+/// Takes back an invite the user created, as long as it hasn't been redeemed yet
+#[handler]
+pub async fn delete_user_invite(
+    db: Data<&sqlx::Pool<sqlx::Postgres>>,
+    user: AuthenticatedUser,
+    Path((invite_id,)): Path<(Xid,)>,
+) -> poem::Result<StatusCode> {
+    let deleted = sqlx::query!(
+        "DELETE FROM user_invites WHERE id = $1 AND created_by = $2 AND redeemed_at IS NULL",
+        invite_id.as_bytes(),
+        user.id.as_bytes(),
+    )
+    .execute(db.0)
+    .await
+    .unwrap()
+    .rows_affected();
+
+    if deleted == 0 {
+        return Err(NotFoundError.into());
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Deserialize)]
+struct SignupData {
+    pub invite_code: String,
+    pub name: String,
+    pub email: String,
+    pub password: String,
+}
+
+/// Creates an account with an invite code & logs into it
+#[handler]
+pub async fn signup(
+    db: Data<&sqlx::Pool<sqlx::Postgres>>,
+    config: Data<&Config>,
+    data: Json<SignupData>,
+    headers: &HeaderMap,
+    remote_address: &RemoteAddr,
+) -> poem::Result<Json<UserTokens>> {
+    let user_id = redeem_invite(
+        db.0,
+        &data.invite_code,
+        &data.name,
+        &data.email,
+        &data.password,
+    )
+    .await?;
+
+    let user_agent = headers.typed_get::<UserAgent>().map(|ua| ua.to_string());
+    let remote_address = remote_address.to_string();
+    let client = RefreshTokenClient {
+        user_agent: user_agent.as_deref(),
+        remote_address: &remote_address,
+    };
+    let tokens = issue_user_tokens(db.0, &config, user_id, &client)
+        .await
+        .unwrap();
+
+    Ok(Json(tokens))
+}
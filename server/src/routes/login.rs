@@ -1,25 +1,45 @@
 use std::time::Duration;
 
-use argon2::{Argon2, PasswordHash, PasswordVerifier};
 use chrono::{DateTime, Utc};
 use poem::{
+    error::NotFoundError,
     handler,
-    http::HeaderMap,
+    http::{HeaderMap, StatusCode},
     web::{
         headers::{HeaderMapExt, UserAgent},
         Data, Json, RemoteAddr,
     },
     Error,
 };
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use webauthn_rs::prelude::{DiscoverableKey, PublicKeyCredential, RequestChallengeResponse};
 
 use crate::{
     config::Config,
+    logic::{
+        ldap::{authenticate_ldap_user, find_ldap_user_id, sync_ldap_user, LdapLoginError},
+        login_throttling::{
+            forget_login_attempt, record_failed_login, record_successful_login, remote_ip,
+            start_login_attempt, LoginAttempt, LoginMethod,
+        },
+        oidc::{find_or_link_user, finish_oidc_login, start_oidc_login},
+        passwords::load_password_identity,
+        refresh_tokens::{issue_user_tokens, RefreshTokenClient},
+        security_events::{record_security_event, SecurityEventKind},
+        totp::{
+            complete_login_challenge, create_login_challenge, fail_login_challenge,
+            find_login_challenge, has_confirmed_totp_identity, load_totp_identity,
+            save_totp_identity,
+        },
+        webauthn::{
+            build_webauthn, find_user_by_handle, load_user_credentials, record_credential_use,
+            start_ceremony, take_ceremony, WebauthnCeremonyState,
+        },
+    },
     models::users::User,
     utils::{
-        response_errors::ForbiddenError,
-        security::ensure_execution_time,
-        user_security::{self, hash_refresh_token},
+        response_errors::ForbiddenError, security::ensure_execution_time, user_security, xid::Xid,
     },
 };
 
@@ -29,9 +49,38 @@ struct EmailAndPasswordLoginData {
     pub password: String,
 }
 
-#[derive(Deserialize)]
-struct PasswordIdentityData {
-    password: String,
+/// Users with two-factor authentication get a challenge instead of tokens, which is completed at `/login/totp/`
+#[derive(Serialize)]
+#[serde(untagged)]
+pub enum LoginResponse {
+    Tokens(user_security::UserTokens),
+    TwoFactorChallenge {
+        two_factor_challenge: String,
+        expires_at: DateTime<Utc>,
+    },
+}
+
+/// Issues tokens for a user whose password was correct, unless they still need to enter a second factor
+async fn complete_password_login(
+    db: &sqlx::Pool<sqlx::Postgres>,
+    config: &Config,
+    user_id: Xid,
+    client: &RefreshTokenClient<'_>,
+) -> LoginResponse {
+    if has_confirmed_totp_identity(db, user_id).await.unwrap() {
+        let challenge = create_login_challenge(db, user_id).await.unwrap();
+
+        return LoginResponse::TwoFactorChallenge {
+            two_factor_challenge: challenge.token,
+            expires_at: challenge.expires_at,
+        };
+    }
+
+    LoginResponse::Tokens(
+        issue_user_tokens(db, config, user_id, client)
+            .await
+            .unwrap(),
+    )
 }
 
 #[handler]
@@ -41,13 +90,23 @@ pub async fn login_email_and_password(
     data: Json<EmailAndPasswordLoginData>,
     headers: &HeaderMap,
     remote_address: &RemoteAddr,
-) -> poem::Result<Json<user_security::UserTokens>> {
+) -> poem::Result<Json<LoginResponse>> {
     let user_agent = headers.typed_get::<UserAgent>().map(|ua| ua.to_string());
 
     // TODO: Support X-Forwarded-For / CF-Connecting-IP
+    let remote_ip = remote_ip(remote_address);
     let remote_address = remote_address.to_string();
 
-    let tokens: Result<user_security::UserTokens, Error> = ensure_execution_time(Duration::from_millis(1000), || Box::pin(async {
+    let attempt = LoginAttempt {
+        identifier: &data.email,
+        method: LoginMethod::EmailAndPassword,
+        remote_address: &remote_ip,
+        user_agent: user_agent.as_deref(),
+    };
+
+    let response: Result<LoginResponse, Error> = ensure_execution_time(Duration::from_millis(1000), || Box::pin(async {
+        start_login_attempt(db.0, config.0, &attempt).await?;
+
         let user = sqlx::query_as!(
             User,
             "SELECT id, created_at, last_login_at, name, email FROM users WHERE LOWER(email) = LOWER($1)",
@@ -58,50 +117,437 @@ pub async fn login_email_and_password(
         .unwrap();
 
         if user.is_none() {
+            record_failed_login(db.0, &attempt, None).await.unwrap();
             return Err(ForbiddenError.into());
         }
         let user = user.unwrap();
 
-        let user_identity = sqlx::query!(
-            "SELECT data FROM user_identities WHERE user_id = $1 AND provider = $2",
-            user.id.as_bytes(),
-            "email_and_password"
-        )
-        .fetch_optional(db.0)
-        .await
-        .unwrap();
+        let password_identity = load_password_identity(db.0, user.id).await.unwrap();
 
-        if user_identity.is_none() {
+        if !password_identity.is_some_and(|identity| identity.verify(&data.password)) {
+            record_failed_login(db.0, &attempt, Some(user.id)).await.unwrap();
             return Err(ForbiddenError.into());
         }
-        let user_identity = user_identity.unwrap();
+        forget_login_attempt(db.0, config.0, &attempt).await.unwrap();
+
+        let client = RefreshTokenClient { user_agent: user_agent.as_deref(), remote_address: &remote_address };
+        let response = complete_password_login(db.0, config.0, user.id, &client).await;
+
+        // Users with two-factor authentication haven't logged in until they entered a code
+        if matches!(response, LoginResponse::Tokens(_)) {
+            record_successful_login(db.0, &attempt).await.unwrap();
+        }
+
+        Ok(response)
+    })).await;
+
+    Ok(Json(response?))
+}
+
+#[derive(Deserialize)]
+struct TotpLoginData {
+    pub two_factor_challenge: String,
+    pub code: Option<String>,
+    pub recovery_code: Option<String>,
+}
+
+/// Completes a login started at `/login/email_and_password/` with a code from the user's authenticator app or one of
+/// their recovery codes
+#[handler]
+pub async fn login_totp(
+    db: Data<&sqlx::Pool<sqlx::Postgres>>,
+    config: Data<&Config>,
+    data: Json<TotpLoginData>,
+    headers: &HeaderMap,
+    remote_address: &RemoteAddr,
+) -> poem::Result<Json<user_security::UserTokens>> {
+    let user_agent = headers.typed_get::<UserAgent>().map(|ua| ua.to_string());
+    let remote_ip = remote_ip(remote_address);
+    let remote_address = remote_address.to_string();
+
+    let tokens: Result<user_security::UserTokens, Error> =
+        ensure_execution_time(Duration::from_millis(1000), || {
+            Box::pin(async {
+                let mut tx = db.begin().await.unwrap();
+
+                let challenge = find_login_challenge(&mut tx, &data.two_factor_challenge)
+                    .await
+                    .unwrap();
+                if challenge.is_none() {
+                    return Err(ForbiddenError.into());
+                }
+                let challenge = challenge.unwrap();
+
+                // Wrong codes count against the same account as wrong passwords, so they can't be guessed either
+                let email = sqlx::query_scalar!(
+                    "SELECT email FROM users WHERE id = $1",
+                    challenge.user_id.as_bytes()
+                )
+                .fetch_one(&mut *tx)
+                .await
+                .unwrap();
+                let attempt = LoginAttempt {
+                    identifier: &email,
+                    method: LoginMethod::Totp,
+                    remote_address: &remote_ip,
+                    user_agent: user_agent.as_deref(),
+                };
+                start_login_attempt(db.0, config.0, &attempt).await?;
+
+                let identity = load_totp_identity(&mut tx, challenge.user_id)
+                    .await
+                    .unwrap();
+                let Some((_, mut identity_data)) =
+                    identity.filter(|(_, data)| data.confirmed_at.is_some())
+                else {
+                    forget_login_attempt(db.0, config.0, &attempt)
+                        .await
+                        .unwrap();
+                    return Err(ForbiddenError.into());
+                };
 
-        let user_identity_data = PasswordIdentityData::deserialize(user_identity.data).unwrap();
-        let hashed_password = PasswordHash::new(&user_identity_data.password).unwrap();
+                let used_recovery_code = data.code.is_none() && data.recovery_code.is_some();
+                if !identity_data.verify(data.code.as_deref(), data.recovery_code.as_deref()) {
+                    fail_login_challenge(&mut tx, challenge.id).await.unwrap();
+                    tx.commit().await.unwrap();
+                    record_failed_login(db.0, &attempt, Some(challenge.user_id))
+                        .await
+                        .unwrap();
 
-        let password_is_correct = Argon2::default()
-            .verify_password(data.password.as_bytes(), &hashed_password)
-            .is_ok();
+                    return Err(ForbiddenError.into());
+                }
+                forget_login_attempt(db.0, config.0, &attempt)
+                    .await
+                    .unwrap();
+
+                save_totp_identity(&mut tx, challenge.user_id, &identity_data)
+                    .await
+                    .unwrap();
+                complete_login_challenge(&mut tx, challenge.id)
+                    .await
+                    .unwrap();
+
+                if used_recovery_code {
+                    let remaining = identity_data.remaining_recovery_codes();
+
+                    record_security_event(
+                        &mut *tx,
+                        challenge.user_id,
+                        SecurityEventKind::TotpRecoveryCodeUsed,
+                        user_agent.as_deref(),
+                        Some(&remote_address),
+                        json!({ "remaining_recovery_codes": remaining }),
+                    )
+                    .await
+                    .unwrap();
+                }
+
+                tx.commit().await.unwrap();
+
+                let client = RefreshTokenClient {
+                    user_agent: user_agent.as_deref(),
+                    remote_address: &remote_address,
+                };
+                let tokens = issue_user_tokens(db.0, config.0, challenge.user_id, &client)
+                    .await
+                    .unwrap();
+                record_successful_login(db.0, &attempt).await.unwrap();
+
+                Ok(tokens)
+            })
+        })
+        .await;
+
+    Ok(Json(tokens?))
+}
+
+#[derive(Serialize)]
+struct WebauthnLoginStart {
+    ceremony_id: Xid,
+    /// Passed to `navigator.credentials.get()` by the frontend
+    options: RequestChallengeResponse,
+}
+
+/// Starts a passwordless login with a passkey. The browser offers whichever passkeys it has for the site, asking for
+/// the passkeys of a given account instead would tell anyone which accounts exist & have passkeys.
+#[handler]
+pub async fn start_webauthn_login(
+    db: Data<&sqlx::Pool<sqlx::Postgres>>,
+    config: Data<&Config>,
+    headers: &HeaderMap,
+    remote_address: &RemoteAddr,
+) -> poem::Result<Json<WebauthnLoginStart>> {
+    let user_agent = headers.typed_get::<UserAgent>().map(|ua| ua.to_string());
+    let remote_ip = remote_ip(remote_address);
+
+    // Each start stores a ceremony, which is counted against the IP address until the login is finished
+    let attempt = LoginAttempt {
+        identifier: "",
+        method: LoginMethod::Passkey,
+        remote_address: &remote_ip,
+        user_agent: user_agent.as_deref(),
+    };
+    start_login_attempt(db.0, config.0, &attempt).await?;
+
+    let webauthn = build_webauthn(&config).unwrap();
+
+    let (options, state) = webauthn.start_discoverable_authentication().unwrap();
+    let state = WebauthnCeremonyState::DiscoverableAuthentication(state);
+
+    let ceremony_id = start_ceremony(db.0, None, &state).await.unwrap();
+
+    Ok(Json(WebauthnLoginStart {
+        ceremony_id,
+        options,
+    }))
+}
+
+#[derive(Deserialize)]
+struct WebauthnLoginFinishData {
+    pub ceremony_id: Xid,
+    pub credential: PublicKeyCredential,
+}
+
+/// Completes a passkey login, which needs no second factor since passkeys require user verification
+#[handler]
+pub async fn finish_webauthn_login(
+    db: Data<&sqlx::Pool<sqlx::Postgres>>,
+    config: Data<&Config>,
+    data: Json<WebauthnLoginFinishData>,
+    headers: &HeaderMap,
+    remote_address: &RemoteAddr,
+) -> poem::Result<Json<user_security::UserTokens>> {
+    let user_agent = headers.typed_get::<UserAgent>().map(|ua| ua.to_string());
+    let remote_ip = remote_ip(remote_address);
+    let remote_address = remote_address.to_string();
 
-        if !password_is_correct {
+    let webauthn = build_webauthn(&config).unwrap();
+
+    let ceremony = take_ceremony(db.0, data.ceremony_id).await.unwrap();
+    let Some(ceremony) = ceremony else {
+        return Err(ForbiddenError.into());
+    };
+
+    let (user_id, credentials, result) = match (ceremony.state, ceremony.user_id) {
+        (WebauthnCeremonyState::DiscoverableAuthentication(state), None) => {
+            let Ok((user_handle, _)) =
+                webauthn.identify_discoverable_authentication(&data.credential)
+            else {
+                return Err(ForbiddenError.into());
+            };
+            let Some(user_id) = find_user_by_handle(db.0, user_handle).await.unwrap() else {
+                return Err(ForbiddenError.into());
+            };
+
+            let credentials = load_user_credentials(db.0, user_id).await.unwrap();
+            let keys = credentials
+                .iter()
+                .map(|credential| DiscoverableKey::from(&credential.passkey))
+                .collect::<Vec<_>>();
+            let result =
+                webauthn.finish_discoverable_authentication(&data.credential, state, &keys);
+
+            (user_id, credentials, result)
+        }
+        _ => return Err(ForbiddenError.into()),
+    };
+
+    let result = match result {
+        Ok(result) => result,
+        Err(error) => {
+            println!("Failed passkey login: {error:?}");
             return Err(ForbiddenError.into());
         }
+    };
 
-        let tokens = user_security::generate_tokens(&config.jwt_signing_key, &user.id);
+    // The passkey might have been removed since the login was started
+    let credential = credentials
+        .into_iter()
+        .find(|credential| credential.passkey.cred_id() == result.cred_id());
+    let Some(credential) = credential else {
+        return Err(ForbiddenError.into());
+    };
 
-        let refresh_token_id = xid::new();
-        let hashed_refresh_token = hash_refresh_token(&tokens.refresh_token);
+    record_credential_use(db.0, credential, &result)
+        .await
+        .unwrap();
 
-        sqlx::query!(
-            "INSERT INTO user_refresh_tokens (id, user_id, token_hash, last_used_at, created_at, user_agent, remote_address) VALUES ($1, $2, $3, $4, $5, $6, $7)",
-            refresh_token_id.as_bytes(), user.id.as_bytes(), hashed_refresh_token, <Option<DateTime<Utc>>>::None, Utc::now(), user_agent, remote_address,
-        )
-        .execute(db.0)
+    let attempt = LoginAttempt {
+        identifier: "",
+        method: LoginMethod::Passkey,
+        remote_address: &remote_ip,
+        user_agent: user_agent.as_deref(),
+    };
+    forget_login_attempt(db.0, config.0, &attempt)
         .await
         .unwrap();
 
-        Ok(tokens)
-    })).await;
+    let client = RefreshTokenClient {
+        user_agent: user_agent.as_deref(),
+        remote_address: &remote_address,
+    };
+    let tokens = issue_user_tokens(db.0, config.0, user_id, &client)
+        .await
+        .unwrap();
 
-    Ok(Json(tokens?))
+    Ok(Json(tokens))
+}
+
+#[derive(Serialize)]
+struct OidcLoginInfo {
+    provider_name: String,
+}
+
+/// Tells the frontend whether single sign-on is available, 404s if it isn't configured
+#[handler]
+pub async fn get_oidc_login(config: Data<&Config>) -> poem::Result<Json<OidcLoginInfo>> {
+    let Some(oidc_config) = &config.oidc else {
+        return Err(NotFoundError.into());
+    };
+
+    Ok(Json(OidcLoginInfo {
+        provider_name: oidc_config.provider_name.clone(),
+    }))
+}
+
+#[derive(Serialize)]
+struct OidcLoginStart {
+    /// The frontend sends the user here, the provider then redirects them back to the configured redirect URL
+    authorization_url: String,
+}
+
+#[handler]
+pub async fn start_oidc(
+    db: Data<&sqlx::Pool<sqlx::Postgres>>,
+    config: Data<&Config>,
+) -> poem::Result<Json<OidcLoginStart>> {
+    let Some(oidc_config) = &config.oidc else {
+        return Err(NotFoundError.into());
+    };
+
+    let authorization_url = start_oidc_login(db.0, oidc_config).await?;
+
+    Ok(Json(OidcLoginStart { authorization_url }))
+}
+
+#[derive(Deserialize)]
+struct OidcLoginFinishData {
+    pub state: String,
+    pub code: String,
+}
+
+/// Completes a single sign-on login with the query parameters the provider redirected back with
+#[handler]
+pub async fn finish_oidc(
+    db: Data<&sqlx::Pool<sqlx::Postgres>>,
+    config: Data<&Config>,
+    data: Json<OidcLoginFinishData>,
+    headers: &HeaderMap,
+    remote_address: &RemoteAddr,
+) -> poem::Result<Json<user_security::UserTokens>> {
+    let Some(oidc_config) = &config.oidc else {
+        return Err(NotFoundError.into());
+    };
+
+    let user_agent = headers.typed_get::<UserAgent>().map(|ua| ua.to_string());
+    let remote_address = remote_address.to_string();
+
+    let user_id = match finish_oidc_login(db.0, oidc_config, &data.state, &data.code).await {
+        Ok(claims) => find_or_link_user(db.0, oidc_config, &claims).await,
+        Err(error) => Err(error),
+    };
+    let user_id = user_id.inspect_err(|error| println!("OIDC login failed: {error}"))?;
+
+    let client = RefreshTokenClient {
+        user_agent: user_agent.as_deref(),
+        remote_address: &remote_address,
+    };
+    let tokens = issue_user_tokens(db.0, config.0, user_id, &client)
+        .await
+        .unwrap();
+
+    Ok(Json(tokens))
+}
+
+/// Tells the frontend whether logging in with a directory account is available, 404s if it isn't configured
+#[handler]
+pub async fn get_ldap_login(config: Data<&Config>) -> poem::Result<StatusCode> {
+    match config.ldap {
+        Some(_) => Ok(StatusCode::NO_CONTENT),
+        None => Err(NotFoundError.into()),
+    }
+}
+
+#[derive(Deserialize)]
+struct LdapLoginData {
+    /// Matched against the directory with the configured user filter, usually the uid or email of the user
+    pub username: String,
+    pub password: String,
+}
+
+/// Logs in with a directory account, creating the user on their first login
+#[handler]
+pub async fn login_ldap(
+    db: Data<&sqlx::Pool<sqlx::Postgres>>,
+    config: Data<&Config>,
+    data: Json<LdapLoginData>,
+    headers: &HeaderMap,
+    remote_address: &RemoteAddr,
+) -> poem::Result<Json<LoginResponse>> {
+    let Some(ldap_config) = &config.ldap else {
+        return Err(NotFoundError.into());
+    };
+
+    let user_agent = headers.typed_get::<UserAgent>().map(|ua| ua.to_string());
+    let remote_ip = remote_ip(remote_address);
+    let remote_address = remote_address.to_string();
+
+    let attempt = LoginAttempt {
+        identifier: &data.username,
+        method: LoginMethod::Ldap,
+        remote_address: &remote_ip,
+        user_agent: user_agent.as_deref(),
+    };
+
+    let response: Result<LoginResponse, Error> =
+        ensure_execution_time(Duration::from_millis(1000), || {
+            Box::pin(async {
+                start_login_attempt(db.0, config.0, &attempt).await?;
+
+                let ldap_user =
+                    authenticate_ldap_user(ldap_config, data.username.trim(), &data.password)
+                        .await
+                        .inspect_err(|error| println!("LDAP login failed: {error}"));
+
+                // Only wrong credentials are counted, not the directory server being unreachable
+                if let Err(LdapLoginError::InvalidCredentials(dn)) = &ldap_user {
+                    let user_id = match dn {
+                        Some(dn) => find_ldap_user_id(db.0, dn).await.unwrap(),
+                        None => None,
+                    };
+                    record_failed_login(db.0, &attempt, user_id).await.unwrap();
+                } else {
+                    forget_login_attempt(db.0, config.0, &attempt)
+                        .await
+                        .unwrap();
+                }
+                let ldap_user = ldap_user?;
+                let user_id = sync_ldap_user(db.0, ldap_config, &ldap_user).await?;
+
+                let client = RefreshTokenClient {
+                    user_agent: user_agent.as_deref(),
+                    remote_address: &remote_address,
+                };
+                let response = complete_password_login(db.0, config.0, user_id, &client).await;
+
+                if matches!(response, LoginResponse::Tokens(_)) {
+                    record_successful_login(db.0, &attempt).await.unwrap();
+                }
+
+                Ok(response)
+            })
+        })
+        .await;
+
+    Ok(Json(response?))
 }
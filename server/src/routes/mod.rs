@@ -1,8 +1,15 @@
-use poem::{get, post, Route};
+use poem::{delete, get, post, Route};
 
+mod index_jobs;
+mod invites;
 mod login;
+mod passwords;
+mod search;
+mod sessions;
 mod tokens;
+mod totp;
 mod vaults;
+mod webauthn;
 
 pub fn setup_routes() -> Route {
     Route::new()
@@ -10,15 +17,90 @@ pub fn setup_routes() -> Route {
             "/login/email_and_password/",
             post(login::login_email_and_password),
         )
+        .at("/login/totp/", post(login::login_totp))
+        .at("/login/webauthn/start/", post(login::start_webauthn_login))
+        .at(
+            "/login/webauthn/finish/",
+            post(login::finish_webauthn_login),
+        )
+        .at(
+            "/login/ldap/",
+            get(login::get_ldap_login).post(login::login_ldap),
+        )
+        .at("/login/oidc/", get(login::get_oidc_login))
+        .at("/login/oidc/start/", post(login::start_oidc))
+        .at("/login/oidc/finish/", post(login::finish_oidc))
+        .at("/password_reset/", post(passwords::request_password_reset))
+        .at("/password_reset/confirm/", post(passwords::reset_password))
+        .at("/signup/", post(invites::signup))
         .at("/tokens/refresh/", post(tokens::refresh))
+        .at("/logout/", post(tokens::logout))
+        .at("/sessions/", get(sessions::list_sessions))
+        .at(
+            "/sessions/revoke_others/",
+            post(sessions::revoke_other_sessions),
+        )
+        .at("/sessions/:session_id/", delete(sessions::revoke_session))
+        .at("/account/password/", post(passwords::change_password))
+        .at(
+            "/account/totp/",
+            get(totp::get_totp_status).post(totp::start_totp_enrollment),
+        )
+        .at(
+            "/account/totp/confirm/",
+            post(totp::confirm_totp_enrollment),
+        )
+        .at("/account/totp/disable/", post(totp::disable_totp))
+        .at(
+            "/account/totp/recovery_codes/",
+            post(totp::regenerate_totp_recovery_codes),
+        )
+        .at(
+            "/account/webauthn/credentials/",
+            get(webauthn::list_webauthn_credentials),
+        )
+        .at(
+            "/account/webauthn/credentials/:credential_id/",
+            delete(webauthn::delete_webauthn_credential),
+        )
+        .at(
+            "/account/webauthn/register/start/",
+            post(webauthn::start_webauthn_registration),
+        )
+        .at(
+            "/account/webauthn/register/finish/",
+            post(webauthn::finish_webauthn_registration),
+        )
+        .at(
+            "/invites/",
+            get(invites::list_invites).post(invites::create_user_invite),
+        )
+        .at("/invites/:invite_id/", delete(invites::delete_user_invite))
+        .at("/search/", get(search::search_contents))
         .at("/vaults/", get(vaults::list_vaults))
         .at("/vaults/:vault_id/files/", get(vaults::list_vault_files))
+        .at(
+            "/vaults/:vault_id/files/search/",
+            get(search::search_vault_files),
+        )
         .at(
             "/vaults/:vault_id/files/:file_id/",
             get(vaults::download_vault_file),
         )
+        .at(
+            "/vaults/:vault_id/files/:file_id/details/",
+            get(vaults::get_vault_file_details),
+        )
         .at(
             "/vaults/:vault_id/files/:file_id/access_code/",
             get(vaults::get_vault_file_access_code),
         )
+        .at(
+            "/vaults/:vault_id/index_jobs/",
+            get(index_jobs::list_vault_index_jobs).post(index_jobs::start_vault_index_job),
+        )
+        .at(
+            "/vaults/:vault_id/index_jobs/:job_id/",
+            get(index_jobs::get_vault_index_job),
+        )
 }
@@ -0,0 +1,474 @@
+use std::time::Duration;
+
+use poem::{
+    handler,
+    http::{HeaderMap, StatusCode},
+    web::{
+        headers::{HeaderMapExt, UserAgent},
+        Data, Json, RemoteAddr,
+    },
+};
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::{
+    config::Config,
+    logic::{
+        email::{send_email_in_background, Email},
+        login_throttling::{
+            forget_login_attempt, record_failed_login, remote_ip, start_login_attempt,
+            LoginAttempt, LoginMethod,
+        },
+        passwords::{
+            create_password_reset_token, load_password_identity, set_user_password,
+            take_password_reset_token, validate_new_password, PASSWORD_IDENTITY_PROVIDER,
+        },
+        refresh_tokens::revoke_user_sessions,
+        security_events::{record_security_event, SecurityEventKind},
+        totp::delete_login_challenges,
+    },
+    utils::{
+        response_errors::ForbiddenError, security::ensure_execution_time,
+        user_security::AuthenticatedUser, xid::Xid,
+    },
+};
+
+#[derive(Deserialize)]
+struct ChangePasswordData {
+    pub current_password: String,
+    pub new_password: String,
+}
+
+/// Changes the password of the logged in user, logging out their other sessions. Wrong current passwords count as
+/// failed logins, so a stolen session can't be used to guess the password.
+#[handler]
+pub async fn change_password(
+    db: Data<&sqlx::Pool<sqlx::Postgres>>,
+    config: Data<&Config>,
+    user: AuthenticatedUser,
+    data: Json<ChangePasswordData>,
+    headers: &HeaderMap,
+    remote_address: &RemoteAddr,
+) -> poem::Result<StatusCode> {
+    validate_new_password(&data.new_password)?;
+
+    let mut tx = db.begin().await.unwrap();
+
+    let Some(identity) = load_password_identity(&mut *tx, user.id).await.unwrap() else {
+        return Err(poem::Error::from_string(
+            "This account doesn't log in with a password",
+            StatusCode::CONFLICT,
+        ));
+    };
+
+    let user_agent = headers.typed_get::<UserAgent>().map(|ua| ua.to_string());
+    let remote_ip = remote_ip(remote_address);
+    let email = sqlx::query_scalar!("SELECT email FROM users WHERE id = $1", user.id.as_bytes())
+        .fetch_one(&mut *tx)
+        .await
+        .unwrap();
+    let attempt = LoginAttempt {
+        identifier: &email,
+        method: LoginMethod::PasswordChange,
+        remote_address: &remote_ip,
+        user_agent: user_agent.as_deref(),
+    };
+    start_login_attempt(db.0, config.0, &attempt).await?;
+
+    if !identity.verify(&data.current_password) {
+        record_failed_login(db.0, &attempt, Some(user.id))
+            .await
+            .unwrap();
+        return Err(ForbiddenError.into());
+    }
+    forget_login_attempt(db.0, config.0, &attempt)
+        .await
+        .unwrap();
+
+    set_user_password(&mut *tx, user.id, &data.new_password)
+        .await
+        .unwrap();
+    delete_login_challenges(&mut *tx, user.id).await.unwrap();
+    let revoked = revoke_user_sessions(&mut *tx, user.id, user.session_id)
+        .await
+        .unwrap();
+
+    record_security_event(
+        &mut *tx,
+        user.id,
+        SecurityEventKind::PasswordChanged,
+        user_agent.as_deref(),
+        Some(&remote_address.to_string()),
+        json!({ "revoked_refresh_tokens": revoked }),
+    )
+    .await
+    .unwrap();
+
+    tx.commit().await.unwrap();
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Deserialize)]
+struct PasswordResetRequestData {
+    pub email: String,
+}
+
+/// Emails a password reset link to the user with the given email address. Only users who log in with a password can
+/// reset it, so one can't be added to accounts which are meant to log in through SSO or LDAP. The response is the same
+/// whether or not an email was sent, so it can't be used to find out who has an account.
+#[handler]
+pub async fn request_password_reset(
+    db: Data<&sqlx::Pool<sqlx::Postgres>>,
+    config: Data<&Config>,
+    data: Json<PasswordResetRequestData>,
+) -> poem::Result<StatusCode> {
+    ensure_execution_time(Duration::from_millis(1000), || Box::pin(async {
+        let user = sqlx::query!(
+            "SELECT id, name, email FROM users WHERE LOWER(email) = LOWER($1) \
+            AND EXISTS(SELECT FROM user_identities WHERE user_id = users.id AND provider = $2)",
+            data.email,
+            PASSWORD_IDENTITY_PROVIDER,
+        )
+        .fetch_optional(db.0)
+        .await
+        .unwrap();
+
+        let Some(user) = user else {
+            return;
+        };
+
+        let token = create_password_reset_token(db.0, &config, Xid::from(user.id))
+            .await
+            .unwrap();
+
+        let link = format!(
+            "{}/login/reset-password?token={token}",
+            config.frontend_url.trim_end_matches('/')
+        );
+        let body = format!(
+            "Hi {},\n\n\
+            Someone asked to reset the password of your Floppy account. If it was you, you can pick a new password here:\n\n\
+            {link}\n\n\
+            The link can only be used once and expires in {} minutes. If you didn't ask for this, you can ignore this email.\n",
+            user.name,
+            config.password_reset_token_lifetime_seconds / 60,
+        );
+
+        send_email_in_background(
+            &config,
+            Email {
+                to: user.email,
+                subject: "Reset your Floppy password".to_string(),
+                body,
+            },
+        );
+    }))
+    .await;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Deserialize)]
+struct PasswordResetData {
+    pub token: String,
+    pub new_password: String,
+}
+
+/// Sets a new password with the token from a password reset email, logging out all of the user's sessions. Following
+/// the link also proves the user owns their email address.
+#[handler]
+pub async fn reset_password(
+    db: Data<&sqlx::Pool<sqlx::Postgres>>,
+    data: Json<PasswordResetData>,
+    headers: &HeaderMap,
+    remote_address: &RemoteAddr,
+) -> poem::Result<StatusCode> {
+    // Checked before the token is used up, so the user can try again with a better password
+    validate_new_password(&data.new_password)?;
+
+    let mut tx = db.begin().await.unwrap();
+
+    let Some(user_id) = take_password_reset_token(&mut tx, &data.token)
+        .await
+        .unwrap()
+    else {
+        return Err(poem::Error::from_string(
+            "This password reset link has expired or was already used",
+            StatusCode::FORBIDDEN,
+        ));
+    };
+
+    set_user_password(&mut *tx, user_id, &data.new_password)
+        .await
+        .unwrap();
+    sqlx::query!(
+        "UPDATE users SET email_verified_at = NOW() WHERE id = $1 AND email_verified_at IS NULL",
+        user_id.as_bytes(),
+    )
+    .execute(&mut *tx)
+    .await
+    .unwrap();
+    delete_login_challenges(&mut *tx, user_id).await.unwrap();
+    let revoked = revoke_user_sessions(&mut *tx, user_id, None).await.unwrap();
+
+    let user_agent = headers.typed_get::<UserAgent>().map(|ua| ua.to_string());
+    record_security_event(
+        &mut *tx,
+        user_id,
+        SecurityEventKind::PasswordReset,
+        user_agent.as_deref(),
+        Some(&remote_address.to_string()),
+        json!({ "revoked_refresh_tokens": revoked }),
+    )
+    .await
+    .unwrap();
+
+    tx.commit().await.unwrap();
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use poem::{
+        listener::{Acceptor, Listener},
+        EndpointExt, Server,
+    };
+    use serde_json::Value;
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+        net::{TcpListener, TcpStream},
+    };
+
+    use super::*;
+    use crate::{
+        config::{SmtpConfig, SmtpSecurity},
+        routes::setup_routes,
+    };
+
+    const EMAIL: &str = "alice@example.org";
+    const OLD_PASSWORD: &str = "old password";
+    const NEW_PASSWORD: &str = "new password";
+
+    /// An SMTP server on a local port which keeps the messages it's sent instead of delivering them
+    async fn start_mail_catcher() -> (Arc<Mutex<Vec<String>>>, SmtpConfig) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let messages = Arc::<Mutex<Vec<String>>>::default();
+        let caught = messages.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(catch_mail(stream, caught.clone()));
+            }
+        });
+
+        let config = SmtpConfig {
+            host: "127.0.0.1".to_string(),
+            port,
+            security: SmtpSecurity::None,
+            username: None,
+            password: None,
+            from: "Floppy <floppy@example.org>".to_string(),
+        };
+
+        (messages, config)
+    }
+
+    async fn catch_mail(stream: TcpStream, messages: Arc<Mutex<Vec<String>>>) {
+        let (reader, mut writer) = stream.into_split();
+        let mut lines = BufReader::new(reader).lines();
+        writer.write_all(b"220 localhost\r\n").await.unwrap();
+
+        while let Ok(Some(line)) = lines.next_line().await {
+            let command = line.get(..4).unwrap_or(&line).to_uppercase();
+            let reply: &[u8] = match command.as_str() {
+                "DATA" => {
+                    writer.write_all(b"354 End with .\r\n").await.unwrap();
+
+                    let mut message = vec![];
+                    while let Ok(Some(line)) = lines.next_line().await {
+                        if line == "." {
+                            break;
+                        }
+                        message.push(line);
+                    }
+                    let message = decode_quoted_printable(&message.join("\n"));
+                    messages.lock().unwrap().push(message);
+
+                    b"250 OK\r\n"
+                }
+                "QUIT" => {
+                    let _ = writer.write_all(b"221 Bye\r\n").await;
+                    return;
+                }
+                _ => b"250 OK\r\n",
+            };
+            writer.write_all(reply).await.unwrap();
+        }
+    }
+
+    /// Decodes the body of a message sent as quoted-printable, which lettre uses for bodies with long lines
+    fn decode_quoted_printable(message: &str) -> String {
+        let (headers, body) = message.split_once("\n\n").unwrap();
+        let body = body.replace("=\n", "");
+        let mut decoded = vec![];
+        let mut bytes = body.bytes();
+
+        while let Some(byte) = bytes.next() {
+            if byte != b'=' {
+                decoded.push(byte);
+                continue;
+            }
+            let hex = [bytes.next().unwrap(), bytes.next().unwrap()];
+            decoded.push(u8::from_str_radix(std::str::from_utf8(&hex).unwrap(), 16).unwrap());
+        }
+
+        format!("{headers}\n\n{}", String::from_utf8(decoded).unwrap())
+    }
+
+    /// Serves the API on a local port, returning its URL
+    async fn start_api(db: &sqlx::Pool<sqlx::Postgres>, smtp: SmtpConfig) -> String {
+        let config = Config {
+            database_url: String::new(),
+            database_pool_size: 1,
+            server_host_address: String::new(),
+            jwt_signing_key: "test signing key".to_string(),
+            frontend_url: "http://localhost:3000/".to_string(),
+            vault_reconcile_interval_seconds: 60 * 60,
+            job_workers: 1,
+            refresh_token_lifetime_seconds: 60 * 60,
+            refresh_token_idle_timeout_seconds: 60 * 60,
+            oidc: None,
+            ldap: None,
+            smtp: Some(smtp),
+            password_reset_token_lifetime_seconds: 60 * 60,
+            invite_lifetime_seconds: 60 * 60,
+            login_max_account_attempts: 5,
+            login_max_ip_attempts: 20,
+            login_lockout_seconds: 15 * 60,
+        };
+
+        let acceptor = poem::listener::TcpListener::bind("127.0.0.1:0")
+            .into_acceptor()
+            .await
+            .unwrap();
+        let address = *acceptor.local_addr()[0].as_socket_addr().unwrap();
+        let app = setup_routes().data(db.clone()).data(config);
+        tokio::spawn(Server::new_with_acceptor(acceptor).run(app));
+
+        format!("http://{address}")
+    }
+
+    async fn create_user(db: &sqlx::Pool<sqlx::Postgres>) -> Xid {
+        let id = Xid::new();
+
+        sqlx::query!(
+            "INSERT INTO users (id, created_at, name, email, email_verified_at) VALUES ($1, NOW(), 'Alice', $2, NOW())",
+            id.as_bytes(),
+            EMAIL,
+        )
+        .execute(db)
+        .await
+        .unwrap();
+        set_user_password(db, id, OLD_PASSWORD).await.unwrap();
+
+        id
+    }
+
+    async fn post(api_url: &str, path: &str, body: Value) -> reqwest::Response {
+        reqwest::Client::new()
+            .post(format!("{api_url}{path}"))
+            .json(&body)
+            .send()
+            .await
+            .unwrap()
+    }
+
+    /// Asks for a reset link & returns the token from the email it was sent in
+    async fn request_reset_token(api_url: &str, messages: &Mutex<Vec<String>>) -> String {
+        let response = post(api_url, "/password_reset/", json!({ "email": EMAIL })).await;
+        assert_eq!(response.status(), reqwest::StatusCode::NO_CONTENT);
+
+        // The email is sent in the background
+        for _ in 0..100 {
+            let message = messages.lock().unwrap().pop();
+            if let Some(message) = message {
+                assert!(message.contains(&format!("To: {EMAIL}")));
+                let (_, link) = message
+                    .split_once("http://localhost:3000/login/reset-password?token=")
+                    .unwrap();
+                return link
+                    .chars()
+                    .take_while(char::is_ascii_alphanumeric)
+                    .collect();
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+
+        panic!("No password reset email was sent");
+    }
+
+    async fn log_in(api_url: &str, password: &str) -> reqwest::Response {
+        let body = json!({ "email": EMAIL, "password": password });
+        post(api_url, "/login/email_and_password/", body).await
+    }
+
+    #[sqlx::test]
+    async fn resets_the_password_once_with_the_emailed_token(db: sqlx::PgPool) {
+        let (messages, smtp) = start_mail_catcher().await;
+        let api_url = start_api(&db, smtp).await;
+        create_user(&db).await;
+
+        let session = log_in(&api_url, OLD_PASSWORD)
+            .await
+            .json::<Value>()
+            .await
+            .unwrap();
+        let token = request_reset_token(&api_url, &messages).await;
+
+        let body = json!({ "token": token, "new_password": NEW_PASSWORD });
+        let response = post(&api_url, "/password_reset/confirm/", body).await;
+        assert_eq!(response.status(), reqwest::StatusCode::NO_CONTENT);
+
+        let body = json!({ "token": token, "new_password": "another password" });
+        let response = post(&api_url, "/password_reset/confirm/", body).await;
+        assert_eq!(response.status(), reqwest::StatusCode::FORBIDDEN);
+
+        // Sessions from before the reset are logged out
+        let body = json!({ "refresh_token": session["refresh_token"] });
+        let response = post(&api_url, "/tokens/refresh/", body).await;
+        assert_eq!(response.status(), reqwest::StatusCode::FORBIDDEN);
+
+        let response = log_in(&api_url, OLD_PASSWORD).await;
+        assert_eq!(response.status(), reqwest::StatusCode::FORBIDDEN);
+        let response = log_in(&api_url, NEW_PASSWORD).await;
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+    }
+
+    #[sqlx::test]
+    async fn rejects_an_expired_token(db: sqlx::PgPool) {
+        let (messages, smtp) = start_mail_catcher().await;
+        let api_url = start_api(&db, smtp).await;
+        let user_id = create_user(&db).await;
+
+        let token = request_reset_token(&api_url, &messages).await;
+        sqlx::query!(
+            "UPDATE user_password_reset_tokens SET expires_at = NOW() - INTERVAL '1 second' WHERE user_id = $1",
+            user_id.as_bytes(),
+        )
+        .execute(&db)
+        .await
+        .unwrap();
+
+        let body = json!({ "token": token, "new_password": NEW_PASSWORD });
+        let response = post(&api_url, "/password_reset/confirm/", body).await;
+        assert_eq!(response.status(), reqwest::StatusCode::FORBIDDEN);
+
+        let identity = load_password_identity(&db, user_id).await.unwrap().unwrap();
+        assert!(identity.verify(OLD_PASSWORD));
+    }
+}
@@ -0,0 +1,355 @@
+use std::{collections::HashMap, path::Path as FsPath};
+
+use crate::{
+    logic::indexing::local_folder::LocalFolderVaultData,
+    models::vaults::{Vault, VaultFile},
+    utils::{user_security::AuthenticatedUser, xid::Xid},
+};
+use chrono::{DateTime, Utc};
+use poem::{
+    error::NotFoundError,
+    handler,
+    web::{Data, Json, Path, Query},
+};
+use serde::{Deserialize, Serialize};
+
+const MAX_SEARCH_RESULTS: i64 = 50;
+const MAX_VAULT_SEARCH_RESULTS: i64 = 200;
+
+// Control characters which never show up in extracted text mark the highlighted words in snippets, so the rest of the
+// snippet can be escaped before they're turned into tags
+const HIGHLIGHT_START: char = '\u{2}';
+const HIGHLIGHT_STOP: char = '\u{3}';
+
+/// How similar a search has to be to a word in a file name to match it, low enough to forgive the odd typo
+const FUZZY_NAME_MATCH_THRESHOLD: &str = "0.3";
+
+/// Sets the threshold used by `<%`, which unlike `word_similarity()` can use the trigram index, for the rest of the
+/// transaction
+pub async fn set_fuzzy_name_match_threshold(
+    db: &mut sqlx::PgConnection,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "SELECT set_config('pg_trgm.word_similarity_threshold', $1, TRUE)",
+        FUZZY_NAME_MATCH_THRESHOLD,
+    )
+    .fetch_one(db)
+    .await?;
+
+    Ok(())
+}
+
+pub fn escape_like_pattern(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
+}
+
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+fn highlight_snippet(snippet: &str) -> String {
+    escape_html(snippet)
+        .replace(HIGHLIGHT_START, "<mark>")
+        .replace(HIGHLIGHT_STOP, "</mark>")
+}
+
+#[derive(Deserialize)]
+struct SearchContentsQuery {
+    /// Web search syntax, e.g. `"exact phrase" -excluded or alternative`
+    q: String,
+    vault_id: Option<Xid>,
+    #[serde(default)]
+    offset: i64,
+    limit: Option<i64>,
+}
+
+#[derive(Serialize)]
+struct ContentSearchResult {
+    #[serde(flatten)]
+    file: VaultFile,
+    rank: f32,
+    /// HTML with the matching words wrapped in `<mark>` tags
+    snippet: String,
+}
+
+/// Searches the text contents of the files in every vault the user has access to, best matches first
+#[handler]
+pub async fn search_contents(
+    db: Data<&sqlx::Pool<sqlx::Postgres>>,
+    user: AuthenticatedUser,
+    query: Query<SearchContentsQuery>,
+) -> poem::Result<Json<Vec<ContentSearchResult>>> {
+    if query.q.trim().is_empty() {
+        return Ok(Json(vec![]));
+    }
+
+    let limit = query
+        .limit
+        .unwrap_or(MAX_SEARCH_RESULTS)
+        .clamp(1, MAX_SEARCH_RESULTS);
+
+    // Snippets are slow to generate, so they're only made for the page of results being returned
+    let matches = sqlx::query!(
+        "SELECT \
+            matches.vault_file_id, matches.rank AS \"rank!\", \
+            ts_headline('english', vault_file_contents.content, matches.query, \
+                'MaxFragments=2, MaxWords=20, MinWords=5, StartSel=' || chr(2) || ', StopSel=' || chr(3)) AS \"snippet!\" \
+        FROM ( \
+            SELECT vault_file_contents.vault_file_id, query, ts_rank_cd(vault_file_contents.content_tsv, query) AS rank \
+            FROM vault_file_contents \
+                INNER JOIN vault_files ON vault_files.id = vault_file_contents.vault_file_id, \
+                websearch_to_tsquery('english', $2) query \
+            WHERE \
+                vault_file_contents.content_tsv @@ query \
+                AND EXISTS(SELECT FROM user_vault_links WHERE user_vault_links.user_id = $1 AND user_vault_links.vault_id = vault_files.vault_id) \
+                AND ($3::BYTEA IS NULL OR vault_files.vault_id = $3::BYTEA) \
+            ORDER BY rank DESC, vault_file_contents.vault_file_id \
+            LIMIT $4 OFFSET $5 \
+        ) matches \
+            INNER JOIN vault_file_contents ON vault_file_contents.vault_file_id = matches.vault_file_id \
+        ORDER BY matches.rank DESC, matches.vault_file_id",
+        user.id.as_bytes(),
+        query.q.trim(),
+        query.vault_id.as_ref().map(|id| id.as_bytes() as &[u8]),
+        limit,
+        query.offset.max(0),
+    )
+    .fetch_all(db.0)
+    .await
+    .unwrap();
+
+    let mut files = sqlx::query_as!(
+        VaultFile,
+        "SELECT \
+            id, vault_id, path_id, name, file_type, parent_id, created_at, modified_at, size, mode, is_hidden, symlink_target, content_sha256, mime_type, \
+            subtree_size, subtree_file_count, subtree_folder_count \
+        FROM vault_files WHERE id = ANY($1)",
+        &matches.iter().map(|m| m.vault_file_id.clone()).collect::<Vec<_>>(),
+    )
+    .fetch_all(db.0)
+    .await
+    .unwrap()
+    .into_iter()
+    .map(|file| (file.id.as_bytes().to_vec(), file))
+    .collect::<HashMap<_, _>>();
+
+    let results = matches
+        .into_iter()
+        .filter_map(|m| {
+            Some(ContentSearchResult {
+                file: files.remove(&m.vault_file_id)?,
+                rank: m.rank,
+                snippet: highlight_snippet(&m.snippet),
+            })
+        })
+        .collect();
+
+    Ok(Json(results))
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+enum SearchVaultFilesSort {
+    /// Closest name matches first
+    Relevance,
+    Name,
+    NameDesc,
+    Size,
+    SizeDesc,
+    ModifiedAt,
+    ModifiedAtDesc,
+    CreatedAt,
+    CreatedAtDesc,
+}
+
+impl SearchVaultFilesSort {
+    fn as_str(&self) -> &'static str {
+        match self {
+            SearchVaultFilesSort::Relevance => "relevance",
+            SearchVaultFilesSort::Name => "name",
+            SearchVaultFilesSort::NameDesc => "name_desc",
+            SearchVaultFilesSort::Size => "size",
+            SearchVaultFilesSort::SizeDesc => "size_desc",
+            SearchVaultFilesSort::ModifiedAt => "modified_at",
+            SearchVaultFilesSort::ModifiedAtDesc => "modified_at_desc",
+            SearchVaultFilesSort::CreatedAt => "created_at",
+            SearchVaultFilesSort::CreatedAtDesc => "created_at_desc",
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct SearchVaultFilesQuery {
+    /// Matched against file names ignoring case & accents, forgiving typos
+    name: Option<String>,
+    /// Comma separated list of extensions, without the leading dot
+    extension: Option<String>,
+    /// Either an exact MIME type or a wildcard subtype such as `image/*`
+    mime_type: Option<String>,
+    file_type: Option<String>,
+    /// Sizes of folders are the total size of everything inside of them
+    min_size: Option<i64>,
+    max_size: Option<i64>,
+    created_after: Option<DateTime<Utc>>,
+    created_before: Option<DateTime<Utc>>,
+    modified_after: Option<DateTime<Utc>>,
+    modified_before: Option<DateTime<Utc>>,
+    /// Only searches inside of this folder, relative to the root of the vault
+    path_prefix: Option<String>,
+    #[serde(default)]
+    show_hidden: bool,
+    /// Defaults to relevance when searching by name, otherwise to name
+    sort: Option<SearchVaultFilesSort>,
+    #[serde(default)]
+    offset: i64,
+    limit: Option<i64>,
+}
+
+#[derive(Serialize)]
+struct VaultFileSearchResult {
+    #[serde(flatten)]
+    file: VaultFile,
+    /// Path of the folder containing the file relative to the root of the vault, empty for the root itself
+    folder_path: String,
+}
+
+fn non_empty(value: &Option<String>) -> Option<&str> {
+    value.as_deref().map(str::trim).filter(|v| !v.is_empty())
+}
+
+/// Searches every folder of a vault at once
+#[handler]
+pub async fn search_vault_files(
+    db: Data<&sqlx::Pool<sqlx::Postgres>>,
+    user: AuthenticatedUser,
+    Path((vault_id,)): Path<(Xid,)>,
+    query: Query<SearchVaultFilesQuery>,
+) -> poem::Result<Json<Vec<VaultFileSearchResult>>> {
+    let vault = sqlx::query_as!(
+        Vault,
+        "SELECT id, name, provider, data FROM vaults WHERE EXISTS(SELECT FROM user_vault_links WHERE user_vault_links.user_id = $1 AND user_vault_links.vault_id = vaults.id) AND id = $2",
+        user.id.as_bytes(), vault_id.as_bytes(),
+    )
+    .fetch_optional(db.0)
+    .await
+    .unwrap()
+    .ok_or(NotFoundError)?;
+
+    // Like downloads, a vault whose configuration can't be read has nothing to offer
+    let vault_root = LocalFolderVaultData::from_vault(&vault)
+        .map_err(|_| NotFoundError)?
+        .path;
+
+    let extensions = non_empty(&query.extension).map(|extensions| {
+        extensions
+            .split(',')
+            .map(|extension| extension.trim().trim_start_matches('.').to_lowercase())
+            .filter(|extension| !extension.is_empty())
+            .collect::<Vec<_>>()
+    });
+    let folder_path_id = non_empty(&query.path_prefix).map(|prefix| {
+        vault_root
+            .join(prefix.trim_matches('/'))
+            .to_string_lossy()
+            .to_string()
+    });
+    let limit = query
+        .limit
+        .unwrap_or(MAX_SEARCH_RESULTS)
+        .clamp(1, MAX_VAULT_SEARCH_RESULTS);
+    let name = non_empty(&query.name);
+    let sort = query.sort.unwrap_or(match name {
+        Some(_) => SearchVaultFilesSort::Relevance,
+        None => SearchVaultFilesSort::Name,
+    });
+
+    let mut db = db.0.begin().await.unwrap();
+    set_fuzzy_name_match_threshold(&mut db).await.unwrap();
+
+    let files = sqlx::query_as!(
+        VaultFile,
+        "SELECT \
+            id, vault_id, path_id, name, file_type, parent_id, created_at, modified_at, size, mode, is_hidden, symlink_target, content_sha256, mime_type, \
+            subtree_size, subtree_file_count, subtree_folder_count \
+        FROM vault_files \
+        WHERE \
+            vault_id = $1 \
+            AND ($2::TEXT IS NULL OR search_name LIKE '%' || LOWER(immutable_unaccent($17::TEXT)) || '%' OR LOWER(immutable_unaccent($2::TEXT)) <% search_name) \
+            AND ($3::TEXT[] IS NULL OR (file_type <> 'folder' AND LOWER(SUBSTRING(name FROM '\\.([^.]+)$')) = ANY($3::TEXT[]))) \
+            AND ($4::TEXT IS NULL OR mime_type = $4::TEXT OR (RIGHT($4::TEXT, 2) = '/*' AND mime_type LIKE LEFT($4::TEXT, -1) || '%')) \
+            AND ($5::TEXT IS NULL OR file_type = $5::TEXT) \
+            AND ($6::BIGINT IS NULL OR CASE WHEN file_type = 'folder' THEN subtree_size ELSE size END >= $6::BIGINT) \
+            AND ($7::BIGINT IS NULL OR CASE WHEN file_type = 'folder' THEN subtree_size ELSE size END <= $7::BIGINT) \
+            AND ($8::TIMESTAMPTZ IS NULL OR created_at >= $8::TIMESTAMPTZ) \
+            AND ($9::TIMESTAMPTZ IS NULL OR created_at < $9::TIMESTAMPTZ) \
+            AND ($10::TIMESTAMPTZ IS NULL OR modified_at >= $10::TIMESTAMPTZ) \
+            AND ($11::TIMESTAMPTZ IS NULL OR modified_at < $11::TIMESTAMPTZ) \
+            AND ($12::TEXT IS NULL OR STARTS_WITH(path_id, $12::TEXT || '/')) \
+            AND ($13::BOOLEAN OR NOT is_hidden) \
+        ORDER BY \
+            CASE WHEN $14::TEXT = 'relevance' THEN word_similarity(LOWER(immutable_unaccent($2::TEXT)), search_name) END DESC, \
+            CASE WHEN $14::TEXT = 'name' THEN LOWER(name) END ASC, \
+            CASE WHEN $14::TEXT = 'name_desc' THEN LOWER(name) END DESC, \
+            CASE WHEN $14::TEXT = 'size' THEN CASE WHEN file_type = 'folder' THEN subtree_size ELSE size END END ASC NULLS FIRST, \
+            CASE WHEN $14::TEXT = 'size_desc' THEN CASE WHEN file_type = 'folder' THEN subtree_size ELSE size END END DESC NULLS LAST, \
+            CASE WHEN $14::TEXT = 'modified_at' THEN modified_at END ASC NULLS FIRST, \
+            CASE WHEN $14::TEXT = 'modified_at_desc' THEN modified_at END DESC NULLS LAST, \
+            CASE WHEN $14::TEXT = 'created_at' THEN created_at END ASC NULLS FIRST, \
+            CASE WHEN $14::TEXT = 'created_at_desc' THEN created_at END DESC NULLS LAST, \
+            id ASC \
+        LIMIT $15 OFFSET $16",
+        vault_id.as_bytes(),
+        name,
+        extensions.as_deref(),
+        non_empty(&query.mime_type),
+        non_empty(&query.file_type),
+        query.min_size,
+        query.max_size,
+        query.created_after,
+        query.created_before,
+        query.modified_after,
+        query.modified_before,
+        folder_path_id,
+        query.show_hidden,
+        sort.as_str(),
+        limit,
+        query.offset.max(0),
+        name.map(escape_like_pattern),
+    )
+    .fetch_all(&mut *db)
+    .await
+    .unwrap();
+
+    db.commit().await.unwrap();
+
+    let results = files
+        .into_iter()
+        .map(|file| {
+            let folder_path = FsPath::new(&file.path_id)
+                .parent()
+                .and_then(|parent| parent.strip_prefix(&vault_root).ok())
+                .map(|parent| parent.to_string_lossy().to_string())
+                .unwrap_or_default();
+
+            VaultFileSearchResult { file, folder_path }
+        })
+        .collect();
+
+    Ok(Json(results))
+}
@@ -0,0 +1,115 @@
+use chrono::{DateTime, Utc};
+use poem::{
+    error::NotFoundError,
+    handler,
+    http::StatusCode,
+    web::{Data, Json, Path},
+};
+use serde::Serialize;
+use serde_json::json;
+
+use crate::{
+    config::Config,
+    logic::refresh_tokens::{refresh_token_idle_since, revoke_user_session, revoke_user_sessions},
+    utils::{
+        user_agents::{parse_device_info, DeviceInfo},
+        user_security::AuthenticatedUser,
+        xid::Xid,
+    },
+};
+
+#[derive(Serialize)]
+struct UserSession {
+    id: Xid,
+    /// When the user logged in
+    created_at: DateTime<Utc>,
+    /// When the session was last refreshed, or logged in if it hasn't been since
+    last_used_at: DateTime<Utc>,
+    expires_at: DateTime<Utc>,
+    user_agent: Option<String>,
+    remote_address: String,
+    device: DeviceInfo,
+    /// Whether this is the session the request was made with
+    is_current: bool,
+}
+
+/// Lists the sessions of the user which can still be refreshed, most recently used first
+#[handler]
+pub async fn list_sessions(
+    db: Data<&sqlx::Pool<sqlx::Postgres>>,
+    config: Data<&Config>,
+    user: AuthenticatedUser,
+) -> poem::Result<Json<Vec<UserSession>>> {
+    let sessions = sqlx::query!(
+        "SELECT \
+            family_id AS id, \
+            (SELECT MIN(created_at) FROM user_refresh_tokens family WHERE family.family_id = user_refresh_tokens.family_id) AS \"created_at!\", \
+            COALESCE(last_used_at, created_at) AS \"last_used_at!\", expires_at, user_agent, remote_address \
+        FROM user_refresh_tokens \
+        WHERE \
+            user_id = $1 AND rotated_at IS NULL AND revoked_at IS NULL \
+            AND expires_at > NOW() AND COALESCE(last_used_at, created_at) > $2 \
+        ORDER BY COALESCE(last_used_at, created_at) DESC",
+        user.id.as_bytes(),
+        refresh_token_idle_since(&config),
+    )
+    .fetch_all(db.0)
+    .await
+    .unwrap();
+
+    let sessions = sessions
+        .into_iter()
+        .map(|session| {
+            let id = Xid::from(session.id);
+
+            UserSession {
+                id,
+                created_at: session.created_at,
+                last_used_at: session.last_used_at,
+                expires_at: session.expires_at,
+                device: session
+                    .user_agent
+                    .as_deref()
+                    .map(parse_device_info)
+                    .unwrap_or_default(),
+                user_agent: session.user_agent,
+                remote_address: session.remote_address,
+                is_current: user
+                    .session_id
+                    .is_some_and(|session_id| session_id.as_bytes() == id.as_bytes()),
+            }
+        })
+        .collect();
+
+    Ok(Json(sessions))
+}
+
+/// Revokes one of the user's sessions, access tokens issued for it stay valid until they expire
+#[handler]
+pub async fn revoke_session(
+    db: Data<&sqlx::Pool<sqlx::Postgres>>,
+    user: AuthenticatedUser,
+    Path((session_id,)): Path<(Xid,)>,
+) -> poem::Result<StatusCode> {
+    if !revoke_user_session(db.0, user.id, session_id)
+        .await
+        .unwrap()
+    {
+        return Err(NotFoundError.into());
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Revokes all of the user's sessions except for the one the request was made with
+#[handler]
+pub async fn revoke_other_sessions(
+    db: Data<&sqlx::Pool<sqlx::Postgres>>,
+    user: AuthenticatedUser,
+) -> poem::Result<Json<serde_json::Value>> {
+    let revoked = revoke_user_sessions(db.0, user.id, user.session_id)
+        .await
+        .unwrap();
+
+    Ok(Json(json!({ "revoked": revoked })))
+}
@@ -3,7 +3,7 @@ use std::time::Duration;
 use chrono::Utc;
 use poem::{
     handler,
-    http::HeaderMap,
+    http::{HeaderMap, StatusCode},
     web::{
         headers::{HeaderMapExt, UserAgent},
         Data, Json, RemoteAddr,
@@ -13,19 +13,28 @@ use serde::Deserialize;
 
 use crate::{
     config::Config,
+    logic::refresh_tokens::{
+        refresh_token_idle_since, revoke_reused_refresh_token_family, rotate_refresh_token,
+        RefreshTokenClient,
+    },
     models::users::UserRefreshToken,
     utils::{
         response_errors::ForbiddenError,
-        security::ensure_execution_time,
-        user_security::{self, generate_access_token, hash_refresh_token},
+        security::{ensure_execution_time, hash_secret_token},
+        user_security::{self, generate_tokens},
     },
 };
 
+/// How soon after being rotated a refresh token being presented again isn't considered reuse
+const REFRESH_TOKEN_REUSE_GRACE_PERIOD: Duration = Duration::from_secs(10);
+
 #[derive(Deserialize)]
 struct RefreshTokenData {
     refresh_token: String,
 }
 
+/// Exchanges a refresh token for a new access token and the next refresh token of its family, the presented refresh
+/// token can't be used again
 #[handler]
 pub async fn refresh(
     db: Data<&sqlx::Pool<sqlx::Postgres>>,
@@ -33,19 +42,25 @@ pub async fn refresh(
     data: Json<RefreshTokenData>,
     headers: &HeaderMap,
     remote_address: &RemoteAddr,
-) -> poem::Result<Json<user_security::UserAccessToken>> {
+) -> poem::Result<Json<user_security::UserTokens>> {
     let user_agent = headers.typed_get::<UserAgent>().map(|ua| ua.to_string());
 
     // TODO: Support X-Forwarded-For / CF-Connecting-IP
     let remote_address = remote_address.to_string();
 
-    let access_token: Result<user_security::UserAccessToken, poem::Error> = ensure_execution_time(Duration::from_millis(1000), || {
+    let client = RefreshTokenClient {
+        user_agent: user_agent.as_deref(),
+        remote_address: &remote_address,
+    };
+
+    let tokens: Result<user_security::UserTokens, poem::Error> = ensure_execution_time(Duration::from_millis(1000), || {
         Box::pin(async {
-            let hashed_refresh_token = hash_refresh_token(&data.refresh_token);
+            let hashed_refresh_token = hash_secret_token(&data.refresh_token);
 
             let refresh_token = sqlx::query_as!(
                 UserRefreshToken,
-                "SELECT id, user_id, token_hash, created_at, last_used_at, user_agent, remote_address FROM user_refresh_tokens WHERE token_hash = $1",
+                "SELECT id, family_id, user_id, token_hash, created_at, last_used_at, user_agent, remote_address, expires_at, revoked_at, rotated_at \
+                FROM user_refresh_tokens WHERE token_hash = $1",
                 hashed_refresh_token,
             )
             .fetch_optional(db.0)
@@ -57,18 +72,65 @@ pub async fn refresh(
             }
             let refresh_token = refresh_token.unwrap();
 
-            sqlx::query!(
-                "UPDATE user_refresh_tokens SET last_used_at = $2, user_agent = $3, remote_address = $4 WHERE id = $1",
-                refresh_token.id.as_bytes(), Utc::now(), user_agent, remote_address
-            )
-            .execute(db.0)
-            .await
-            .unwrap();
+            if let Some(rotated_at) = refresh_token.rotated_at {
+                // Clients refreshing concurrently (e.g. from multiple tabs) can present the same token twice in quick
+                // succession, which doesn't mean it was stolen
+                if rotated_at > Utc::now() - REFRESH_TOKEN_REUSE_GRACE_PERIOD {
+                    return Err(ForbiddenError.into());
+                }
+
+                revoke_reused_refresh_token_family(db.0, &refresh_token, &client).await.unwrap();
+                return Err(ForbiddenError.into());
+            }
+
+            // Tokens expire a fixed time after logging in, or earlier if they go unused for too long
+            let is_usable = refresh_token.revoked_at.is_none()
+                && refresh_token.expires_at > Utc::now()
+                && refresh_token.last_used_at.unwrap_or(refresh_token.created_at) > refresh_token_idle_since(&config);
+
+            if !is_usable {
+                return Err(ForbiddenError.into());
+            }
+
+            let tokens = generate_tokens(&config.jwt_signing_key, &refresh_token.user_id, &refresh_token.family_id);
 
-            Ok(generate_access_token(&config.jwt_signing_key, &refresh_token.user_id))
+            let mut db = db.0.begin().await.unwrap();
+
+            if !rotate_refresh_token(&mut db, &refresh_token, &tokens.refresh_token, &client).await.unwrap() {
+                return Err(ForbiddenError.into());
+            }
+
+            db.commit().await.unwrap();
+
+            Ok(tokens)
         })
     })
     .await;
 
-    Ok(Json(access_token?))
+    Ok(Json(tokens?))
+}
+
+/// Logs out by revoking the session of the presented refresh token, access tokens issued for it stay valid until they
+/// expire
+#[handler]
+pub async fn logout(
+    db: Data<&sqlx::Pool<sqlx::Postgres>>,
+    data: Json<RefreshTokenData>,
+) -> poem::Result<StatusCode> {
+    let hashed_refresh_token = hash_secret_token(&data.refresh_token);
+
+    let result = sqlx::query!(
+        "UPDATE user_refresh_tokens SET revoked_at = NOW() \
+        WHERE revoked_at IS NULL AND family_id = (SELECT family_id FROM user_refresh_tokens WHERE token_hash = $1 AND rotated_at IS NULL)",
+        hashed_refresh_token,
+    )
+    .execute(db.0)
+    .await
+    .unwrap();
+
+    if result.rows_affected() == 0 {
+        return Err(ForbiddenError.into());
+    }
+
+    Ok(StatusCode::NO_CONTENT)
 }
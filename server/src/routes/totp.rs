@@ -0,0 +1,250 @@
+use chrono::{DateTime, Utc};
+use poem::{
+    handler,
+    http::{HeaderMap, StatusCode},
+    web::{
+        headers::{HeaderMapExt, UserAgent},
+        Data, Json, RemoteAddr,
+    },
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::{
+    logic::{
+        security_events::{record_security_event, SecurityEventKind},
+        totp::{
+            find_totp_identity, load_totp_identity, save_totp_identity, TotpIdentityData,
+            TOTP_IDENTITY_PROVIDER,
+        },
+    },
+    utils::{response_errors::ForbiddenError, user_security::AuthenticatedUser},
+};
+
+#[derive(Serialize)]
+struct TotpStatus {
+    enabled: bool,
+    confirmed_at: Option<DateTime<Utc>>,
+    remaining_recovery_codes: usize,
+}
+
+#[handler]
+pub async fn get_totp_status(
+    db: Data<&sqlx::Pool<sqlx::Postgres>>,
+    user: AuthenticatedUser,
+) -> poem::Result<Json<TotpStatus>> {
+    let identity = find_totp_identity(db.0, user.id).await.unwrap();
+    let confirmed = identity.filter(|data| data.confirmed_at.is_some());
+
+    Ok(Json(TotpStatus {
+        enabled: confirmed.is_some(),
+        confirmed_at: confirmed.as_ref().and_then(|data| data.confirmed_at),
+        remaining_recovery_codes: confirmed
+            .as_ref()
+            .map(|data| data.remaining_recovery_codes())
+            .unwrap_or(0),
+    }))
+}
+
+#[derive(Serialize)]
+struct TotpEnrollment {
+    /// Base32 encoded, for authenticator apps which the secret has to be typed into
+    secret: String,
+    provisioning_uri: String,
+}
+
+/// Starts setting up two-factor authentication with a new secret, which isn't required to log in until it's been
+/// confirmed. Starting again before confirming replaces the secret.
+#[handler]
+pub async fn start_totp_enrollment(
+    db: Data<&sqlx::Pool<sqlx::Postgres>>,
+    user: AuthenticatedUser,
+) -> poem::Result<Json<TotpEnrollment>> {
+    let mut tx = db.begin().await.unwrap();
+
+    let identity = load_totp_identity(&mut tx, user.id).await.unwrap();
+    if identity.is_some_and(|(_, data)| data.confirmed_at.is_some()) {
+        return Err(poem::Error::from_string(
+            "Two-factor authentication is already enabled",
+            StatusCode::CONFLICT,
+        ));
+    }
+
+    let email = sqlx::query_scalar!("SELECT email FROM users WHERE id = $1", user.id.as_bytes())
+        .fetch_one(&mut *tx)
+        .await
+        .unwrap();
+
+    let identity_data = TotpIdentityData::new();
+    save_totp_identity(&mut tx, user.id, &identity_data)
+        .await
+        .unwrap();
+
+    tx.commit().await.unwrap();
+
+    Ok(Json(TotpEnrollment {
+        secret: identity_data.secret().to_string(),
+        provisioning_uri: identity_data.provisioning_uri(&email).unwrap(),
+    }))
+}
+
+#[derive(Deserialize)]
+struct TotpCodeData {
+    pub code: Option<String>,
+    pub recovery_code: Option<String>,
+}
+
+#[derive(Serialize)]
+struct TotpRecoveryCodes {
+    /// Only ever shown once, the server only keeps their hashes
+    recovery_codes: Vec<String>,
+}
+
+/// Enables two-factor authentication once the user has entered a code from their authenticator app
+#[handler]
+pub async fn confirm_totp_enrollment(
+    db: Data<&sqlx::Pool<sqlx::Postgres>>,
+    user: AuthenticatedUser,
+    data: Json<TotpCodeData>,
+    headers: &HeaderMap,
+    remote_address: &RemoteAddr,
+) -> poem::Result<Json<TotpRecoveryCodes>> {
+    let mut tx = db.begin().await.unwrap();
+
+    let identity = load_totp_identity(&mut tx, user.id).await.unwrap();
+    let Some((_, mut identity_data)) = identity else {
+        return Err(poem::Error::from_string(
+            "Two-factor authentication hasn't been set up",
+            StatusCode::CONFLICT,
+        ));
+    };
+
+    if identity_data.confirmed_at.is_some() {
+        return Err(poem::Error::from_string(
+            "Two-factor authentication is already enabled",
+            StatusCode::CONFLICT,
+        ));
+    }
+
+    // Recovery codes don't prove the authenticator app was set up correctly
+    if !identity_data.verify(data.code.as_deref(), None) {
+        return Err(ForbiddenError.into());
+    }
+
+    identity_data.confirmed_at = Some(Utc::now());
+    let recovery_codes = identity_data.generate_recovery_codes();
+
+    save_totp_identity(&mut tx, user.id, &identity_data)
+        .await
+        .unwrap();
+
+    let user_agent = headers.typed_get::<UserAgent>().map(|ua| ua.to_string());
+    record_security_event(
+        &mut *tx,
+        user.id,
+        SecurityEventKind::TotpEnabled,
+        user_agent.as_deref(),
+        Some(&remote_address.to_string()),
+        json!({}),
+    )
+    .await
+    .unwrap();
+
+    tx.commit().await.unwrap();
+
+    Ok(Json(TotpRecoveryCodes { recovery_codes }))
+}
+
+/// Turns two-factor authentication off, which requires a code from the authenticator app or a recovery code
+#[handler]
+pub async fn disable_totp(
+    db: Data<&sqlx::Pool<sqlx::Postgres>>,
+    user: AuthenticatedUser,
+    data: Json<TotpCodeData>,
+    headers: &HeaderMap,
+    remote_address: &RemoteAddr,
+) -> poem::Result<StatusCode> {
+    let mut tx = db.begin().await.unwrap();
+
+    let identity = load_totp_identity(&mut tx, user.id).await.unwrap();
+    let Some((identity_id, mut identity_data)) = identity else {
+        return Err(poem::Error::from_string(
+            "Two-factor authentication isn't enabled",
+            StatusCode::CONFLICT,
+        ));
+    };
+
+    // An enrollment which was never confirmed can simply be abandoned
+    if identity_data.confirmed_at.is_some()
+        && !identity_data.verify(data.code.as_deref(), data.recovery_code.as_deref())
+    {
+        return Err(ForbiddenError.into());
+    }
+
+    sqlx::query!(
+        "DELETE FROM user_identities WHERE id = $1 AND provider = $2",
+        identity_id.as_bytes(),
+        TOTP_IDENTITY_PROVIDER,
+    )
+    .execute(&mut *tx)
+    .await
+    .unwrap();
+
+    sqlx::query!(
+        "DELETE FROM user_login_challenges WHERE user_id = $1",
+        user.id.as_bytes(),
+    )
+    .execute(&mut *tx)
+    .await
+    .unwrap();
+
+    if identity_data.confirmed_at.is_some() {
+        let user_agent = headers.typed_get::<UserAgent>().map(|ua| ua.to_string());
+        record_security_event(
+            &mut *tx,
+            user.id,
+            SecurityEventKind::TotpDisabled,
+            user_agent.as_deref(),
+            Some(&remote_address.to_string()),
+            json!({}),
+        )
+        .await
+        .unwrap();
+    }
+
+    tx.commit().await.unwrap();
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Replaces the user's recovery codes with new ones, which requires a code from the authenticator app
+#[handler]
+pub async fn regenerate_totp_recovery_codes(
+    db: Data<&sqlx::Pool<sqlx::Postgres>>,
+    user: AuthenticatedUser,
+    data: Json<TotpCodeData>,
+) -> poem::Result<Json<TotpRecoveryCodes>> {
+    let mut tx = db.begin().await.unwrap();
+
+    let identity = load_totp_identity(&mut tx, user.id).await.unwrap();
+    let Some((_, mut identity_data)) = identity.filter(|(_, data)| data.confirmed_at.is_some())
+    else {
+        return Err(poem::Error::from_string(
+            "Two-factor authentication isn't enabled",
+            StatusCode::CONFLICT,
+        ));
+    };
+
+    if !identity_data.verify(data.code.as_deref(), None) {
+        return Err(ForbiddenError.into());
+    }
+
+    let recovery_codes = identity_data.generate_recovery_codes();
+    save_totp_identity(&mut tx, user.id, &identity_data)
+        .await
+        .unwrap();
+
+    tx.commit().await.unwrap();
+
+    Ok(Json(TotpRecoveryCodes { recovery_codes }))
+}
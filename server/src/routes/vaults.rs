@@ -1,20 +1,23 @@
-use std::{path::PathBuf, time::Duration};
+use std::time::Duration;
 
+use super::search::{escape_like_pattern, set_fuzzy_name_match_threshold};
 use crate::{
-    models::vaults::{Vault, VaultFile},
+    logic::indexing::local_folder::resolve_local_folder_vault_file_path,
+    models::vaults::{Vault, VaultFile, VaultFileMediaMetadata},
     utils::{
-        response_errors::ForbiddenError, security::random_string, user_security::AuthenticatedUser,
-        xid::Xid,
+        mime::UNKNOWN_MIME_TYPE, response_errors::ForbiddenError, security::random_string,
+        user_security::AuthenticatedUser, xid::Xid,
     },
 };
+use base64::{prelude::BASE64_STANDARD, Engine};
 use chrono::Utc;
 use poem::{
     error::NotFoundError,
     handler,
     web::{Data, Json, Path, Query},
-    Body,
+    Body, Response,
 };
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use serde_json::json;
 use sha3::{Digest, Sha3_384};
 use tokio::fs::File;
@@ -35,11 +38,36 @@ pub async fn list_vaults(
     Ok(Json(vaults))
 }
 
+#[derive(Deserialize, Default, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+enum ListVaultFilesSort {
+    #[default]
+    Id,
+    ModifiedAt,
+    ModifiedAtDesc,
+}
+
+impl ListVaultFilesSort {
+    fn as_str(&self) -> &'static str {
+        match self {
+            ListVaultFilesSort::Id => "id",
+            ListVaultFilesSort::ModifiedAt => "modified_at",
+            ListVaultFilesSort::ModifiedAtDesc => "modified_at_desc",
+        }
+    }
+}
+
 #[derive(Deserialize)]
 struct ListVaultFilesQuery {
     after: Option<Xid>,
     parent_id: Option<Xid>,
     search: Option<String>,
+    /// Either an exact MIME type or a wildcard subtype such as `image/*`
+    mime_type: Option<String>,
+    #[serde(default)]
+    show_hidden: bool,
+    #[serde(default)]
+    sort: ListVaultFilesSort,
 }
 
 #[handler]
@@ -49,11 +77,6 @@ pub async fn list_vault_files(
     Path((vault_id,)): Path<(Xid,)>,
     query: Query<ListVaultFilesQuery>,
 ) -> poem::Result<Json<Vec<VaultFile>>> {
-    let query_after_id = match &query.after {
-        None => b"\0\0\0\0\0\0\0\0\0\0\0\0",
-        Some(xid) => xid.as_bytes(),
-    };
-
     let vault = sqlx::query_as!(
         Vault,
         "SELECT id, name, provider, data FROM vaults WHERE EXISTS(SELECT FROM user_vault_links WHERE user_vault_links.user_id = $1 AND user_vault_links.vault_id = vaults.id) AND id = $2",
@@ -73,20 +96,103 @@ pub async fn list_vault_files(
         str => Some(str),
     };
 
+    // The after cursor is always a file id, when sorting by modification time it's resolved to that file's position in
+    // the sort order. Files without a modification time are sorted first.
+    let mut db = db.0.begin().await.unwrap();
+    set_fuzzy_name_match_threshold(&mut db).await.unwrap();
+
     let files = sqlx::query_as!(
         VaultFile,
-        "SELECT id, vault_id, path_id, name, file_type, parent_id, created_at, size FROM vault_files WHERE vault_id = $1 AND id > $2 AND (($3::BYTEA IS NULL AND parent_id IS NULL) OR parent_id = $3::BYTEA) AND ($4::TEXT IS NULL OR POSITION(LOWER($4::TEXT) IN LOWER(name)) > 0)",
+        "SELECT \
+            id, vault_id, path_id, name, file_type, parent_id, created_at, modified_at, size, mode, is_hidden, symlink_target, content_sha256, mime_type, \
+            subtree_size, subtree_file_count, subtree_folder_count \
+        FROM vault_files \
+        WHERE \
+            vault_id = $1 \
+            AND (($3::BYTEA IS NULL AND parent_id IS NULL) OR parent_id = $3::BYTEA) \
+            AND ($4::TEXT IS NULL OR search_name LIKE '%' || LOWER(immutable_unaccent($8::TEXT)) || '%' OR LOWER(immutable_unaccent($4::TEXT)) <% search_name) \
+            AND ($5::TEXT IS NULL OR mime_type = $5::TEXT OR (RIGHT($5::TEXT, 2) = '/*' AND mime_type LIKE LEFT($5::TEXT, -1) || '%')) \
+            AND ($6::BOOLEAN OR NOT is_hidden) \
+            AND ($2::BYTEA IS NULL OR CASE $7::TEXT \
+                WHEN 'modified_at' THEN (COALESCE(modified_at, '-infinity'), id) > (SELECT COALESCE(modified_at, '-infinity'), id FROM vault_files WHERE id = $2::BYTEA) \
+                WHEN 'modified_at_desc' THEN (COALESCE(modified_at, '-infinity'), id) < (SELECT COALESCE(modified_at, '-infinity'), id FROM vault_files WHERE id = $2::BYTEA) \
+                ELSE id > $2::BYTEA \
+            END) \
+        ORDER BY \
+            CASE WHEN $7::TEXT = 'modified_at' THEN COALESCE(modified_at, '-infinity') END ASC, \
+            CASE WHEN $7::TEXT = 'modified_at_desc' THEN COALESCE(modified_at, '-infinity') END DESC, \
+            CASE WHEN $7::TEXT = 'modified_at_desc' THEN id END DESC, \
+            id ASC",
         vault_id.as_bytes(),
-        query_after_id,
+        query.after.map(|a| a.as_bytes().to_vec()),
         query.parent_id.map(|p| p.as_bytes().to_vec()),
         search,
-    ).fetch_all(db.0)
+        query.mime_type.as_deref().map(str::trim).filter(|m| !m.is_empty()),
+        query.show_hidden,
+        query.sort.as_str(),
+        search.map(escape_like_pattern),
+    ).fetch_all(&mut *db)
     .await
     .unwrap();
 
+    db.commit().await.unwrap();
+
     Ok(Json(files))
 }
 
+#[derive(Serialize)]
+struct VaultFileDetails {
+    #[serde(flatten)]
+    file: VaultFile,
+    /// Only set for images, audio & video files which have been indexed
+    media_metadata: Option<VaultFileMediaMetadata>,
+}
+
+#[handler]
+pub async fn get_vault_file_details(
+    db: Data<&sqlx::Pool<sqlx::Postgres>>,
+    user: AuthenticatedUser,
+    Path((vault_id, file_id)): Path<(Xid, Xid)>,
+) -> poem::Result<Json<VaultFileDetails>> {
+    let file = sqlx::query_as!(
+        VaultFile,
+        "SELECT \
+            id, vault_id, path_id, name, file_type, parent_id, created_at, modified_at, size, mode, is_hidden, symlink_target, content_sha256, mime_type, \
+            subtree_size, subtree_file_count, subtree_folder_count \
+        FROM vault_files \
+        WHERE \
+            id = $2 AND vault_id = $3 \
+            AND EXISTS(SELECT FROM user_vault_links WHERE user_vault_links.user_id = $1 AND user_vault_links.vault_id = vault_files.vault_id)",
+        user.id.as_bytes(), file_id.as_bytes(), vault_id.as_bytes(),
+    )
+    .fetch_optional(db.0)
+    .await
+    .unwrap()
+    .ok_or(NotFoundError)?;
+
+    let media_metadata = sqlx::query_as!(
+        VaultFileMediaMetadata,
+        "SELECT width, height, orientation, captured_at, camera_make, camera_model, latitude, longitude, altitude, duration_seconds \
+        FROM vault_file_media_metadata WHERE vault_file_id = $1",
+        file_id.as_bytes(),
+    )
+    .fetch_optional(db.0)
+    .await
+    .unwrap();
+
+    Ok(Json(VaultFileDetails {
+        file,
+        media_metadata,
+    }))
+}
+
+struct DownloadableVaultFile {
+    path_id: String,
+    content_sha256: Option<Vec<u8>>,
+    mime_type: Option<String>,
+    vault_data: serde_json::Value,
+}
+
 #[derive(Deserialize)]
 struct DownloadVaultFileQuery {
     code: Option<String>,
@@ -98,18 +204,22 @@ pub async fn download_vault_file(
     user: Option<AuthenticatedUser>,
     query: Query<DownloadVaultFileQuery>,
     Path((vault_id, file_id)): Path<(Xid, Xid)>,
-) -> poem::Result<Body> {
+) -> poem::Result<Response> {
     // Assumes we're dealing with local folder vault
 
-    let vault_file_path_id = match (user, &query.code) {
+    let vault_file = match (user, &query.code) {
         (None, None) => Err(ForbiddenError),
         (Some(user), _) => {
-            let record = sqlx::query!(
-                "SELECT path_id FROM vault_files LEFT JOIN user_vault_links ON user_vault_links.vault_id = vault_files.vault_id WHERE user_vault_links.user_id = $1 AND vault_files.id = $2 AND vault_files.vault_id = $3 AND vault_files.file_type = 'file'",
+            let record = sqlx::query_as!(
+                DownloadableVaultFile,
+                "SELECT vault_files.path_id, vault_files.content_sha256, vault_files.mime_type, vaults.data AS vault_data FROM vault_files \
+                    INNER JOIN vaults ON vaults.id = vault_files.vault_id \
+                    LEFT JOIN user_vault_links ON user_vault_links.vault_id = vault_files.vault_id \
+                WHERE user_vault_links.user_id = $1 AND vault_files.id = $2 AND vault_files.vault_id = $3 AND vault_files.file_type = 'file'",
                 user.id.as_bytes(), file_id.as_bytes(), vault_id.as_bytes(),
             ).fetch_optional(db.0).await.unwrap();
 
-            Ok(record.map(|r| r.path_id))
+            Ok(record)
         }
         (None, Some(code)) => {
             let vault_file_code_hash = {
@@ -120,8 +230,10 @@ pub async fn download_vault_file(
 
             // AND vault_file_access_codes.expires_at < NOW()
 
-            let record = sqlx::query!(
-                "SELECT path_id FROM vault_files \
+            let record = sqlx::query_as!(
+                DownloadableVaultFile,
+                "SELECT vault_files.path_id, vault_files.content_sha256, vault_files.mime_type, vaults.data AS vault_data FROM vault_files \
+                    INNER JOIN vaults ON vaults.id = vault_files.vault_id \
                     LEFT JOIN vault_file_access_codes \
                     ON vault_file_access_codes.vault_file_id = vault_files.id \
                 WHERE \
@@ -130,20 +242,41 @@ pub async fn download_vault_file(
                 vault_file_code_hash, file_id.as_bytes(), vault_id.as_bytes(),
             ).fetch_optional(db.0).await.unwrap();
 
-            Ok(record.map(|r| r.path_id))
+            Ok(record)
         }
     }?;
 
-    if vault_file_path_id.is_none() {
+    if vault_file.is_none() {
         return Err(NotFoundError.into());
     }
-    let vault_file_path_id = vault_file_path_id.unwrap();
+    let vault_file = vault_file.unwrap();
+
+    // Whatever the index says, files outside of the vault root (e.g. behind a symlink) are never served
+    let vault_file_path =
+        resolve_local_folder_vault_file_path(&vault_file.vault_data, &vault_file.path_id)
+            .await
+            .map_err(|_| NotFoundError)?
+            .ok_or(NotFoundError)?;
 
-    let vault_file_path = PathBuf::from(vault_file_path_id);
+    let file_stream = File::open(vault_file_path)
+        .await
+        .map_err(|_| NotFoundError)?;
 
-    let file_stream = File::open(vault_file_path).await.unwrap();
+    let mut response = Response::builder().content_type(
+        vault_file
+            .mime_type
+            .unwrap_or_else(|| UNKNOWN_MIME_TYPE.to_string()),
+    );
+
+    // RFC 3230 instance digest, only available once the background hasher has gotten to the file
+    if let Some(content_sha256) = vault_file.content_sha256 {
+        response = response.header(
+            "Digest",
+            format!("sha-256={}", BASE64_STANDARD.encode(content_sha256)),
+        );
+    }
 
-    Ok(Body::from_async_read(file_stream))
+    Ok(response.body(Body::from_async_read(file_stream)))
 }
 
 #[handler]
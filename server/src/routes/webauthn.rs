@@ -0,0 +1,266 @@
+use chrono::{DateTime, Utc};
+use poem::{
+    error::NotFoundError,
+    handler,
+    http::{HeaderMap, StatusCode},
+    web::{
+        headers::{HeaderMapExt, UserAgent},
+        Data, Json, Path, RemoteAddr,
+    },
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use webauthn_rs::prelude::{CreationChallengeResponse, RegisterPublicKeyCredential};
+
+use crate::{
+    config::Config,
+    logic::{
+        security_events::{record_security_event, SecurityEventKind},
+        webauthn::{
+            build_webauthn, get_or_create_user_handle, load_user_credentials,
+            require_discoverable_passkey, start_ceremony, take_ceremony, WebauthnCeremonyState,
+        },
+    },
+    utils::{
+        response_errors::ForbiddenError, user_agents::parse_device_info,
+        user_security::AuthenticatedUser, xid::Xid,
+    },
+};
+
+#[derive(Serialize)]
+struct WebauthnCredentialInfo {
+    id: Xid,
+    name: String,
+    created_at: DateTime<Utc>,
+    last_used_at: Option<DateTime<Utc>>,
+}
+
+/// Lists the passkeys the user can log in with, most recently added first
+#[handler]
+pub async fn list_webauthn_credentials(
+    db: Data<&sqlx::Pool<sqlx::Postgres>>,
+    user: AuthenticatedUser,
+) -> poem::Result<Json<Vec<WebauthnCredentialInfo>>> {
+    let credentials = sqlx::query!(
+        "SELECT id, name, created_at, last_used_at FROM user_webauthn_credentials WHERE user_id = $1 ORDER BY created_at DESC",
+        user.id.as_bytes(),
+    )
+    .fetch_all(db.0)
+    .await
+    .unwrap();
+
+    Ok(Json(
+        credentials
+            .into_iter()
+            .map(|credential| WebauthnCredentialInfo {
+                id: Xid::from(credential.id),
+                name: credential.name,
+                created_at: credential.created_at,
+                last_used_at: credential.last_used_at,
+            })
+            .collect(),
+    ))
+}
+
+#[derive(Serialize)]
+struct WebauthnRegistrationStart {
+    ceremony_id: Xid,
+    /// Passed to `navigator.credentials.create()` by the frontend
+    options: CreationChallengeResponse,
+}
+
+/// Starts adding a passkey to the user's account
+#[handler]
+pub async fn start_webauthn_registration(
+    db: Data<&sqlx::Pool<sqlx::Postgres>>,
+    config: Data<&Config>,
+    user: AuthenticatedUser,
+) -> poem::Result<Json<WebauthnRegistrationStart>> {
+    let webauthn = build_webauthn(&config).unwrap();
+
+    let mut conn = db.acquire().await.unwrap();
+    let user_handle = get_or_create_user_handle(&mut conn, user.id).await.unwrap();
+
+    let account = sqlx::query!(
+        "SELECT name, email FROM users WHERE id = $1",
+        user.id.as_bytes()
+    )
+    .fetch_one(&mut *conn)
+    .await
+    .unwrap();
+
+    // Stops the browser from creating a second passkey on an authenticator which already has one
+    let existing_credentials = load_user_credentials(db.0, user.id)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|credential| credential.passkey.cred_id().clone())
+        .collect::<Vec<_>>();
+
+    let (mut options, state) = webauthn
+        .start_passkey_registration(
+            user_handle,
+            &account.email,
+            &account.name,
+            Some(existing_credentials),
+        )
+        .unwrap();
+    require_discoverable_passkey(&mut options);
+
+    let ceremony_id = start_ceremony(
+        db.0,
+        Some(user.id),
+        &WebauthnCeremonyState::Registration(state),
+    )
+    .await
+    .unwrap();
+
+    Ok(Json(WebauthnRegistrationStart {
+        ceremony_id,
+        options,
+    }))
+}
+
+#[derive(Deserialize)]
+struct WebauthnRegistrationFinishData {
+    pub ceremony_id: Xid,
+    /// Defaults to a description of the device the passkey was added from
+    pub name: Option<String>,
+    pub credential: RegisterPublicKeyCredential,
+}
+
+#[handler]
+pub async fn finish_webauthn_registration(
+    db: Data<&sqlx::Pool<sqlx::Postgres>>,
+    config: Data<&Config>,
+    user: AuthenticatedUser,
+    data: Json<WebauthnRegistrationFinishData>,
+    headers: &HeaderMap,
+    remote_address: &RemoteAddr,
+) -> poem::Result<Json<WebauthnCredentialInfo>> {
+    let webauthn = build_webauthn(&config).unwrap();
+
+    let ceremony = take_ceremony(db.0, data.ceremony_id).await.unwrap();
+    let Some(WebauthnCeremonyState::Registration(state)) = ceremony
+        .filter(|ceremony| {
+            ceremony
+                .user_id
+                .is_some_and(|user_id| user_id.as_bytes() == user.id.as_bytes())
+        })
+        .map(|ceremony| ceremony.state)
+    else {
+        return Err(ForbiddenError.into());
+    };
+
+    let passkey = match webauthn.finish_passkey_registration(&data.credential, &state) {
+        Ok(passkey) => passkey,
+        Err(error) => {
+            println!("Failed to register passkey: {error:?}");
+            return Err(ForbiddenError.into());
+        }
+    };
+
+    let user_agent = headers.typed_get::<UserAgent>().map(|ua| ua.to_string());
+    let name = data
+        .name
+        .as_deref()
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .map(str::to_string)
+        .unwrap_or_else(|| {
+            let device = user_agent
+                .as_deref()
+                .map(parse_device_info)
+                .unwrap_or_default();
+
+            match (device.browser, device.os) {
+                (Some(browser), Some(os)) => format!("{browser} on {os}"),
+                (Some(name), None) | (None, Some(name)) => name,
+                (None, None) => "Passkey".to_string(),
+            }
+        });
+
+    let id = Xid::new();
+    let mut tx = db.begin().await.unwrap();
+
+    let credential = sqlx::query!(
+        "INSERT INTO user_webauthn_credentials (id, user_id, credential_id, name, passkey) VALUES ($1, $2, $3, $4, $5) \
+        ON CONFLICT (credential_id) DO NOTHING \
+        RETURNING created_at",
+        id.as_bytes(),
+        user.id.as_bytes(),
+        passkey.cred_id().as_ref(),
+        name,
+        serde_json::to_value(&passkey).unwrap(),
+    )
+    .fetch_optional(&mut *tx)
+    .await
+    .unwrap();
+
+    let Some(credential) = credential else {
+        return Err(poem::Error::from_string(
+            "This passkey has already been registered",
+            StatusCode::CONFLICT,
+        ));
+    };
+
+    record_security_event(
+        &mut *tx,
+        user.id,
+        SecurityEventKind::WebauthnCredentialAdded,
+        user_agent.as_deref(),
+        Some(&remote_address.to_string()),
+        json!({ "credential_id": id, "name": name }),
+    )
+    .await
+    .unwrap();
+
+    tx.commit().await.unwrap();
+
+    Ok(Json(WebauthnCredentialInfo {
+        id,
+        name,
+        created_at: credential.created_at,
+        last_used_at: None,
+    }))
+}
+
+#[handler]
+pub async fn delete_webauthn_credential(
+    db: Data<&sqlx::Pool<sqlx::Postgres>>,
+    user: AuthenticatedUser,
+    Path((credential_id,)): Path<(Xid,)>,
+    headers: &HeaderMap,
+    remote_address: &RemoteAddr,
+) -> poem::Result<StatusCode> {
+    let mut tx = db.begin().await.unwrap();
+
+    let deleted = sqlx::query!(
+        "DELETE FROM user_webauthn_credentials WHERE id = $1 AND user_id = $2 RETURNING name",
+        credential_id.as_bytes(),
+        user.id.as_bytes(),
+    )
+    .fetch_optional(&mut *tx)
+    .await
+    .unwrap();
+
+    let Some(deleted) = deleted else {
+        return Err(NotFoundError.into());
+    };
+
+    let user_agent = headers.typed_get::<UserAgent>().map(|ua| ua.to_string());
+    record_security_event(
+        &mut *tx,
+        user.id,
+        SecurityEventKind::WebauthnCredentialRemoved,
+        user_agent.as_deref(),
+        Some(&remote_address.to_string()),
+        json!({ "credential_id": credential_id, "name": deleted.name }),
+    )
+    .await
+    .unwrap();
+
+    tx.commit().await.unwrap();
+
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -1,9 +1,24 @@
-use std::{fs::read_dir, io, path::PathBuf};
+use std::{
+    collections::VecDeque,
+    fmt,
+    fs::{read_dir, ReadDir},
+    io,
+    num::NonZero,
+    path::{Path, PathBuf},
+    sync::{Arc, Condvar, Mutex},
+    thread,
+};
+
+use serde::Deserialize;
+use tokio::sync::mpsc;
+
+use super::ignore_rules::IgnoreRules;
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum FileType {
     File,
     Folder,
+    Symlink,
 }
 
 impl FileType {
@@ -12,32 +27,331 @@ impl FileType {
         match self {
             FileType::Folder => "folder",
             FileType::File => "file",
+            FileType::Symlink => "symlink",
         }
         .to_string()
     }
 }
 
-pub fn walk_directory(path: PathBuf) -> Result<Vec<(PathBuf, FileType)>, io::Error> {
-    let mut out = vec![];
+/// How symlinks found inside a folder are treated
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SymlinkPolicy {
+    /// Symlinks are skipped entirely
+    #[default]
+    Ignore,
+    /// Symlinks are treated as whatever they point to, as long as that's inside the root folder
+    FollowWithinRoot,
+    /// Symlinks are listed as links without being followed
+    ShowAsLinks,
+}
 
-    let dir_results = read_dir(&path);
+/// Determines how a path should be indexed, `None` means it shouldn't be. The root is expected to be canonical.
+pub fn resolve_file_type(
+    path: &Path,
+    root: &Path,
+    symlinks: SymlinkPolicy,
+) -> Result<Option<FileType>, io::Error> {
+    let metadata = path.symlink_metadata()?;
 
-    if let Err(error) = dir_results {
-        println!("An error occurred while indexing directory {path:?}: {error:?}");
-        return Ok(vec![]);
+    if metadata.is_file() {
+        return Ok(Some(FileType::File));
+    } else if metadata.is_dir() {
+        return Ok(Some(FileType::Folder));
+    } else if !metadata.is_symlink() {
+        return Ok(None);
     }
 
-    for file in read_dir(path)? {
-        let file = file?;
-        let file_type = file.file_type()?;
+    match symlinks {
+        SymlinkPolicy::Ignore => Ok(None),
+        SymlinkPolicy::ShowAsLinks => Ok(Some(FileType::Symlink)),
+        SymlinkPolicy::FollowWithinRoot => {
+            // Dangling symlinks fail to canonicalize, they're skipped like anything else we can't follow
+            let target = match path.canonicalize() {
+                Ok(target) => target,
+                Err(_) => return Ok(None),
+            };
+
+            if !target.starts_with(root) {
+                return Ok(None);
+            }
+
+            if target.is_dir() {
+                Ok(Some(FileType::Folder))
+            } else if target.is_file() {
+                Ok(Some(FileType::File))
+            } else {
+                Ok(None)
+            }
+        }
+    }
+}
+
+/// Most of the time spent walking is waiting on the disk, a few threads are enough to keep it busy
+const MAX_WALK_THREADS: usize = 8;
+/// How many entries can be waiting on the caller before the walk pauses
+const WALK_CHANNEL_CAPACITY: usize = 1024;
 
-        if file_type.is_file() {
-            out.push((file.path(), FileType::File));
-        } else if file_type.is_dir() {
-            out.push((file.path(), FileType::Folder));
-            out.extend_from_slice(walk_directory(file.path())?.as_slice());
+#[derive(Debug, Clone, Copy)]
+pub struct WalkOptions {
+    pub symlinks: SymlinkPolicy,
+    /// How many levels below the root are walked, e.g. 1 only gives the root's children. No limit if `None`.
+    pub max_depth: Option<usize>,
+    pub threads: usize,
+}
+
+impl WalkOptions {
+    pub fn new(symlinks: SymlinkPolicy, max_depth: Option<usize>) -> WalkOptions {
+        WalkOptions {
+            symlinks,
+            max_depth,
+            threads: thread::available_parallelism()
+                .map_or(1, NonZero::get)
+                .min(MAX_WALK_THREADS),
         }
     }
+}
+
+/// Whether a path lies deeper below the root than a walk with this depth limit would go
+pub fn exceeds_max_depth(path: &Path, root: &Path, max_depth: Option<usize>) -> bool {
+    match (max_depth, path.strip_prefix(root)) {
+        (Some(max_depth), Ok(relative_path)) => relative_path.components().count() > max_depth,
+        _ => false,
+    }
+}
+
+/// An entry which was skipped during a walk, if it's a folder none of its children are walked either
+#[derive(Debug)]
+pub struct WalkError {
+    pub path: PathBuf,
+    pub error: io::Error,
+}
+
+impl fmt::Display for WalkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Failed to read {:?}: {}", self.path, self.error)
+    }
+}
+
+struct QueuedFolder {
+    path: PathBuf,
+    depth: usize,
+    /// Canonical paths of the folder and everything above it, to stop followed symlinks from looping
+    ancestors: Arc<Vec<PathBuf>>,
+    /// The root is opened before the walk starts so failing to read it is an error for the whole walk
+    entries: Option<ReadDir>,
+}
+
+struct WalkQueue {
+    folders: VecDeque<QueuedFolder>,
+    /// Folders which are queued or being read by a thread, the walk is done once there are none left
+    unfinished: usize,
+    /// Set once the caller stops listening
+    is_stopped: bool,
+}
+
+struct WalkState {
+    root: PathBuf,
+    options: WalkOptions,
+    ignore_rules: Mutex<IgnoreRules>,
+    queue: Mutex<WalkQueue>,
+    queue_changed: Condvar,
+    entries_tx: mpsc::Sender<Result<(PathBuf, FileType), WalkError>>,
+}
+
+impl WalkState {
+    fn next_folder(&self) -> Option<QueuedFolder> {
+        let mut queue = self.queue.lock().unwrap();
+
+        loop {
+            if queue.is_stopped || queue.unfinished == 0 {
+                return None;
+            }
+
+            if let Some(folder) = queue.folders.pop_front() {
+                return Some(folder);
+            }
+
+            queue = self.queue_changed.wait(queue).unwrap();
+        }
+    }
+
+    fn finish_folder(&self, subfolders: Vec<QueuedFolder>, is_stopped: bool) {
+        let mut queue = self.queue.lock().unwrap();
+
+        queue.unfinished += subfolders.len();
+        queue.unfinished -= 1;
+        queue.folders.extend(subfolders);
+        queue.is_stopped |= is_stopped;
+
+        self.queue_changed.notify_all();
+    }
+
+    fn send(&self, entry: Result<(PathBuf, FileType), WalkError>) -> bool {
+        self.entries_tx.blocking_send(entry).is_ok()
+    }
+
+    fn resolve_entry(&self, path: &Path) -> Result<Option<FileType>, io::Error> {
+        let file_type = resolve_file_type(path, &self.root, self.options.symlinks)?;
+
+        Ok(file_type.filter(|file_type| {
+            !self
+                .ignore_rules
+                .lock()
+                .unwrap()
+                .is_ignored(path, *file_type == FileType::Folder)
+        }))
+    }
+
+    /// Sends the entries of a folder, returning its subfolders to walk next or `None` if the caller stopped listening.
+    /// Each subfolder is sent before it's queued, which is what guarantees parents come before their children.
+    fn walk_folder(&self, folder: QueuedFolder) -> Option<Vec<QueuedFolder>> {
+        // The entries of a folder are one level deeper than it
+        if self
+            .options
+            .max_depth
+            .is_some_and(|max_depth| folder.depth >= max_depth)
+        {
+            return Some(vec![]);
+        }
+
+        let entries = match folder.entries {
+            Some(entries) => entries,
+            None => match read_dir(&folder.path) {
+                Ok(entries) => entries,
+                Err(error) => {
+                    let error = WalkError {
+                        path: folder.path,
+                        error,
+                    };
+                    return self.send(Err(error)).then(Vec::new);
+                }
+            },
+        };
+
+        let mut subfolders = vec![];
+
+        for entry in entries {
+            let result = match entry {
+                Ok(entry) => {
+                    let path = entry.path();
+                    self.resolve_entry(&path)
+                        .map(|file_type| (path.clone(), file_type))
+                        .map_err(|error| WalkError { path, error })
+                }
+                Err(error) => Err(WalkError {
+                    path: folder.path.clone(),
+                    error,
+                }),
+            };
+
+            let (path, file_type) = match result {
+                Ok((path, Some(file_type))) => (path, file_type),
+                Ok((_, None)) => continue,
+                Err(error) => {
+                    if !self.send(Err(error)) {
+                        return None;
+                    }
+                    continue;
+                }
+            };
+
+            if file_type != FileType::Folder {
+                if !self.send(Ok((path, file_type))) {
+                    return None;
+                }
+                continue;
+            }
+
+            // Followed symlinks can loop back on to a folder we're already inside of
+            let canonical_path = match path.canonicalize() {
+                Ok(canonical_path) => canonical_path,
+                Err(error) => {
+                    if !self.send(Err(WalkError { path, error })) {
+                        return None;
+                    }
+                    continue;
+                }
+            };
+            if folder.ancestors.contains(&canonical_path) {
+                continue;
+            }
+
+            if !self.send(Ok((path.clone(), FileType::Folder))) {
+                return None;
+            }
+
+            let mut ancestors = folder.ancestors.as_ref().clone();
+            ancestors.push(canonical_path);
+
+            subfolders.push(QueuedFolder {
+                path,
+                depth: folder.depth + 1,
+                ancestors: Arc::new(ancestors),
+                entries: None,
+            });
+        }
+
+        Some(subfolders)
+    }
+
+    fn run_thread(&self) {
+        while let Some(folder) = self.next_folder() {
+            match self.walk_folder(folder) {
+                Some(subfolders) => self.finish_folder(subfolders, false),
+                None => self.finish_folder(vec![], true),
+            }
+        }
+    }
+}
+
+/// A walk running in the background, see [`walk_directory`]
+pub struct DirectoryWalk {
+    entries_rx: mpsc::Receiver<Result<(PathBuf, FileType), WalkError>>,
+}
+
+impl DirectoryWalk {
+    /// Gives the next entry of the walk, or `None` once everything has been walked. Dropping the walk stops it.
+    pub async fn next(&mut self) -> Option<Result<(PathBuf, FileType), WalkError>> {
+        self.entries_rx.recv().await
+    }
+}
+
+/// Walks everything below a folder on a pool of threads, streaming entries as they're found. Entries come in no
+/// particular order except that folders always come before anything inside of them. Entries that can't be read are
+/// reported as errors without stopping the walk, only failing to read the folder itself is an error up front.
+pub fn walk_directory(
+    path: PathBuf,
+    options: WalkOptions,
+    ignore_rules: IgnoreRules,
+) -> Result<DirectoryWalk, io::Error> {
+    let root = path.canonicalize()?;
+    let entries = read_dir(&path)?;
+
+    let (entries_tx, entries_rx) = mpsc::channel(WALK_CHANNEL_CAPACITY);
+
+    let state = Arc::new(WalkState {
+        root: root.clone(),
+        options,
+        ignore_rules: Mutex::new(ignore_rules),
+        queue: Mutex::new(WalkQueue {
+            folders: VecDeque::from([QueuedFolder {
+                path,
+                depth: 0,
+                ancestors: Arc::new(vec![root]),
+                entries: Some(entries),
+            }]),
+            unfinished: 1,
+            is_stopped: false,
+        }),
+        queue_changed: Condvar::new(),
+        entries_tx,
+    });
+
+    for _ in 0..options.threads.max(1) {
+        let state = state.clone();
+        thread::spawn(move || state.run_thread());
+    }
 
-    Ok(out)
+    Ok(DirectoryWalk { entries_rx })
 }
@@ -1,8 +1,24 @@
 use std::num::ParseIntError;
 
+use serde::Serializer;
+
 pub fn decode_hex(str: &str) -> Result<Vec<u8>, ParseIntError> {
     (0..str.len())
         .step_by(2)
         .map(|i| u8::from_str_radix(&str[i..i + 2], 16))
         .collect()
 }
+
+pub fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+pub fn serialize_optional_hex<S>(bytes: &Option<Vec<u8>>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    match bytes {
+        None => serializer.serialize_none(),
+        Some(bytes) => serializer.serialize_str(&encode_hex(bytes)),
+    }
+}
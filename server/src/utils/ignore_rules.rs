@@ -0,0 +1,84 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use ignore::{
+    gitignore::{Gitignore, GitignoreBuilder},
+    Match,
+};
+
+pub const IGNORE_FILE_NAME: &str = ".floppyignore";
+
+/// Gitignore-style rules for a folder tree, made up of `.floppyignore` files at any level of the tree plus a set of
+/// patterns which apply to the whole tree. Ignore files are loaded lazily and cached until reloaded.
+pub struct IgnoreRules {
+    root: PathBuf,
+    patterns: Gitignore,
+    ignore_files: HashMap<PathBuf, Gitignore>,
+}
+
+impl IgnoreRules {
+    pub fn new(root: &Path, patterns: &[String]) -> Result<IgnoreRules, ignore::Error> {
+        let mut builder = GitignoreBuilder::new(root);
+
+        for pattern in patterns {
+            builder.add_line(None, pattern)?;
+        }
+
+        Ok(IgnoreRules {
+            root: root.to_path_buf(),
+            patterns: builder.build()?,
+            ignore_files: HashMap::new(),
+        })
+    }
+
+    fn ignore_file(&mut self, folder: &Path) -> &Gitignore {
+        self.ignore_files
+            .entry(folder.to_path_buf())
+            .or_insert_with(|| {
+                let (ignore_file, error) = Gitignore::new(folder.join(IGNORE_FILE_NAME));
+
+                if let Some(error) = error {
+                    println!("An error occurred while loading ignore file in {folder:?}: {error}");
+                }
+
+                ignore_file
+            })
+    }
+
+    /// Forgets the cached ignore file of a folder, so it's loaded again the next time it's needed
+    pub fn reload(&mut self, folder: &Path) {
+        self.ignore_files.remove(folder);
+    }
+
+    pub fn is_ignored(&mut self, path: &Path, is_dir: bool) -> bool {
+        if !path.starts_with(&self.root) || path == self.root {
+            return false;
+        }
+
+        // Like git, ignore files deeper in the tree take precedence over those closer to the root, and the patterns for
+        // the whole tree come last
+        let folders = path
+            .ancestors()
+            .skip(1)
+            .take_while(|folder| folder.starts_with(&self.root))
+            .map(Path::to_path_buf)
+            .collect::<Vec<_>>();
+
+        for folder in folders {
+            match self
+                .ignore_file(&folder)
+                .matched_path_or_any_parents(path, is_dir)
+            {
+                Match::None => {}
+                Match::Ignore(_) => return true,
+                Match::Whitelist(_) => return false,
+            }
+        }
+
+        self.patterns
+            .matched_path_or_any_parents(path, is_dir)
+            .is_ignore()
+    }
+}
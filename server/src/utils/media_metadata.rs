@@ -0,0 +1,289 @@
+use std::{
+    error::Error,
+    fs::File,
+    io::{self, BufReader, Read, Seek, SeekFrom},
+    path::Path,
+};
+
+use chrono::NaiveDate;
+use exif::{In, Tag, Value};
+use symphonia::core::{
+    formats::FormatOptions, io::MediaSourceStream, meta::MetadataOptions, probe::Hint,
+};
+
+use crate::models::vaults::VaultFileMediaMetadata;
+
+/// Video containers using the ISO base media file format, whose headers are parsed directly
+const MP4_MIME_TYPES: &[&str] = &["video/mp4", "video/quicktime", "video/x-m4v", "video/3gpp"];
+/// The movie header is usually small, anything larger is most likely a corrupt file
+const MAX_MP4_MOVIE_BOX_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Whether media metadata can be extracted from files with this MIME type
+pub fn can_extract_media_metadata(mime_type: &str) -> bool {
+    mime_type.starts_with("image/")
+        || mime_type.starts_with("audio/")
+        || MP4_MIME_TYPES.contains(&mime_type)
+}
+
+/// Extracts the dimensions, EXIF data & duration of a media file, expects [`can_extract_media_metadata`] to have been
+/// checked. Files whose headers can't be parsed give empty metadata rather than an error.
+pub fn extract_media_metadata(
+    path: &Path,
+    mime_type: &str,
+) -> Result<VaultFileMediaMetadata, Box<dyn Error>> {
+    let mut metadata = VaultFileMediaMetadata::default();
+
+    if mime_type.starts_with("image/") {
+        read_exif(path, &mut metadata)?;
+
+        if let Ok(size) = imagesize::size(path) {
+            metadata.width = i32::try_from(size.width).ok();
+            metadata.height = i32::try_from(size.height).ok();
+        }
+    } else if mime_type.starts_with("audio/") {
+        metadata.duration_seconds = read_audio_duration(path, mime_type)?;
+    } else if MP4_MIME_TYPES.contains(&mime_type) {
+        read_mp4_movie_header(&mut BufReader::new(File::open(path)?), &mut metadata)?;
+    }
+
+    Ok(metadata)
+}
+
+fn read_exif(path: &Path, metadata: &mut VaultFileMediaMetadata) -> Result<(), io::Error> {
+    let exif = match exif::Reader::new().read_from_container(&mut BufReader::new(File::open(path)?))
+    {
+        Ok(exif) => exif,
+        Err(exif::Error::Io(error)) => return Err(error),
+        // Most images simply don't have any EXIF data
+        Err(_) => return Ok(()),
+    };
+
+    let ascii_field = |tag: Tag| match exif.get_field(tag, In::PRIMARY).map(|field| &field.value) {
+        Some(Value::Ascii(values)) => values.first().cloned(),
+        _ => None,
+    };
+    let string_field = |tag: Tag| {
+        ascii_field(tag)
+            .map(|value| String::from_utf8_lossy(&value).trim().to_string())
+            .filter(|value| !value.is_empty())
+    };
+
+    metadata.orientation = exif
+        .get_field(Tag::Orientation, In::PRIMARY)
+        .and_then(|field| field.value.get_uint(0))
+        .and_then(|orientation| i16::try_from(orientation).ok());
+    metadata.camera_make = string_field(Tag::Make);
+    metadata.camera_model = string_field(Tag::Model);
+    metadata.captured_at = ascii_field(Tag::DateTimeOriginal)
+        .or_else(|| ascii_field(Tag::DateTime))
+        .and_then(|value| exif::DateTime::from_ascii(&value).ok())
+        .and_then(|date_time| {
+            NaiveDate::from_ymd_opt(
+                date_time.year.into(),
+                date_time.month.into(),
+                date_time.day.into(),
+            )?
+            .and_hms_opt(
+                date_time.hour.into(),
+                date_time.minute.into(),
+                date_time.second.into(),
+            )
+        });
+
+    let coordinate = |tag: Tag, ref_tag: Tag, negative_ref: &[u8]| {
+        let degrees = match &exif.get_field(tag, In::PRIMARY)?.value {
+            Value::Rational(values) if values.len() >= 3 => {
+                values[0].to_f64() + values[1].to_f64() / 60.0 + values[2].to_f64() / 3600.0
+            }
+            _ => return None,
+        };
+
+        let is_negative = ascii_field(ref_tag).is_some_and(|value| value == negative_ref);
+        Some(if is_negative { -degrees } else { degrees }).filter(|degrees| degrees.is_finite())
+    };
+
+    metadata.latitude = coordinate(Tag::GPSLatitude, Tag::GPSLatitudeRef, b"S");
+    metadata.longitude = coordinate(Tag::GPSLongitude, Tag::GPSLongitudeRef, b"W");
+    metadata.altitude = match exif
+        .get_field(Tag::GPSAltitude, In::PRIMARY)
+        .map(|field| &field.value)
+    {
+        Some(Value::Rational(values)) if !values.is_empty() => {
+            // An altitude reference of 1 means below sea level
+            let is_below_sea_level = exif
+                .get_field(Tag::GPSAltitudeRef, In::PRIMARY)
+                .and_then(|field| field.value.get_uint(0))
+                == Some(1);
+            let altitude = values[0].to_f64();
+
+            Some(if is_below_sea_level {
+                -altitude
+            } else {
+                altitude
+            })
+            .filter(|altitude| altitude.is_finite())
+        }
+        _ => None,
+    };
+
+    Ok(())
+}
+
+fn read_audio_duration(path: &Path, mime_type: &str) -> Result<Option<f64>, io::Error> {
+    let mut hint = Hint::new();
+    hint.mime_type(mime_type);
+    if let Some(extension) = path.extension().and_then(|extension| extension.to_str()) {
+        hint.with_extension(extension);
+    }
+
+    let source = MediaSourceStream::new(Box::new(File::open(path)?), Default::default());
+    let Ok(probed) = symphonia::default::get_probe().format(
+        &hint,
+        source,
+        &FormatOptions::default(),
+        &MetadataOptions::default(),
+    ) else {
+        return Ok(None);
+    };
+
+    let duration = probed.format.default_track().and_then(|track| {
+        let params = &track.codec_params;
+        let frames = params.n_frames?;
+
+        match (params.time_base, params.sample_rate) {
+            (Some(time_base), _) => {
+                let time = time_base.calc_time(frames);
+                Some(time.seconds as f64 + time.frac)
+            }
+            (None, Some(sample_rate)) if sample_rate > 0 => {
+                Some(frames as f64 / f64::from(sample_rate))
+            }
+            _ => None,
+        }
+    });
+
+    Ok(duration)
+}
+
+/// Reads the header of the next box, returning its type and the size of its contents. Boxes extending to the end of the
+/// file are given a size of `u64::MAX`.
+fn read_mp4_box_header(reader: &mut impl Read) -> Result<Option<([u8; 4], u64)>, io::Error> {
+    let mut header = [0_u8; 8];
+    match reader.read_exact(&mut header) {
+        Ok(()) => {}
+        Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(error) => return Err(error),
+    }
+
+    let box_type = [header[4], header[5], header[6], header[7]];
+    let size = match u32::from_be_bytes([header[0], header[1], header[2], header[3]]) {
+        0 => u64::MAX,
+        1 => {
+            let mut large_size = [0_u8; 8];
+            reader.read_exact(&mut large_size)?;
+            u64::from_be_bytes(large_size).saturating_sub(16)
+        }
+        size => u64::from(size).saturating_sub(8),
+    };
+
+    Ok(Some((box_type, size)))
+}
+
+/// Iterates over the boxes directly inside the contents of a box
+fn mp4_child_boxes(mut contents: &[u8]) -> impl Iterator<Item = ([u8; 4], &[u8])> {
+    std::iter::from_fn(move || {
+        let (box_type, size) = read_mp4_box_header(&mut contents).ok()??;
+        let size = usize::try_from(size)
+            .unwrap_or(usize::MAX)
+            .min(contents.len());
+
+        let (box_contents, rest) = contents.split_at(size);
+        contents = rest;
+        Some((box_type, box_contents))
+    })
+}
+
+fn read_be_u32(bytes: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(
+        bytes.get(offset..offset + 4)?.try_into().ok()?,
+    ))
+}
+
+fn read_be_u64(bytes: &[u8], offset: usize) -> Option<u64> {
+    Some(u64::from_be_bytes(
+        bytes.get(offset..offset + 8)?.try_into().ok()?,
+    ))
+}
+
+/// Reads the duration from the movie header (`mvhd`) & the dimensions from the first visual track header (`tkhd`)
+fn read_mp4_movie_header(
+    reader: &mut (impl Read + Seek),
+    metadata: &mut VaultFileMediaMetadata,
+) -> Result<(), io::Error> {
+    // The movie box can come before or after the media data, depending on whether the file was optimised for streaming
+    let movie = loop {
+        let Some((box_type, size)) = read_mp4_box_header(reader)? else {
+            return Ok(());
+        };
+
+        if &box_type == b"moov" {
+            if size > MAX_MP4_MOVIE_BOX_SIZE {
+                return Ok(());
+            }
+
+            let mut movie = vec![0_u8; size as usize];
+            reader.read_exact(&mut movie)?;
+            break movie;
+        }
+
+        match i64::try_from(size) {
+            Ok(size) => reader.seek(SeekFrom::Current(size))?,
+            Err(_) => return Ok(()),
+        };
+    };
+
+    for (box_type, contents) in mp4_child_boxes(&movie) {
+        match &box_type {
+            b"mvhd" => {
+                // Version 1 headers use 64-bit times & durations
+                let (timescale, duration) = match contents.first() {
+                    Some(1) => (read_be_u32(contents, 20), read_be_u64(contents, 24)),
+                    _ => (
+                        read_be_u32(contents, 12),
+                        read_be_u32(contents, 16).map(u64::from),
+                    ),
+                };
+
+                if let (Some(timescale), Some(duration)) = (timescale, duration) {
+                    if timescale > 0 && duration != u64::MAX && duration != u64::from(u32::MAX) {
+                        metadata.duration_seconds = Some(duration as f64 / f64::from(timescale));
+                    }
+                }
+            }
+            b"trak" if metadata.width.is_none() => {
+                let Some((_, track_header)) =
+                    mp4_child_boxes(contents).find(|(box_type, _)| box_type == b"tkhd")
+                else {
+                    continue;
+                };
+
+                // Audio tracks have a zero width & height, the dimensions are 16.16 fixed point numbers
+                let offset = if track_header.first() == Some(&1) {
+                    88
+                } else {
+                    76
+                };
+                let width = read_be_u32(track_header, offset).unwrap_or(0) >> 16;
+                let height = read_be_u32(track_header, offset + 4).unwrap_or(0) >> 16;
+
+                if width > 0 && height > 0 {
+                    metadata.width = i32::try_from(width).ok();
+                    metadata.height = i32::try_from(height).ok();
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,16 @@
+use std::path::Path;
+
+pub const UNKNOWN_MIME_TYPE: &str = "application/octet-stream";
+
+/// Detects the MIME type of a file from its magic bytes, falling back to its extension for formats without any (plain
+/// text, source code, etc.)
+pub fn detect_mime_type(path: &Path) -> String {
+    if let Ok(Some(kind)) = infer::get_from_path(path) {
+        return kind.mime_type().to_string();
+    }
+
+    mime_guess::from_path(path)
+        .first()
+        .map(|mime_type| mime_type.essence_str().to_string())
+        .unwrap_or_else(|| UNKNOWN_MIME_TYPE.to_string())
+}
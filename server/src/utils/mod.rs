@@ -1,6 +1,11 @@
 pub mod folders;
 pub mod hex;
+pub mod ignore_rules;
+pub mod media_metadata;
+pub mod mime;
 pub mod response_errors;
 pub mod security;
+pub mod text_extraction;
+pub mod user_agents;
 pub mod user_security;
 pub mod xid;
@@ -5,6 +5,7 @@ use std::{
 };
 
 use rand::{distributions::Alphanumeric, thread_rng, Rng};
+use sha3::{Digest, Sha3_384};
 
 pub async fn ensure_execution_time<'a, T, F>(duration: Duration, func: F) -> T
 where
@@ -13,10 +14,11 @@ where
     let now = Instant::now();
     let result = func().await;
 
-    let duration = duration - now.elapsed();
+    // The function can take longer than the duration, in which case there's nothing left to wait for
+    let remaining = duration.saturating_sub(now.elapsed());
 
-    if duration > Duration::from_millis(0) {
-        tokio::time::sleep(duration).await;
+    if !remaining.is_zero() {
+        tokio::time::sleep(remaining).await;
     }
 
     result
@@ -26,3 +28,10 @@ pub fn random_string(n: usize) -> String {
     let rng = thread_rng();
     String::from_utf8(rng.sample_iter(&Alphanumeric).take(n).collect::<Vec<u8>>()).unwrap()
 }
+
+/// Secret tokens are only stored hashed, so they can't be used by someone who gets to read the database
+pub fn hash_secret_token(token: &str) -> Vec<u8> {
+    let mut hasher = Sha3_384::new();
+    hasher.update(token.as_bytes());
+    hasher.finalize().to_vec()
+}
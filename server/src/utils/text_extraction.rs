@@ -0,0 +1,83 @@
+use std::{error::Error, fs, panic, path::Path};
+
+/// Larger files are too slow to extract & unlikely to be documents anyone searches the contents of
+const MAX_EXTRACTED_FILE_SIZE: u64 = 16 * 1024 * 1024;
+/// Postgres caps a tsvector at 1MB, so only the start of longer documents is searchable
+const MAX_EXTRACTED_TEXT_LENGTH: usize = 256 * 1024;
+
+/// Source code is often detected as plain text or not at all, so it's recognised by extension too
+const SOURCE_CODE_EXTENSIONS: &[&str] = &[
+    "c", "cc", "cfg", "conf", "cpp", "cs", "css", "csv", "go", "h", "hpp", "html", "ini", "java",
+    "js", "json", "jsx", "kt", "lua", "md", "php", "pl", "py", "rb", "rs", "scss", "sh", "sql",
+    "swift", "toml", "ts", "tsx", "txt", "vue", "xml", "yaml", "yml",
+];
+
+fn is_text_mime_type(mime_type: &str) -> bool {
+    mime_type.starts_with("text/")
+        || matches!(
+            mime_type,
+            "application/json"
+                | "application/xml"
+                | "application/javascript"
+                | "application/x-sh"
+                | "application/toml"
+                | "application/x-yaml"
+                | "application/sql"
+        )
+}
+
+fn has_source_code_extension(path: &Path) -> bool {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| {
+            SOURCE_CODE_EXTENSIONS.contains(&extension.to_lowercase().as_str())
+        })
+}
+
+/// Whether the text of a file with this MIME type can be extracted for full-text search
+pub fn can_extract_text(path: &Path, mime_type: &str) -> bool {
+    mime_type == "application/pdf"
+        || is_text_mime_type(mime_type)
+        || has_source_code_extension(path)
+}
+
+fn truncate_text(mut text: String) -> String {
+    if text.len() > MAX_EXTRACTED_TEXT_LENGTH {
+        let mut end = MAX_EXTRACTED_TEXT_LENGTH;
+        while !text.is_char_boundary(end) {
+            end -= 1;
+        }
+
+        text.truncate(end);
+    }
+
+    text
+}
+
+/// Extracts the searchable text of a file, expects [`can_extract_text`] to have been checked. Files which turn out not
+/// to contain any text (e.g. binary files with a misleading extension) give an empty string.
+pub fn extract_text(path: &Path, mime_type: &str) -> Result<String, Box<dyn Error>> {
+    if path.metadata()?.len() > MAX_EXTRACTED_FILE_SIZE {
+        return Ok(String::new());
+    }
+
+    let text = match mime_type {
+        "application/pdf" => {
+            // The PDF parser panics on some malformed files rather than returning an error
+            panic::catch_unwind(|| pdf_extract::extract_text(path))
+                .map_err(|_| "Failed to parse PDF")??
+        }
+        _ => {
+            let bytes = fs::read(path)?;
+
+            // NUL bytes don't show up in text and can't be stored in Postgres
+            if bytes.contains(&0) {
+                return Ok(String::new());
+            }
+
+            String::from_utf8_lossy(&bytes).to_string()
+        }
+    };
+
+    Ok(truncate_text(text.replace('\0', "")))
+}
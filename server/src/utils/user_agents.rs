@@ -0,0 +1,31 @@
+use serde::Serialize;
+use woothee::{parser::Parser, woothee::VALUE_UNKNOWN};
+
+/// What can be told about a device from its user agent, anything that couldn't be recognised is left empty
+#[derive(Debug, Default, Serialize)]
+pub struct DeviceInfo {
+    pub browser: Option<String>,
+    pub browser_version: Option<String>,
+    pub os: Option<String>,
+    pub os_version: Option<String>,
+    /// One of `pc`, `smartphone`, `mobilephone`, `appliance`, `crawler` or `misc`
+    pub category: Option<String>,
+}
+
+fn known(value: &str) -> Option<String> {
+    Some(value.to_string()).filter(|value| !value.is_empty() && value != VALUE_UNKNOWN)
+}
+
+pub fn parse_device_info(user_agent: &str) -> DeviceInfo {
+    let Some(result) = Parser::new().parse(user_agent) else {
+        return DeviceInfo::default();
+    };
+
+    DeviceInfo {
+        browser: known(result.name),
+        browser_version: known(result.version),
+        os: known(result.os),
+        os_version: known(&result.os_version),
+        category: known(result.category),
+    }
+}
@@ -2,7 +2,6 @@ use chrono::Utc;
 use jsonwebtoken::{Algorithm, DecodingKey, Validation};
 use poem::http::StatusCode;
 use serde::{Deserialize, Serialize};
-use sha3::{Digest, Sha3_384};
 
 use crate::config::Config;
 
@@ -14,11 +13,10 @@ struct UserAccessTokenClaims {
     sub: String,
     exp: usize,
     iat: usize,
-}
-
-#[derive(Serialize)]
-pub struct UserAccessToken {
-    pub access_token: String,
+    /// Id of the session (refresh token) the access token was issued for, not set on tokens issued before sessions
+    /// were tracked
+    #[serde(default)]
+    sid: Option<String>,
 }
 
 #[derive(Serialize)]
@@ -27,7 +25,7 @@ pub struct UserTokens {
     pub refresh_token: String,
 }
 
-fn generate_access_token_(jwt_signing_key: &str, user_id: &Xid) -> String {
+fn generate_access_token(jwt_signing_key: &str, user_id: &Xid, session_id: &Xid) -> String {
     let iat = Utc::now().timestamp() as usize;
 
     let access_token_claims = UserAccessTokenClaims {
@@ -35,6 +33,7 @@ fn generate_access_token_(jwt_signing_key: &str, user_id: &Xid) -> String {
         sub: user_id.to_string(),
         exp: iat + (15 * 60), // 15 minutes
         iat,
+        sid: Some(session_id.to_string()),
     };
 
     let jwt_signing_key = jsonwebtoken::EncodingKey::from_secret(jwt_signing_key.as_bytes());
@@ -47,14 +46,8 @@ fn generate_access_token_(jwt_signing_key: &str, user_id: &Xid) -> String {
     .unwrap()
 }
 
-pub fn generate_access_token(jwt_signing_key: &str, user_id: &Xid) -> UserAccessToken {
-    UserAccessToken {
-        access_token: generate_access_token_(jwt_signing_key, user_id),
-    }
-}
-
-pub fn generate_tokens(jwt_signing_key: &str, user_id: &Xid) -> UserTokens {
-    let access_token = generate_access_token_(jwt_signing_key, user_id);
+pub fn generate_tokens(jwt_signing_key: &str, user_id: &Xid, session_id: &Xid) -> UserTokens {
+    let access_token = generate_access_token(jwt_signing_key, user_id, session_id);
     let refresh_token = random_string(128);
 
     UserTokens {
@@ -63,17 +56,12 @@ pub fn generate_tokens(jwt_signing_key: &str, user_id: &Xid) -> UserTokens {
     }
 }
 
-pub fn hash_refresh_token(refresh_token: &str) -> Vec<u8> {
-    let mut hasher = Sha3_384::new();
-    hasher.update(refresh_token.as_bytes());
-    hasher.finalize().to_vec()
-}
-
 #[allow(dead_code)]
 #[derive(Debug)]
 pub struct AuthenticatedUser {
     pub access_token: String,
     pub id: Xid,
+    pub session_id: Option<Xid>,
 }
 
 impl<'a> poem::FromRequest<'a> for AuthenticatedUser {
@@ -116,6 +104,10 @@ impl<'a> poem::FromRequest<'a> for AuthenticatedUser {
             Ok(AuthenticatedUser {
                 access_token: access_token.to_string(),
                 id: Xid::try_from(decoded_token.claims.sub.as_str()).unwrap(),
+                session_id: decoded_token
+                    .claims
+                    .sid
+                    .and_then(|sid| Xid::try_from(sid.as_str()).ok()),
             })
         } else {
             Err(poem::Error::from_string(